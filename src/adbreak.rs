@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::config::AdTrimConfig;
+use crate::transcode::{Transcoder, PCM_SAMPLE_RATE};
+
+/// Energy is measured over windows of this length.
+const WINDOW: Duration = Duration::from_millis(50);
+
+/// Where an advertisement segment sits in its ad break.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AdEdges {
+    /// The previous segment is not an advertisement, so the segment may start with its tail.
+    pub starts_break: bool,
+    /// The next segment is not an advertisement, so the segment may end with its head.
+    pub ends_break: bool,
+}
+
+impl AdEdges {
+    pub fn is_edge(&self) -> bool {
+        self.starts_break || self.ends_break
+    }
+}
+
+/// Finds the first and the last segment of ad breaks in the sequence of downloaded segments.
+///
+/// The kind of the following segment is only known once it is in a playlist, so a trailing
+/// advertisement is held back until the next batch.
+pub struct AdBreakTracker<T> {
+    previous_is_ad: bool,
+    held_back: Option<(T, AdEdges)>,
+}
+
+impl<T> AdBreakTracker<T> {
+    pub fn new() -> Self {
+        Self {
+            previous_is_ad: false,
+            held_back: None,
+        }
+    }
+
+    /// Returns segments ready for processing with their edges, in order.
+    pub fn annotate<F>(&mut self, segments: Vec<T>, is_ad: F) -> Vec<(T, AdEdges)>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut result = Vec::with_capacity(segments.len() + 1);
+        let mut pending = self.held_back.take();
+
+        for segment in segments {
+            let segment_is_ad = is_ad(&segment);
+
+            if let Some((held, mut edges)) = pending.take() {
+                edges.ends_break = !segment_is_ad;
+                result.push((held, edges));
+            }
+
+            let edges = AdEdges {
+                starts_break: segment_is_ad && !self.previous_is_ad,
+                ends_break: false,
+            };
+            self.previous_is_ad = segment_is_ad;
+
+            if segment_is_ad {
+                pending = Some((segment, edges));
+            } else {
+                result.push((segment, edges));
+            }
+        }
+
+        self.held_back = pending;
+        result
+    }
+}
+
+/// Part of a segment to keep, as offsets from its start.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cut {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+/// Finds where the advertisement starts and/or ends within an edge segment.
+///
+/// Breaks are separated from the surrounding program by a short pause, so the cut is placed at
+/// the longest run of silent windows. Returns `None` if there is no such run.
+pub fn find_cut(samples: &[i16], edges: AdEdges, config: &AdTrimConfig) -> Option<Cut> {
+    let window = (PCM_SAMPLE_RATE as u128 * WINDOW.as_millis() / 1000) as usize;
+    let min_windows = (config.min_silence.as_millis() / WINDOW.as_millis()).max(1) as usize;
+
+    let silent: Vec<bool> = samples
+        .chunks(window)
+        .map(|chunk| energy_db(chunk) < config.silence_threshold_db)
+        .collect();
+    let runs = silent_runs(&silent, min_windows);
+
+    let at = |index: usize| WINDOW * index as u32;
+    let longest = |runs: &[(usize, usize)]| runs.iter().copied().max_by_key(|(s, e)| e - s);
+
+    match (edges.starts_break, edges.ends_break) {
+        (true, false) => longest(&runs).map(|(_, end)| Cut {
+            start: at(end),
+            end: None,
+        }),
+        (false, true) => longest(&runs).map(|(start, _)| Cut {
+            start: Duration::ZERO,
+            end: Some(at(start)),
+        }),
+        (true, true) => {
+            // Both edges of a short break: the program tail ends at the first long pause and the
+            // program head starts at the last one.
+            let (first, last) = (runs.first()?, runs.last()?);
+            (first != last).then(|| Cut {
+                start: at(first.1),
+                end: Some(at(last.0)),
+            })
+        }
+        (false, false) => None,
+    }
+}
+
+/// Trims an edge segment to its advertisement part.
+///
+/// Returns the bytes unchanged if no cut point is found or the audio cannot be processed.
+pub async fn trim(
+    transcoder: &Transcoder<'_>,
+    config: &AdTrimConfig,
+    bytes: Bytes,
+    format: &str,
+    edges: AdEdges,
+) -> Bytes {
+    if !config.enabled || !edges.is_edge() {
+        return bytes;
+    }
+
+    let samples = match transcoder.decode(&bytes).await {
+        Ok(samples) => samples,
+        Err(e) => {
+            log::error!("Ad trim: failed to decode: {e:#}");
+            return bytes;
+        }
+    };
+
+    let cut = match find_cut(&samples, edges, config) {
+        Some(cut) => cut,
+        None => {
+            log::info!("Ad trim: no cut point found, {edges:?}");
+            return bytes;
+        }
+    };
+
+    log::info!("Ad trim: keeping {cut:?}, {edges:?}");
+    match transcoder.trim(&bytes, format, cut.start, cut.end).await {
+        Ok(trimmed) => trimmed,
+        Err(e) => {
+            log::error!("Ad trim: failed to cut: {e:#}");
+            bytes
+        }
+    }
+}
+
+fn energy_db(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let sum: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+    let rms = (sum / samples.len() as f64).sqrt();
+    20.0 * (rms / i16::MAX as f64).log10()
+}
+
+/// Returns `[start, end)` window ranges of at least `min_len` consecutive silent windows.
+fn silent_runs(silent: &[bool], min_len: usize) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (index, &is_silent) in silent.iter().chain([&false]).enumerate() {
+        match (is_silent, start) {
+            (true, None) => start = Some(index),
+            (false, Some(s)) => {
+                if index - s >= min_len {
+                    runs.push((s, index));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{find_cut, AdBreakTracker, AdEdges, Cut};
+    use crate::config::AdTrimConfig;
+    use crate::transcode::PCM_SAMPLE_RATE;
+
+    fn tone(seconds: f64) -> Vec<i16> {
+        let count = (seconds * PCM_SAMPLE_RATE as f64) as usize;
+        (0..count)
+            .map(|i| {
+                let t = i as f64 / PCM_SAMPLE_RATE as f64;
+                (8000.0 * (2.0 * std::f64::consts::PI * 440.0 * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    fn silence(seconds: f64) -> Vec<i16> {
+        vec![0; (seconds * PCM_SAMPLE_RATE as f64) as usize]
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = AdBreakTracker::new();
+        let is_ad = |s: &&str| s.starts_with("ad");
+
+        let batch = tracker.annotate(vec!["music1", "ad1", "ad2"], is_ad);
+        assert_eq!(
+            batch,
+            vec![
+                ("music1", AdEdges::default()),
+                (
+                    "ad1",
+                    AdEdges {
+                        starts_break: true,
+                        ends_break: false
+                    }
+                ),
+            ]
+        );
+
+        let batch = tracker.annotate(vec!["talk1"], is_ad);
+        assert_eq!(
+            batch,
+            vec![
+                (
+                    "ad2",
+                    AdEdges {
+                        starts_break: false,
+                        ends_break: true
+                    }
+                ),
+                ("talk1", AdEdges::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cut_start() {
+        let samples = [tone(3.0), silence(0.5), tone(6.5)].concat();
+        let edges = AdEdges {
+            starts_break: true,
+            ends_break: false,
+        };
+
+        assert_eq!(
+            find_cut(&samples, edges, &AdTrimConfig::default()),
+            Some(Cut {
+                start: Duration::from_millis(3500),
+                end: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_cut_end() {
+        let samples = [tone(7.0), silence(0.5), tone(2.5)].concat();
+        let edges = AdEdges {
+            starts_break: false,
+            ends_break: true,
+        };
+
+        assert_eq!(
+            find_cut(&samples, edges, &AdTrimConfig::default()),
+            Some(Cut {
+                start: Duration::ZERO,
+                end: Some(Duration::from_millis(7000))
+            })
+        );
+    }
+
+    #[test]
+    fn test_no_silence() {
+        let edges = AdEdges {
+            starts_break: true,
+            ends_break: true,
+        };
+        assert_eq!(find_cut(&tone(10.0), edges, &AdTrimConfig::default()), None);
+    }
+}
@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::output::{print_json, table, Output};
+use crate::storage::{Airplay, MetadataStorage};
+
+#[derive(Debug, Serialize)]
+struct AirplayRow {
+    station: String,
+    track_id: Uuid,
+    artist: Option<String>,
+    title: Option<String>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    duration_seconds: i64,
+    segments: u32,
+    mean_score: f32,
+    max_score: u8,
+}
+
+/// Prints airplays with the artist and title of their tracks.
+pub async fn print(
+    airplays: &[Airplay],
+    metadata_storage: &MetadataStorage,
+    output: Output,
+) -> anyhow::Result<()> {
+    let mut rows = Vec::new();
+    for airplay in airplays {
+        let metadata = metadata_storage.get(airplay.track_id).await.ok();
+        rows.push(AirplayRow {
+            station: airplay.station.clone(),
+            track_id: airplay.track_id,
+            artist: metadata.as_ref().map(|m| m.artist().to_owned()),
+            title: metadata.as_ref().map(|m| m.title().to_owned()),
+            start: airplay.start,
+            end: airplay.end,
+            duration_seconds: airplay.duration().num_seconds(),
+            segments: airplay.segments,
+            mean_score: airplay.mean_score(),
+            max_score: airplay.max_score,
+        });
+    }
+
+    match output {
+        Output::Json => print_json(&rows),
+        Output::Table => {
+            let cells = rows
+                .into_iter()
+                .map(|row| {
+                    vec![
+                        row.start.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        format!("{}s", row.duration_seconds),
+                        row.station,
+                        row.track_id.to_string(),
+                        row.segments.to_string(),
+                        format!("{:.0}", row.mean_score),
+                        row.max_score.to_string(),
+                        row.artist.unwrap_or_default(),
+                        row.title.unwrap_or_default(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print!(
+                "{}",
+                table(
+                    &[
+                        "START", "DURATION", "STATION", "TRACK", "SEGMENTS", "MEAN", "MAX",
+                        "ARTIST", "TITLE"
+                    ],
+                    &cells
+                )
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use lofty::{ItemKey, Tag, TagExt, TagType};
+use uuid::Uuid;
+
+use crate::storage::{AudioData, AudioStorage, Metadata, MetadataFilter, MetadataStorage};
+use crate::transcode::{self, AudioVariant, Transcoder, OPUS_FORMAT};
+
+/// Items read from the database at a time.
+const PAGE_SIZE: usize = 100;
+
+/// File type audio is exported as, with the tag format it carries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Target {
+    Mp3,
+    Opus,
+    /// AAC remuxed from ADTS or MPEG-TS, which cannot carry tags.
+    Mp4,
+}
+
+impl Target {
+    fn of(format: &str) -> anyhow::Result<Self> {
+        let mime = format.split(';').next().unwrap_or_default().trim();
+        Ok(match mime.to_lowercase().as_str() {
+            "audio/mpeg" | "audio/mp3" => Target::Mp3,
+            OPUS_FORMAT | "audio/opus" => Target::Opus,
+            "audio/aac" | "audio/aacp" | "audio/x-aac" | "video/mp2t" => Target::Mp4,
+            _ => bail!("Unsupported format {format}"),
+        })
+    }
+
+    fn extension(&self, format: &str) -> &'static str {
+        match self {
+            Target::Mp4 => "m4a",
+            _ => transcode::extension(format),
+        }
+    }
+
+    fn tag_type(&self) -> TagType {
+        match self {
+            Target::Mp3 => TagType::Id3v2,
+            Target::Opus => TagType::VorbisComments,
+            Target::Mp4 => TagType::Mp4Ilst,
+        }
+    }
+}
+
+/// Writes stored audio to tagged files named after the items.
+pub struct AudioExporter<'a> {
+    transcoder: &'a Transcoder<'a>,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+    variant: AudioVariant,
+}
+
+impl<'a> AudioExporter<'a> {
+    pub fn new(
+        transcoder: &'a Transcoder<'a>,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+    ) -> Self {
+        Self {
+            transcoder,
+            metadata_storage,
+            audio_storage,
+            variant: AudioVariant::Stored,
+        }
+    }
+
+    /// Exports audio in the format it was received in. Items whose audio is stored transcoded
+    /// fail, as their original is not kept.
+    pub fn with_variant(mut self, variant: AudioVariant) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    /// Exports the items `ids` if given, otherwise those matching `filter`, to `dir`. Items
+    /// that fail are logged and skipped. Returns the number of files written.
+    pub async fn export(
+        &self,
+        ids: &[Uuid],
+        filter: &MetadataFilter,
+        dir: &Path,
+    ) -> anyhow::Result<usize> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Create {}", dir.display()))?;
+
+        let mut written = 0;
+        if !ids.is_empty() {
+            for &id in ids {
+                let item = self
+                    .metadata_storage
+                    .get(id)
+                    .await
+                    .with_context(|| format!("Get item {id}"))?;
+                written += self.try_export(&item, dir).await as usize;
+            }
+            return Ok(written);
+        }
+
+        let mut after = None;
+        loop {
+            let page = self
+                .metadata_storage
+                .after(filter, after.as_ref(), PAGE_SIZE)
+                .await?;
+            for item in &page {
+                written += self.try_export(item, dir).await as usize;
+            }
+            if page.len() < PAGE_SIZE {
+                return Ok(written);
+            }
+            after = page.last().cloned();
+        }
+    }
+
+    /// Exports `item`, logging the outcome. Returns whether it was written.
+    async fn try_export(&self, item: &Metadata, dir: &Path) -> bool {
+        match self.export_item(item, dir).await {
+            Ok(path) => {
+                log::info!("Exported {} to {}", item.id, path.display());
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to export {}: {e:#}", item.id);
+                false
+            }
+        }
+    }
+
+    /// Writes the audio of `item` to `dir` and tags it. Returns the path of the file.
+    async fn export_item(&self, item: &Metadata, dir: &Path) -> anyhow::Result<PathBuf> {
+        let audio = self
+            .transcoder
+            .get(self.audio_storage, item.id, self.variant)
+            .await
+            .context("Get audio")?;
+        let target = Target::of(audio.format())?;
+        let path = dir.join(filename(item, target.extension(audio.format())));
+
+        self.write(&audio, target, &path).await?;
+        let mut tag = Tag::new(target.tag_type());
+        for (key, value) in tags(item) {
+            tag.insert_text(key, value);
+        }
+        tag.save_to_path(&path)
+            .with_context(|| format!("Tag {}", path.display()))?;
+        Ok(path)
+    }
+
+    async fn write(&self, audio: &AudioData, target: Target, path: &Path) -> anyhow::Result<()> {
+        match target {
+            Target::Mp4 => self
+                .transcoder
+                .remux_mp4(audio.bytes(), path)
+                .await
+                .context("Remux to MP4"),
+            Target::Mp3 | Target::Opus => tokio::fs::write(path, audio.bytes())
+                .await
+                .with_context(|| format!("Write {}", path.display())),
+        }
+    }
+}
+
+/// When the item aired, or was received if the stream did not tell.
+fn aired(item: &Metadata) -> chrono::DateTime<chrono::Utc> {
+    item.source().aired.unwrap_or_else(|| item.date())
+}
+
+/// Artist, title, kind as genre, air date and the item id as comment.
+fn tags(item: &Metadata) -> Vec<(ItemKey, String)> {
+    vec![
+        (ItemKey::TrackArtist, item.artist().to_owned()),
+        (ItemKey::TrackTitle, item.title().to_owned()),
+        (ItemKey::Genre, item.kind().to_string()),
+        (ItemKey::RecordingDate, aired(item).to_rfc3339()),
+        (ItemKey::Comment, item.id.to_string()),
+    ]
+}
+
+/// `<air time> <kind> <artist> - <title> <id>.<extension>`, safe on common filesystems.
+fn filename(item: &Metadata, extension: &str) -> String {
+    let name = format!(
+        "{} {} {} - {} {}",
+        aired(item).format("%Y%m%d-%H%M%S"),
+        item.kind(),
+        item.artist(),
+        item.title(),
+        item.id
+    );
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    format!(
+        "{}.{extension}",
+        name.trim_matches(|c: char| c == '.' || c.is_whitespace())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use lofty::{ItemKey, TagType};
+    use uuid::Uuid;
+
+    use super::{filename, AudioExporter, Target};
+    use crate::config::TranscodeConfig;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, Metadata};
+    use crate::storage::{MetadataFilter, MetadataStorage, SegmentSource};
+    use crate::transcode::Transcoder;
+
+    #[tokio::test]
+    async fn test_export() {
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let exporter = AudioExporter::new(&transcoder, &metadata_storage, &audio_storage);
+
+        let item = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            AudioKind::Music,
+            "Artist".to_owned(),
+            "Title".to_owned(),
+        );
+        // MPEG-1 layer III frames at 128 kbit/s, 44.1 kHz, of silence.
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x64]);
+        let mp3 = frame.repeat(10);
+        metadata_storage.insert(&item).await.unwrap();
+        audio_storage
+            .insert(&AudioData::new(
+                item.id,
+                "audio/mpeg".to_owned(),
+                mp3.into(),
+            ))
+            .await
+            .unwrap();
+        // Not exported, the others still are.
+        let unknown = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            AudioKind::Music,
+            "Artist".to_owned(),
+            "Title".to_owned(),
+        );
+        metadata_storage.insert(&unknown).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("test_export_{}", Uuid::new_v4()));
+        let written = exporter
+            .export(&[], &MetadataFilter::default(), &dir)
+            .await
+            .unwrap();
+        assert_eq!(written, 1);
+
+        let path = dir.join(filename(&item, "mp3"));
+        let file = lofty::read_from_path(&path, false).unwrap();
+        let tag = file.tag(&TagType::Id3v2).unwrap();
+        assert_eq!(tag.get_string(&ItemKey::TrackArtist), Some("Artist"));
+        assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("Title"));
+        assert_eq!(
+            tag.get_string(&ItemKey::Comment),
+            Some(item.id.to_string().as_str())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_filename() {
+        let id = Uuid::new_v4();
+        let received = Utc.with_ymd_and_hms(2022, 6, 1, 10, 0, 5).unwrap();
+        let item = Metadata::new(
+            id,
+            received,
+            AudioKind::Advertisement,
+            "AC/DC".to_owned(),
+            "What? \"Now\"\n".to_owned(),
+        );
+        assert_eq!(
+            filename(&item, "m4a"),
+            format!("20220601-100005 advertisement AC_DC - What_ _Now__ {id}.m4a")
+        );
+
+        let item = item.with_source(SegmentSource {
+            aired: Some(received - chrono::Duration::seconds(5)),
+            ..Default::default()
+        });
+        assert!(filename(&item, "mp3").starts_with("20220601-100000 "));
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(Target::of("audio/aac").unwrap(), Target::Mp4);
+        assert_eq!(
+            Target::of("video/mp2t").unwrap().extension("video/mp2t"),
+            "m4a"
+        );
+        assert_eq!(
+            Target::of("audio/ogg").unwrap().extension("audio/ogg"),
+            "ogg"
+        );
+        assert_eq!(
+            Target::of("audio/mpeg; charset=UTF-8").unwrap(),
+            Target::Mp3
+        );
+        assert!(Target::of("text/plain").is_err());
+    }
+}
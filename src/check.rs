@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::output::{print_json, table, Output};
+use crate::storage::QueueStorage;
+use crate::storage::{AudioStorage, Database, MatchesStorage, MetadataStorage, OutboxStorage};
+use crate::transcode::Transcoder;
+
+/// What is wrong with a database or an id in it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// A problem reported by `PRAGMA integrity_check`.
+    Integrity,
+    /// Metadata without stored audio.
+    MissingAudio,
+    /// Stored audio of neither an item, a queued segment nor a match.
+    OrphanAudio,
+    /// Stored audio that cannot be read.
+    UnreadableAudio,
+    /// Stored audio without any bytes.
+    EmptyAudio,
+    /// Stored audio that does not decode.
+    UndecodableAudio,
+    /// Matches or plays of an id without metadata.
+    OrphanMatches,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub problem: Problem,
+    pub id: Option<Uuid>,
+    pub detail: String,
+}
+
+/// Checks the databases and the consistency of the stores in them.
+pub struct Checker<'a> {
+    transcoder: &'a Transcoder<'a>,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+    matches_storage: &'a MatchesStorage,
+    queue: &'a QueueStorage,
+    outbox: &'a OutboxStorage,
+    databases: Vec<(&'a str, &'a Database)>,
+    decode: bool,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(
+        transcoder: &'a Transcoder<'a>,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+        matches_storage: &'a MatchesStorage,
+        queue: &'a QueueStorage,
+        outbox: &'a OutboxStorage,
+    ) -> Self {
+        Self {
+            transcoder,
+            metadata_storage,
+            audio_storage,
+            matches_storage,
+            queue,
+            outbox,
+            databases: Vec::new(),
+            decode: true,
+        }
+    }
+
+    /// Runs `PRAGMA integrity_check` on `db`, reported as `name`.
+    pub fn with_database(mut self, name: &'a str, db: &'a Database) -> Self {
+        self.databases.push((name, db));
+        self
+    }
+
+    /// Whether stored audio is decoded, which needs ffmpeg and takes a while.
+    pub fn with_decode(mut self, decode: bool) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    pub async fn check(&self) -> anyhow::Result<Vec<Finding>> {
+        let mut findings = Vec::new();
+        let mut push = |problem, id, detail: String| {
+            findings.push(Finding {
+                problem,
+                id,
+                detail,
+            })
+        };
+
+        for (name, db) in &self.databases {
+            for message in db.integrity_check().await? {
+                push(Problem::Integrity, None, format!("{name}: {message}"));
+            }
+        }
+
+        let local: HashSet<Uuid> = self.metadata_storage.ids().await?.into_iter().collect();
+        let mut audio = self.audio_storage.ids().await?;
+        audio.sort();
+        let stored: HashSet<Uuid> = audio.iter().copied().collect();
+        let queued: HashSet<Uuid> = self.queue.ids().await?.into_iter().collect();
+        let registering: HashSet<Uuid> = self.outbox.ids().await?.into_iter().collect();
+        let queries: HashSet<Uuid> = self
+            .matches_storage
+            .query_ids(None)
+            .await?
+            .into_iter()
+            .collect();
+        log::info!(
+            "Check: {} items, {} audio, {} queued, {} registering",
+            local.len(),
+            audio.len(),
+            queued.len(),
+            registering.len()
+        );
+
+        let mut missing: Vec<&Uuid> = local.difference(&stored).collect();
+        missing.sort();
+        for &id in missing {
+            push(Problem::MissingAudio, Some(id), String::new());
+        }
+
+        for &id in &audio {
+            if !local.contains(&id)
+                && !queued.contains(&id)
+                && !registering.contains(&id)
+                && !queries.contains(&id)
+            {
+                push(Problem::OrphanAudio, Some(id), String::new());
+            }
+
+            let data = match self.audio_storage.get(id).await {
+                Ok(data) => data,
+                Err(e) => {
+                    push(Problem::UnreadableAudio, Some(id), format!("{e:#}"));
+                    continue;
+                }
+            };
+            if data.bytes().is_empty() {
+                push(Problem::EmptyAudio, Some(id), data.format().to_owned());
+            } else if self.decode {
+                match self.transcoder.decode(data.bytes()).await {
+                    Ok(samples) if samples.is_empty() => {
+                        push(Problem::UndecodableAudio, Some(id), "No samples".to_owned())
+                    }
+                    Ok(_) => {}
+                    Err(e) => push(Problem::UndecodableAudio, Some(id), format!("{e:#}")),
+                }
+            }
+        }
+
+        for (id, matches, plays) in self.matches_storage.orphans().await? {
+            push(
+                Problem::OrphanMatches,
+                Some(id),
+                format!("{matches} matches, {plays} plays"),
+            );
+        }
+        Ok(findings)
+    }
+}
+
+pub fn print(findings: &[Finding], output: Output) -> anyhow::Result<()> {
+    match output {
+        Output::Json => print_json(findings),
+        Output::Table => {
+            let rows = findings
+                .iter()
+                .map(|f| {
+                    vec![
+                        format!("{:?}", f.problem),
+                        f.id.map(|id| id.to_string()).unwrap_or_default(),
+                        f.detail.clone(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print!("{}", table(&["PROBLEM", "ID", "DETAIL"], &rows));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::{Checker, Finding, Problem};
+    use crate::config::TranscodeConfig;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, MatchData};
+    use crate::storage::{MatchesStorage, Metadata, MetadataStorage, OutboxStorage, QueueStorage};
+    use crate::transcode::Transcoder;
+
+    #[tokio::test]
+    async fn test_check() {
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let checker = Checker::new(
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &outbox,
+        )
+        .with_database("feeder", &db)
+        .with_decode(false);
+
+        let item = |id| {
+            Metadata::new(
+                id,
+                Utc::now(),
+                AudioKind::Music,
+                "Artist".to_owned(),
+                "Title".to_owned(),
+            )
+        };
+        let audio =
+            |id, bytes: &'static [u8]| AudioData::new(id, "audio/aac".to_owned(), bytes.into());
+
+        let good = Uuid::new_v4();
+        metadata_storage.insert(&item(good)).await.unwrap();
+        audio_storage.insert(&audio(good, b"123")).await.unwrap();
+        matches_storage
+            .insert(&MatchData::new(good, Utc::now(), 90))
+            .await
+            .unwrap();
+        assert!(checker.check().await.unwrap().is_empty());
+
+        let missing = Uuid::new_v4();
+        metadata_storage.insert(&item(missing)).await.unwrap();
+        let empty = Uuid::new_v4();
+        metadata_storage.insert(&item(empty)).await.unwrap();
+        audio_storage.insert(&audio(empty, b"")).await.unwrap();
+        let orphan = Uuid::new_v4();
+        audio_storage.insert(&audio(orphan, b"123")).await.unwrap();
+        let deleted = Uuid::new_v4();
+        matches_storage
+            .insert(&MatchData::new(deleted, Utc::now(), 90))
+            .await
+            .unwrap();
+
+        let findings = checker.check().await.unwrap();
+        let finding = |problem, id| {
+            findings
+                .iter()
+                .any(|f: &Finding| f.problem == problem && f.id == Some(id))
+        };
+        assert_eq!(findings.len(), 4);
+        assert!(finding(Problem::MissingAudio, missing));
+        assert!(finding(Problem::EmptyAudio, empty));
+        assert!(finding(Problem::OrphanAudio, orphan));
+        assert!(finding(Problem::OrphanMatches, deleted));
+    }
+}
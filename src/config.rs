@@ -0,0 +1,699 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::storage::AudioKind;
+
+/// Feeder configuration, read from a TOML file.
+///
+/// Every section is optional; missing values fall back to the defaults below. Tables under
+/// `[stations.<name>]` override the top-level values for that station:
+///
+/// ```toml
+/// [fingerprint]
+/// backend = "emysound"
+/// min_coverage = 0.3
+///
+/// [stations.kosta.fingerprint]
+/// min_coverage = 0.5
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub audio: AudioStorageConfig,
+    pub retention: RetentionConfig,
+    pub transcode: TranscodeConfig,
+    pub ad_trim: AdTrimConfig,
+    pub segment_metadata: SegmentMetadataConfig,
+    pub fingerprint: FingerprintConfig,
+    pub queue: QueueConfig,
+    pub airplay: AirplayConfig,
+    pub matching: MatchConfig,
+    pub stations: HashMap<String, toml::value::Table>,
+}
+
+impl Config {
+    /// Reads the config, with the overrides of `station` applied if it has any.
+    pub fn load<P>(path: &P, station: Option<&str>) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Read config {}", path.as_ref().display()))?;
+        Self::parse(&content, station)
+    }
+
+    fn parse(content: &str, station: Option<&str>) -> anyhow::Result<Self> {
+        let mut value: toml::Value = toml::from_str(content).context("Parse config")?;
+
+        let overrides = station.and_then(|station| value.get("stations")?.get(station).cloned());
+        if let Some(overrides) = overrides {
+            merge(&mut value, overrides);
+        }
+
+        value.try_into().context("Parse config")
+    }
+}
+
+/// Recursively replaces values in `base` with those in `overrides`, keeping the rest of tables.
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// The SQLite database of metadata, matches and the retry queue.
+///
+/// ```toml
+/// [database]
+/// path = "./feeder.sqlite3"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: "./feeder.sqlite3".into(),
+        }
+    }
+}
+
+/// Where audio segments are stored, by default in their own SQLite database `audio.sqlite3`.
+///
+/// ```toml
+/// [audio]
+/// backend = "directory"
+/// path = "./audio"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum AudioStorageConfig {
+    /// BLOBs in a SQLite database, the one in `[database]` if `path` is not set. That one
+    /// imports the audio of a `./audio.sqlite3` of an older feeder.
+    Sqlite { path: Option<PathBuf> },
+    /// Content-addressed files in a directory.
+    Directory { path: PathBuf },
+    /// Objects in an S3-compatible bucket.
+    S3(S3Config),
+}
+
+impl Default for AudioStorageConfig {
+    fn default() -> Self {
+        AudioStorageConfig::Sqlite {
+            path: Some("./audio.sqlite3".into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Custom endpoint for S3-compatible services, e.g. `http://localhost:9000` for MinIO.
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    #[serde(default = "S3Config::default_prefix")]
+    pub prefix: String,
+    /// Allow plain HTTP endpoints.
+    #[serde(default)]
+    pub allow_http: bool,
+}
+
+impl S3Config {
+    fn default_prefix() -> String {
+        "audio".to_owned()
+    }
+}
+
+/// How long items are kept, per kind. Kinds without a period are kept forever.
+///
+/// ```toml
+/// [retention]
+/// talk = "7d"
+/// unknown = "1d"
+/// delete_tracks = true
+/// orphans = true
+/// interval = "1h"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    #[serde(with = "humantime_serde")]
+    pub advertisement: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub music: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub talk: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub unknown: Option<Duration>,
+    /// How long matches are kept, regardless of the kind of the matched item.
+    #[serde(with = "humantime_serde")]
+    pub matches: Option<Duration>,
+    /// Also delete expired items from EmySound.
+    pub delete_tracks: bool,
+    /// Also delete metadata without audio and audio without metadata, left by interrupted
+    /// writes.
+    pub orphans: bool,
+    /// Prune in the background of `run` this often. Pruning runs only on `prune` if unset.
+    #[serde(with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    /// Maximum number of free pages given back to the filesystem per prune.
+    pub vacuum_pages: Option<u32>,
+}
+
+impl RetentionConfig {
+    pub fn period(&self, kind: AudioKind) -> Option<Duration> {
+        match kind {
+            AudioKind::Advertisement => self.advertisement,
+            AudioKind::Music => self.music,
+            AudioKind::Talk => self.talk,
+            AudioKind::Unknown => self.unknown,
+        }
+    }
+}
+
+/// Re-encoding of stored audio to low-bitrate Opus.
+///
+/// ```toml
+/// [transcode]
+/// kinds = ["talk", "unknown"]
+/// bitrate = "24k"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodeConfig {
+    /// Kinds stored as Opus. Everything else is stored as received.
+    pub kinds: Vec<AudioKind>,
+    /// Opus bitrate, in ffmpeg notation.
+    pub bitrate: String,
+    /// Path to the ffmpeg binary.
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self {
+            kinds: Vec::new(),
+            bitrate: "24k".to_owned(),
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+}
+
+/// Trimming of program tails and heads off the edge segments of ad breaks.
+///
+/// ```toml
+/// [ad_trim]
+/// enabled = true
+/// silence_threshold_db = -45.0
+/// min_silence = "200ms"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdTrimConfig {
+    pub enabled: bool,
+    /// Windows quieter than this, in dBFS, are silent.
+    pub silence_threshold_db: f64,
+    /// Shortest pause that can separate an ad from the program.
+    #[serde(with = "humantime_serde")]
+    pub min_silence: Duration,
+}
+
+impl Default for AdTrimConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            silence_threshold_db: -45.0,
+            min_silence: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Which fingerprint backend matches segments.
+///
+/// ```toml
+/// [fingerprint]
+/// backend = "local"
+/// path = "./fingerprints.sqlite3"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum FingerprintConfig {
+    /// The EmySound service.
+    EmySound(EmySoundConfig),
+    /// In-process fingerprinting, hashes kept in a SQLite database.
+    Local { path: PathBuf },
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        FingerprintConfig::EmySound(EmySoundConfig::default())
+    }
+}
+
+/// EmySound endpoint, credentials and query parameters.
+///
+/// The password is a secret, so it is never part of the config itself: it is read from the
+/// environment variable named by `password_env`, or from `password_file`.
+///
+/// ```toml
+/// [fingerprint]
+/// backend = "emysound"
+/// url = "http://emysound:3340/api/v1.1"
+/// username = "feeder"
+/// password_env = "EMYSOUND_PASSWORD"
+/// min_confidence = 0.2
+/// min_coverage = 0.0
+/// timeout = "30s"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmySoundConfig {
+    /// Base URL of the REST API.
+    pub url: String,
+    pub username: String,
+    pub password_env: Option<String>,
+    pub password_file: Option<PathBuf>,
+    /// Passed to queries as `minConfidence`.
+    pub min_confidence: f32,
+    /// Passed to queries as `minCoverage`.
+    pub min_coverage: f32,
+    /// Timeout of a whole request, upload included.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    pub connect_timeout: Duration,
+}
+
+impl EmySoundConfig {
+    /// Returns the password from the environment or the password file, if either is set.
+    pub fn password(&self) -> anyhow::Result<Option<String>> {
+        if let Some(name) = &self.password_env {
+            return std::env::var(name)
+                .map(Some)
+                .with_context(|| format!("Read EmySound password from ${name}"));
+        }
+        if let Some(path) = &self.password_file {
+            return std::fs::read_to_string(path)
+                .map(|password| Some(password.trim_end().to_owned()))
+                .with_context(|| format!("Read EmySound password from {}", path.display()));
+        }
+        Ok(None)
+    }
+}
+
+impl Default for EmySoundConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:3340/api/v1.1".to_owned(),
+            username: "ADMIN".to_owned(),
+            password_env: None,
+            password_file: None,
+            min_confidence: 0.2,
+            min_coverage: 0.0,
+            timeout: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Retries of segments that failed to reach the fingerprint backend.
+///
+/// ```toml
+/// [queue]
+/// poll_interval = "5s"
+/// min_backoff = "10s"
+/// max_backoff = "10m"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// How often the queue is checked for due segments.
+    #[serde(with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Delay after the first failed retry, doubled with every further failure.
+    #[serde(with = "humantime_serde")]
+    pub min_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Most segments retried per poll.
+    pub batch_size: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(5),
+            min_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(600),
+            batch_size: 10,
+        }
+    }
+}
+
+/// Merging of consecutive matches into airplay events.
+///
+/// ```toml
+/// [airplay]
+/// max_gap = "30s"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AirplayConfig {
+    /// Longest time between two matches of one track that still belong to the same play.
+    #[serde(with = "humantime_serde")]
+    pub max_gap: Duration,
+}
+
+impl Default for AirplayConfig {
+    fn default() -> Self {
+        Self {
+            max_gap: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Which fingerprint results count as matches of a segment.
+///
+/// A result is accepted if its score reaches the threshold for the kind of the segment, or if it
+/// pairs up with another result under one of the pair rules. Results are ordered by score.
+///
+/// ```toml
+/// [matching]
+/// min_score = 75
+/// max_results = 3
+/// store_query_audio = true
+///
+/// [matching.kinds]
+/// advertisement = 60
+///
+/// # Ads all have artist "Advertisement", so pair them on title only.
+/// [[matching.pairs]]
+/// key = "title"
+/// min_sum = 90
+/// max_sum = 100
+/// kinds = ["advertisement"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Score from 0 to 100 a single result needs.
+    pub min_score: u8,
+    /// `min_score` overrides by segment kind.
+    pub kinds: KindScores,
+    /// Rules accepting two results below the threshold that are parts of one item.
+    pub pairs: Vec<PairRule>,
+    /// Keep only this many best results.
+    pub max_results: Option<usize>,
+    /// Keep the audio of matched segments in the audio storage, referenced by their matches.
+    pub store_query_audio: bool,
+}
+
+impl MatchConfig {
+    pub fn min_score(&self, kind: AudioKind) -> u8 {
+        self.kinds.get(kind).unwrap_or(self.min_score)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KindScores {
+    pub advertisement: Option<u8>,
+    pub music: Option<u8>,
+    pub talk: Option<u8>,
+    pub unknown: Option<u8>,
+}
+
+impl KindScores {
+    pub fn get(&self, kind: AudioKind) -> Option<u8> {
+        match kind {
+            AudioKind::Advertisement => self.advertisement,
+            AudioKind::Music => self.music,
+            AudioKind::Talk => self.talk,
+            AudioKind::Unknown => self.unknown,
+        }
+    }
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            min_score: 75,
+            kinds: KindScores::default(),
+            pairs: vec![PairRule::default()],
+            max_results: None,
+            store_query_audio: false,
+        }
+    }
+}
+
+/// Two results with the same `key` whose scores add up to `min_sum..=max_sum`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairRule {
+    pub key: PairKey,
+    pub min_sum: u8,
+    pub max_sum: u8,
+    /// Segment kinds the rule applies to. Empty means all kinds.
+    pub kinds: Vec<AudioKind>,
+}
+
+impl PairRule {
+    pub fn applies(&self, kind: AudioKind) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&kind)
+    }
+}
+
+impl Default for PairRule {
+    fn default() -> Self {
+        Self {
+            key: PairKey::ArtistOrTitle,
+            min_sum: 90,
+            max_sum: 100,
+            kinds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairKey {
+    Artist,
+    Title,
+    ArtistOrTitle,
+    ArtistAndTitle,
+}
+
+/// Where artist and title of a segment come from.
+///
+/// ```toml
+/// [segment_metadata]
+/// precedence = ["id3", "extinf"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmentMetadataConfig {
+    /// Sources in order of preference; the first one with a value wins. By default ID3 fills in
+    /// segments without #EXTINF artist and title.
+    pub precedence: Vec<MetadataSource>,
+}
+
+impl Default for SegmentMetadataConfig {
+    fn default() -> Self {
+        Self {
+            precedence: vec![MetadataSource::Extinf, MetadataSource::Id3],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataSource {
+    /// `#EXTINF` title of the playlist entry.
+    Extinf,
+    /// ID3 tags inside the segment.
+    Id3,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AudioStorageConfig, Config, FingerprintConfig, PairKey};
+    use crate::storage::AudioKind;
+
+    #[test]
+    fn test_default() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(matches!(
+            config.audio,
+            AudioStorageConfig::Sqlite { path: Some(path) } if path.ends_with("audio.sqlite3")
+        ));
+        assert!(config.database.path.ends_with("feeder.sqlite3"));
+    }
+
+    #[test]
+    fn test_audio_backend() {
+        let config: Config = toml::from_str(
+            r#"
+            [audio]
+            backend = "s3"
+            bucket = "feeder"
+            endpoint = "http://localhost:9000"
+            allow_http = true
+            "#,
+        )
+        .unwrap();
+
+        match config.audio {
+            AudioStorageConfig::S3(s3) => {
+                assert_eq!(s3.bucket, "feeder");
+                assert_eq!(s3.prefix, "audio");
+                assert!(s3.allow_http);
+            }
+            other => panic!("Unexpected backend {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        let config: Config = toml::from_str(
+            r#"
+            [retention]
+            talk = "7d"
+            unknown = "1day"
+            "#,
+        )
+        .unwrap();
+
+        let retention = config.retention;
+        assert_eq!(retention.period(AudioKind::Music), None);
+        assert_eq!(
+            retention.period(AudioKind::Talk),
+            Some(Duration::from_secs(7 * 24 * 3600))
+        );
+        assert_eq!(
+            retention.period(AudioKind::Unknown),
+            Some(Duration::from_secs(24 * 3600))
+        );
+        assert!(!retention.delete_tracks);
+    }
+
+    #[test]
+    fn test_transcode() {
+        let config: Config = toml::from_str(
+            r#"
+            [transcode]
+            kinds = ["talk", "unknown"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.transcode.kinds,
+            vec![AudioKind::Talk, AudioKind::Unknown]
+        );
+        assert_eq!(config.transcode.bitrate, "24k");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(matches!(config.fingerprint, FingerprintConfig::EmySound(_)));
+
+        let config: Config = toml::from_str(
+            r#"
+            [fingerprint]
+            backend = "local"
+            path = "./fingerprints.sqlite3"
+            "#,
+        )
+        .unwrap();
+        assert!(
+            matches!(config.fingerprint, FingerprintConfig::Local { path } if path.ends_with("fingerprints.sqlite3"))
+        );
+    }
+
+    #[test]
+    fn test_matching() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.matching.min_score(AudioKind::Music), 75);
+        assert_eq!(config.matching.pairs[0].key, PairKey::ArtistOrTitle);
+        assert!(!config.matching.store_query_audio);
+
+        let config: Config = toml::from_str(
+            r#"
+            [matching]
+            max_results = 1
+            store_query_audio = true
+
+            [matching.kinds]
+            advertisement = 60
+
+            [[matching.pairs]]
+            key = "title"
+            min_sum = 80
+            kinds = ["advertisement"]
+            "#,
+        )
+        .unwrap();
+        let matching = config.matching;
+        assert_eq!(matching.min_score(AudioKind::Advertisement), 60);
+        assert_eq!(matching.min_score(AudioKind::Talk), 75);
+        assert_eq!(matching.max_results, Some(1));
+        assert!(matching.store_query_audio);
+        assert_eq!(matching.pairs.len(), 1);
+        assert_eq!(matching.pairs[0].key, PairKey::Title);
+        assert_eq!(matching.pairs[0].max_sum, 100);
+        assert!(matching.pairs[0].applies(AudioKind::Advertisement));
+        assert!(!matching.pairs[0].applies(AudioKind::Music));
+    }
+
+    #[test]
+    fn test_station() {
+        let content = r#"
+            [fingerprint]
+            backend = "emysound"
+            url = "http://emysound:3340/api/v1.1"
+            min_coverage = 0.3
+
+            [stations.kosta.fingerprint]
+            min_coverage = 0.5
+            timeout = "5s"
+            "#;
+
+        let emysound = |config: Config| match config.fingerprint {
+            FingerprintConfig::EmySound(config) => config,
+            config => panic!("Unexpected {config:?}"),
+        };
+
+        let config = emysound(Config::parse(content, None).unwrap());
+        assert_eq!(config.min_coverage, 0.3);
+        assert_eq!(config.timeout, Duration::from_secs(60));
+
+        let config = emysound(Config::parse(content, Some("kosta")).unwrap());
+        assert_eq!(config.url, "http://emysound:3340/api/v1.1");
+        assert_eq!(config.min_coverage, 0.5);
+        assert_eq!(config.timeout, Duration::from_secs(5));
+
+        let config = emysound(Config::parse(content, Some("other")).unwrap());
+        assert_eq!(config.min_coverage, 0.3);
+    }
+}
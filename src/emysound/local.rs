@@ -0,0 +1,408 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rusqlite::{params, Connection, OpenFlags};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use uuid::Uuid;
+
+use super::{FingerprintBackend, QueryResult, TrackInfo};
+use crate::storage::MatchOffsets;
+use crate::transcode::{Transcoder, PCM_SAMPLE_RATE};
+
+/// FFT frame length, 128 ms at 8 kHz.
+const FRAME_SIZE: usize = 1024;
+/// Step between frames, 32 ms at 8 kHz.
+const HOP_SIZE: usize = 256;
+/// Frequency bands, in Hz, with at most one peak per band and frame.
+const BANDS: [f32; 7] = [250.0, 500.0, 750.0, 1000.0, 1500.0, 2000.0, 3000.0];
+/// Peaks weaker than this many times the frame mean magnitude are ignored.
+const PEAK_RATIO: f32 = 4.0;
+/// Target zone of an anchor peak, in frames.
+const TARGET_ZONE: std::ops::RangeInclusive<u32> = 2..=40;
+/// Number of target peaks paired with each anchor.
+const FAN_OUT: usize = 5;
+/// Fewest aligned hashes for a track to be a candidate.
+const MIN_MATCHES: usize = 8;
+/// Coverage is measured in slices of this many frames, about one second.
+const COVERAGE_SLICE: u32 = 32;
+
+/// A fingerprint hash and the frame of its anchor peak.
+type Hash = (u32, u32);
+
+/// In-process fingerprint index for deployments without EmySound.
+///
+/// Fingerprints are spectral-peak pairs hashed as anchor frequency, target frequency and time
+/// delta, and kept in SQLite. Query coverage is the share of the query, in one-second slices,
+/// that has hashes aligned with a track at a single offset, so it is on the same 0..1 scale as
+/// EmySound's query coverage.
+///
+/// Fingerprinting and lookups run on tokio's blocking thread pool, like `Database` statements.
+pub struct LocalBackend<'a> {
+    conn: Arc<Mutex<Connection>>,
+    transcoder: &'a Transcoder<'a>,
+}
+
+impl<'a> LocalBackend<'a> {
+    pub fn new<P>(path: &P, transcoder: &'a Transcoder<'a>) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
+        )?;
+
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS tracks(
+                id STRING PRIMARY KEY,
+                artist STRING NOT NULL,
+                title STRING NOT NULL
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS hashes(
+                hash INTEGER NOT NULL,
+                id STRING NOT NULL,
+                offset INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS hashes_hash ON hashes(hash);
+            CREATE INDEX IF NOT EXISTS hashes_id ON hashes(id);
+            "#,
+        )?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            transcoder,
+        })
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool.
+    async fn call<F, T>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| anyhow!("Fingerprint connection poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+
+    async fn insert_samples(&self, info: TrackInfo, samples: Vec<i16>) -> anyhow::Result<()> {
+        self.call(move |conn| insert_hashes(conn, &info, &fingerprint(&samples)))
+            .await
+    }
+
+    async fn query_samples(&self, samples: Vec<i16>) -> anyhow::Result<Vec<QueryResult>> {
+        self.call(move |conn| query_hashes(conn, &fingerprint(&samples)))
+            .await
+    }
+}
+
+/// Adds a track and the `hashes` of its audio.
+fn insert_hashes(conn: &mut Connection, info: &TrackInfo, hashes: &[Hash]) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO tracks VALUES(?, ?, ?)",
+        params![info.id.to_string(), info.artist, info.title],
+    )?;
+    {
+        let mut stmt = tx.prepare_cached("INSERT INTO hashes VALUES(?, ?, ?)")?;
+        for (hash, offset) in hashes {
+            stmt.execute(params![hash, info.id.to_string(), offset])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns tracks with enough `hashes` aligned at one offset, best coverage first.
+fn query_hashes(conn: &Connection, hashes: &[Hash]) -> anyhow::Result<Vec<QueryResult>> {
+    let query_slices: HashSet<u32> = hashes
+        .iter()
+        .map(|(_, offset)| offset / COVERAGE_SLICE)
+        .collect();
+    if query_slices.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Query frames of matching hashes, per track and offset difference.
+    let mut aligned: HashMap<(String, i64), Vec<u32>> = HashMap::new();
+    let mut stmt = conn.prepare_cached("SELECT id, offset FROM hashes WHERE hash=?")?;
+    for (hash, query_offset) in hashes {
+        let rows = stmt.query_map([hash], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (id, track_offset) = row?;
+            aligned
+                .entry((id, track_offset - *query_offset as i64))
+                .or_default()
+                .push(*query_offset);
+        }
+    }
+
+    // The best offset of each track.
+    let mut best: HashMap<String, (i64, Vec<u32>)> = HashMap::new();
+    for ((id, delta), frames) in aligned {
+        let current = best.entry(id).or_default();
+        if frames.len() > current.1.len() {
+            *current = (delta, frames);
+        }
+    }
+
+    let mut results = Vec::new();
+    for (id, (delta, frames)) in best {
+        if frames.len() < MIN_MATCHES {
+            continue;
+        }
+        let covered: HashSet<u32> = frames.iter().map(|f| f / COVERAGE_SLICE).collect();
+        let coverage = covered.len() as f32 / query_slices.len() as f32;
+
+        let (artist, title) = conn.query_row(
+            "SELECT artist, title FROM tracks WHERE id=?",
+            [&id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        results.push(
+            QueryResult::new(
+                Uuid::try_parse(&id).context("Parsing uuid")?,
+                coverage.min(1.0),
+                Some(artist),
+                Some(title),
+            )
+            .with_offsets(Some(offsets(&frames, delta))),
+        );
+    }
+
+    results.sort_by_key(|result| std::cmp::Reverse(result.score()));
+    Ok(results)
+}
+
+#[async_trait(?Send)]
+impl FingerprintBackend for LocalBackend<'_> {
+    async fn query(&self, _filename: &str, bytes: &Bytes) -> anyhow::Result<Vec<QueryResult>> {
+        let samples = self.transcoder.decode(bytes).await?;
+        self.query_samples(samples).await
+    }
+
+    async fn insert(&self, info: TrackInfo, _filename: &str, bytes: &Bytes) -> anyhow::Result<()> {
+        let samples = self.transcoder.decode(bytes).await?;
+        self.insert_samples(info, samples).await
+    }
+
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM hashes WHERE id=?", [id.to_string()])?;
+            tx.execute("DELETE FROM tracks WHERE id=?", [id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self, offset: usize) -> anyhow::Result<Vec<TrackInfo>> {
+        self.call(move |conn| {
+            let mut stmt = conn
+                .prepare("SELECT id, artist, title FROM tracks ORDER BY id LIMIT 100 OFFSET ?")?;
+            let rows = stmt.query([offset])?;
+            rows.mapped(|row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map(|row| {
+                let (id, artist, title) = row?;
+                Ok(TrackInfo::new(Uuid::try_parse(&id)?, artist, title))
+            })
+            .collect()
+        })
+        .await
+    }
+}
+
+/// Match positions in seconds from the query frames of aligned hashes and the track offset
+/// relative to the query, in frames.
+fn offsets(frames: &[u32], delta: i64) -> MatchOffsets {
+    let first = frames.iter().min().copied().unwrap_or_default() as i64;
+    let last = frames.iter().max().copied().unwrap_or_default() as i64 + 1;
+    let seconds = |frame: i64| (frame * HOP_SIZE as i64) as f32 / PCM_SAMPLE_RATE as f32;
+    MatchOffsets {
+        query_start: seconds(first),
+        query_end: seconds(last),
+        track_start: seconds(first + delta),
+        track_end: seconds(last + delta),
+    }
+}
+
+/// Returns the hashes of spectral-peak pairs of mono PCM at `PCM_SAMPLE_RATE`.
+fn fingerprint(samples: &[i16]) -> Vec<Hash> {
+    let peaks = peaks(samples);
+
+    let mut hashes = Vec::new();
+    for (index, &(anchor_frame, anchor_bin)) in peaks.iter().enumerate() {
+        let targets = peaks[index + 1..]
+            .iter()
+            .skip_while(|(frame, _)| frame - anchor_frame < *TARGET_ZONE.start())
+            .take_while(|(frame, _)| frame - anchor_frame <= *TARGET_ZONE.end())
+            // A sustained tone paired with itself says little about the audio.
+            .filter(|(_, bin)| *bin != anchor_bin)
+            .take(FAN_OUT);
+        for &(target_frame, target_bin) in targets {
+            let delta = target_frame - anchor_frame;
+            let hash = (anchor_bin << 16) | (target_bin << 6) | delta;
+            hashes.push((hash, anchor_frame));
+        }
+    }
+    hashes
+}
+
+/// Returns `(frame, bin)` of the strongest bin in each band of each frame, in frame order.
+fn peaks(samples: &[i16]) -> Vec<(u32, u32)> {
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FRAME_SIZE);
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32).cos()
+        })
+        .collect();
+    let bin_of = |hz: f32| (hz * FRAME_SIZE as f32 / PCM_SAMPLE_RATE as f32) as usize;
+
+    let mut peaks = Vec::new();
+    let mut buffer = vec![Complex::default(); FRAME_SIZE];
+    for (frame, start) in (0..samples.len().saturating_sub(FRAME_SIZE))
+        .step_by(HOP_SIZE)
+        .enumerate()
+    {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(samples[start + i] as f32 * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let magnitudes: Vec<f32> = buffer[..FRAME_SIZE / 2].iter().map(|c| c.norm()).collect();
+        let mean = magnitudes.iter().sum::<f32>() / magnitudes.len() as f32;
+
+        for band in BANDS.windows(2) {
+            let (low, high) = (bin_of(band[0]), bin_of(band[1]));
+            let (bin, magnitude) =
+                magnitudes[low..high]
+                    .iter()
+                    .enumerate()
+                    .fold(
+                        (0, 0.0f32),
+                        |best, (i, &m)| if m > best.1 { (i, m) } else { best },
+                    );
+            if magnitude > mean * PEAK_RATIO && magnitude > 0.0 {
+                peaks.push((frame as u32, (low + bin) as u32));
+            }
+        }
+    }
+    peaks
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::LocalBackend;
+    use crate::config::TranscodeConfig;
+    use crate::emysound::TrackInfo;
+    use crate::transcode::{Transcoder, PCM_SAMPLE_RATE};
+
+    /// A sequence of random two-tone notes, a quarter of a second each.
+    fn melody(seed: u64, seconds: usize) -> Vec<i16> {
+        let mut state = seed;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            300.0 + (state >> 33) as f64 % 2500.0
+        };
+
+        let note = PCM_SAMPLE_RATE as usize / 4;
+        let mut samples = Vec::new();
+        for _ in 0..seconds * 4 {
+            let (f1, f2) = (next(), next());
+            samples.extend((0..note).map(|i| {
+                let t = i as f64 / PCM_SAMPLE_RATE as f64;
+                let v = (2.0 * std::f64::consts::PI * f1 * t).sin()
+                    + (2.0 * std::f64::consts::PI * f2 * t).sin();
+                (v * 6000.0) as i16
+            }));
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let backend = LocalBackend::new(&":memory:", &transcoder).unwrap();
+
+        let track = melody(1, 30);
+        let info = TrackInfo::new(Uuid::new_v4(), "Artist".to_owned(), "Title".to_owned());
+        backend
+            .insert_samples(info.clone(), track.clone())
+            .await
+            .unwrap();
+        backend
+            .insert_samples(
+                TrackInfo::new(Uuid::new_v4(), "Other".to_owned(), "Other".to_owned()),
+                melody(2, 30),
+            )
+            .await
+            .unwrap();
+
+        let rate = PCM_SAMPLE_RATE as usize;
+        let results = backend
+            .query_samples(track[10 * rate..20 * rate].to_vec())
+            .await
+            .unwrap();
+        assert_eq!(results[0].id(), info.id());
+        assert!(results[0].score() >= 80, "{results:?}");
+        let offsets = results[0].offsets().unwrap();
+        assert!(
+            (offsets.track_start - offsets.query_start - 10.0).abs() < 0.1,
+            "{offsets:?}"
+        );
+        assert!(offsets.query_end - offsets.query_start > 8.0, "{offsets:?}");
+        assert!(
+            results.iter().skip(1).all(|r| r.score() < 20),
+            "{results:?}"
+        );
+
+        let results = backend.query_samples(melody(3, 10)).await.unwrap();
+        assert!(results.iter().all(|r| r.score() < 20), "{results:?}");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        use crate::emysound::FingerprintBackend;
+
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let backend = LocalBackend::new(&":memory:", &transcoder).unwrap();
+
+        let track = melody(1, 10);
+        let info = TrackInfo::new(Uuid::new_v4(), "Artist".to_owned(), "Title".to_owned());
+        backend
+            .insert_samples(info.clone(), track.clone())
+            .await
+            .unwrap();
+        assert_eq!(backend.list(0).await.unwrap(), vec![info.clone()]);
+
+        backend.delete(info.id()).await.unwrap();
+        assert!(backend.list(0).await.unwrap().is_empty());
+        assert!(backend.query_samples(track).await.unwrap().is_empty());
+    }
+}
@@ -1,49 +1,165 @@
 use super::QueryResult;
+use crate::config::{MatchConfig, PairKey, PairRule};
+use crate::storage::AudioKind;
 
-pub fn best_results(results: Vec<QueryResult>) -> Vec<QueryResult> {
-    results
+/// Selects the results accepted as matches of a segment of `kind` under `config`, best first.
+pub fn best_results(
+    config: &MatchConfig,
+    kind: AudioKind,
+    results: Vec<QueryResult>,
+) -> Vec<QueryResult> {
+    let min_score = config.min_score(kind);
+    let rules: Vec<&PairRule> = config.pairs.iter().filter(|p| p.applies(kind)).collect();
+
+    let mut accepted: Vec<QueryResult> = results
         .iter()
         .filter(|r| {
-            if r.score() >= 75 {
+            if r.score() >= min_score {
                 true
             } else {
                 results
                     .iter()
-                    .find(|r2| {
-                        r2.id() != r.id()
-                            && (r2.artist() == r.artist() || r2.title() == r.title())
-                            && (90u8..=100).contains(&(r.score() + r2.score()))
-                    })
-                    .map(|v| {
-                        log::debug!("Result match: {r:?} - {v:?}");
-                        v
-                    })
+                    .find(|r2| rules.iter().any(|rule| pairs(rule, r, r2)))
+                    .inspect(|v| log::debug!("Result match: {r:?} - {v:?}"))
                     .is_some()
             }
         })
         .cloned()
-        .collect()
+        .collect();
+
+    accepted.sort_by_key(|r| std::cmp::Reverse(r.score()));
+    if let Some(max_results) = config.max_results {
+        accepted.truncate(max_results);
+    }
+    accepted
+}
+
+fn pairs(rule: &PairRule, r: &QueryResult, r2: &QueryResult) -> bool {
+    let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+    let key = match rule.key {
+        PairKey::Artist => same(r.artist(), r2.artist()),
+        PairKey::Title => same(r.title(), r2.title()),
+        PairKey::ArtistOrTitle => same(r.artist(), r2.artist()) || same(r.title(), r2.title()),
+        PairKey::ArtistAndTitle => same(r.artist(), r2.artist()) && same(r.title(), r2.title()),
+    };
+    let sum = r.score() as u16 + r2.score() as u16;
+    r2.id() != r.id() && key && (rule.min_sum as u16..=rule.max_sum as u16).contains(&sum)
 }
 
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
 
+    use crate::config::{MatchConfig, PairKey, PairRule};
     use crate::emysound::matcher::best_results;
     use crate::emysound::QueryResult;
+    use crate::storage::AudioKind;
 
-    #[test]
-    fn test() {
-        let make_result = |coverage: f64, artist: &str, title: &str| QueryResult {
+    fn make_result(coverage: f64, artist: &str, title: &str) -> QueryResult {
+        QueryResult {
             id: Uuid::new_v4(),
             coverage: coverage as f32,
             artist: Some(artist.to_owned()),
             title: Some(title.to_owned()),
-        };
+            offsets: None,
+        }
+    }
+
+    fn ids(results: &[QueryResult]) -> Vec<Uuid> {
+        results.iter().map(QueryResult::id).collect()
+    }
+
+    #[test]
+    fn test() {
         let single = make_result(0.9, "Artist", "Title");
         let pair_a = make_result(0.6, "A", "B");
         let pair_b = make_result(0.3, "A", "B");
+        let weak = make_result(0.5, "C", "D");
+
+        let results = best_results(
+            &MatchConfig::default(),
+            AudioKind::Music,
+            vec![pair_b.clone(), weak, single.clone(), pair_a.clone()],
+        );
+        assert_eq!(ids(&results), ids(&[single, pair_a, pair_b]));
+    }
 
-        println!("{:?}", best_results(vec![single, pair_a, pair_b]));
+    #[test]
+    fn test_kind_threshold() {
+        let result = make_result(0.65, "Advertisement", "Shop");
+        let mut config = MatchConfig::default();
+        config.kinds.advertisement = Some(60);
+
+        assert!(best_results(&config, AudioKind::Music, vec![result.clone()]).is_empty());
+        assert_eq!(
+            best_results(&config, AudioKind::Advertisement, vec![result.clone()]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_pair_rules() {
+        // Different ads, with the artist every ad has.
+        let ad_a = make_result(0.5, "Advertisement", "Shop");
+        let ad_b = make_result(0.45, "Advertisement", "Bank");
+        let results = vec![ad_a.clone(), ad_b.clone()];
+
+        let config = MatchConfig::default();
+        assert_eq!(
+            best_results(&config, AudioKind::Advertisement, results.clone()).len(),
+            2
+        );
+
+        let config = MatchConfig {
+            pairs: vec![
+                PairRule {
+                    key: PairKey::Title,
+                    kinds: vec![AudioKind::Advertisement],
+                    ..Default::default()
+                },
+                PairRule {
+                    kinds: vec![AudioKind::Music],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(best_results(&config, AudioKind::Advertisement, results.clone()).is_empty());
+        assert_eq!(
+            best_results(&config, AudioKind::Music, results.clone()).len(),
+            2
+        );
+
+        // Two parts of the same ad.
+        let part = make_result(0.45, "Advertisement", "Shop");
+        assert_eq!(
+            ids(&best_results(
+                &config,
+                AudioKind::Advertisement,
+                vec![ad_a.clone(), ad_b, part.clone()]
+            )),
+            ids(&[ad_a, part])
+        );
+
+        let config = MatchConfig {
+            pairs: Vec::new(),
+            ..Default::default()
+        };
+        assert!(best_results(&config, AudioKind::Music, results).is_empty());
+    }
+
+    #[test]
+    fn test_max_results() {
+        let results = vec![
+            make_result(0.8, "A", "A"),
+            make_result(0.95, "B", "B"),
+            make_result(0.9, "C", "C"),
+        ];
+        let config = MatchConfig {
+            max_results: Some(2),
+            ..Default::default()
+        };
+        let best = best_results(&config, AudioKind::Music, results.clone());
+        assert_eq!(ids(&best), ids(&[results[1].clone(), results[2].clone()]));
     }
 }
@@ -0,0 +1,79 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use anyhow::bail;
+use async_trait::async_trait;
+use bytes::Bytes;
+use uuid::Uuid;
+
+use super::{FingerprintBackend, QueryResult, TrackInfo};
+
+const PAGE_SIZE: usize = 10;
+
+/// In-memory backend for tests.
+///
+/// Queries return the scripted results in order, then nothing. Inserted tracks are kept, so
+/// `list` and `delete` behave like a real index.
+#[derive(Default)]
+pub struct MockBackend {
+    unavailable: Cell<bool>,
+    results: RefCell<VecDeque<Vec<QueryResult>>>,
+    tracks: RefCell<Vec<TrackInfo>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes queries, inserts and deletes fail, as if the service was down.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.set(unavailable);
+    }
+
+    /// Queues the result of a future query.
+    pub fn push_result(&self, results: Vec<QueryResult>) {
+        self.results.borrow_mut().push_back(results);
+    }
+
+    pub fn tracks(&self) -> Vec<TrackInfo> {
+        self.tracks.borrow().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl FingerprintBackend for MockBackend {
+    async fn query(&self, _filename: &str, _bytes: &Bytes) -> anyhow::Result<Vec<QueryResult>> {
+        if self.unavailable.get() {
+            bail!("Service unavailable");
+        }
+        Ok(self.results.borrow_mut().pop_front().unwrap_or_default())
+    }
+
+    async fn insert(&self, info: TrackInfo, _filename: &str, _bytes: &Bytes) -> anyhow::Result<()> {
+        if self.unavailable.get() {
+            bail!("Service unavailable");
+        }
+        self.tracks.borrow_mut().push(info);
+        Ok(())
+    }
+
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        if self.unavailable.get() {
+            bail!("Service unavailable");
+        }
+        self.tracks.borrow_mut().retain(|track| track.id != id);
+        Ok(())
+    }
+
+    async fn list(&self, offset: usize) -> anyhow::Result<Vec<TrackInfo>> {
+        Ok(self
+            .tracks
+            .borrow()
+            .iter()
+            .skip(offset)
+            .take(PAGE_SIZE)
+            .cloned()
+            .collect())
+    }
+}
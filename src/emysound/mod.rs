@@ -1,22 +1,50 @@
-mod matcher;
+mod local;
+pub mod matcher;
+#[cfg(test)]
+mod mock;
 
 use anyhow::{anyhow, Context};
+use async_trait::async_trait;
 use bytes::Bytes;
-use emycloud_client_rs::MediaSource;
+use reqwest::header::ACCEPT;
+use reqwest::multipart::{Form, Part};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use self::matcher::best_results;
+use crate::config::EmySoundConfig;
+use crate::storage::{Candidate, MatchOffsets};
 
-#[derive(Debug, Clone)]
+pub use local::LocalBackend;
+#[cfg(test)]
+pub use mock::MockBackend;
+
+#[derive(Debug, Clone, Serialize)]
 #[allow(dead_code)]
 pub struct QueryResult {
     id: Uuid,
     coverage: f32,
     artist: Option<String>,
     title: Option<String>,
+    offsets: Option<MatchOffsets>,
 }
 
 impl QueryResult {
+    pub fn new(id: Uuid, coverage: f32, artist: Option<String>, title: Option<String>) -> Self {
+        Self {
+            id,
+            coverage,
+            artist,
+            title,
+            offsets: None,
+        }
+    }
+
+    pub fn with_offsets(mut self, offsets: Option<MatchOffsets>) -> Self {
+        self.offsets = offsets;
+        self
+    }
+
     pub fn id(&self) -> Uuid {
         self.id
     }
@@ -26,8 +54,11 @@ impl QueryResult {
     pub fn title(&self) -> &Option<String> {
         &self.title
     }
+    pub fn offsets(&self) -> Option<MatchOffsets> {
+        self.offsets
+    }
     pub fn score(&self) -> u8 {
-        return if self.coverage >= 0f32 && self.coverage <= 1f32 {
+        if self.coverage >= 0f32 && self.coverage <= 1f32 {
             (self.coverage * 100f32).trunc() as u8
         } else {
             log::error!(
@@ -37,64 +68,344 @@ impl QueryResult {
                 self.coverage
             );
             0u8
-        };
+        }
+    }
+}
+
+impl From<&QueryResult> for Candidate {
+    fn from(result: &QueryResult) -> Self {
+        Self {
+            id: result.id,
+            score: result.score(),
+            artist: result.artist.clone(),
+            title: result.title.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrackInfo {
+    id: Uuid,
+    artist: String,
+    title: String,
+}
+
+impl TrackInfo {
+    pub fn new(id: Uuid, artist: String, title: String) -> Self {
+        Self { id, artist, title }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
     }
 }
 
-impl TryFrom<&emycloud_client_rs::QueryResult> for QueryResult {
+/// A fingerprint index: audio goes in with [`TrackInfo`], queries return scored candidates.
+#[async_trait(?Send)]
+pub trait FingerprintBackend {
+    /// Returns all candidates matching the audio, unfiltered.
+    async fn query(&self, filename: &str, bytes: &Bytes) -> anyhow::Result<Vec<QueryResult>>;
+    async fn insert(&self, info: TrackInfo, filename: &str, bytes: &Bytes) -> anyhow::Result<()>;
+    /// Deletes the track `id`. Succeeds if there is no such track.
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()>;
+    /// Returns one page of tracks starting at `offset`; an empty page means the end.
+    async fn list(&self, offset: usize) -> anyhow::Result<Vec<TrackInfo>>;
+
+    /// Collects all pages of tracks.
+    async fn list_all(&self) -> anyhow::Result<Vec<TrackInfo>> {
+        let mut tracks = Vec::new();
+        loop {
+            let page = self.list(tracks.len()).await?;
+            if page.is_empty() {
+                return Ok(tracks);
+            }
+            tracks.extend(page);
+        }
+    }
+}
+
+/// The EmySound service, through its REST API.
+///
+/// The API is called directly rather than through `emycloud_client_rs`, which has the endpoint
+/// and the credentials built in and takes only `minConfidence`.
+pub struct EmySound {
+    client: reqwest::Client,
+    url: String,
+    username: String,
+    password: Option<String>,
+    min_confidence: f32,
+    min_coverage: f32,
+}
+
+impl EmySound {
+    pub fn new(config: &EmySoundConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .build()?;
+
+        Ok(Self {
+            client,
+            url: config.url.trim_end_matches('/').to_owned(),
+            username: config.username.clone(),
+            password: config.password()?,
+            min_confidence: config.min_confidence,
+            min_coverage: config.min_coverage,
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}/{path}", self.url))
+            .header(ACCEPT, "application/json")
+            .basic_auth(&self.username, self.password.as_ref())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TracksPage {
+    results: Vec<Track>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Track {
+    id: String,
+    artist: Option<String>,
+    title: Option<String>,
+}
+
+impl TryFrom<Track> for TrackInfo {
     type Error = anyhow::Error;
 
-    fn try_from(value: &emycloud_client_rs::QueryResult) -> Result<Self, Self::Error> {
+    fn try_from(track: Track) -> Result<Self, Self::Error> {
+        Ok(TrackInfo::new(
+            Uuid::try_parse(&track.id).context("Parsing uuid")?,
+            track.artist.unwrap_or_default(),
+            track.title.unwrap_or_default(),
+        ))
+    }
+}
+
+/// One element of the `Query` response.
+#[derive(Debug, Deserialize)]
+struct QueryMatch {
+    track: Track,
+    audio: Option<AudioMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AudioMatch {
+    coverage: Coverage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Coverage {
+    query_coverage: Option<f32>,
+    query_match_starts_at: Option<f32>,
+    query_coverage_with_permitted_gaps_length: Option<f32>,
+    track_match_starts_at: Option<f32>,
+    track_coverage_with_permitted_gaps_length: Option<f32>,
+}
+
+impl Coverage {
+    /// Match positions, if the response has all of them.
+    fn offsets(&self) -> Option<MatchOffsets> {
+        let query_start = self.query_match_starts_at?;
+        let track_start = self.track_match_starts_at?;
+        Some(MatchOffsets {
+            query_start,
+            query_end: query_start + self.query_coverage_with_permitted_gaps_length?,
+            track_start,
+            track_end: track_start + self.track_coverage_with_permitted_gaps_length?,
+        })
+    }
+}
+
+impl TryFrom<QueryMatch> for QueryResult {
+    type Error = anyhow::Error;
+
+    fn try_from(value: QueryMatch) -> Result<Self, Self::Error> {
         let id = Uuid::try_parse(&value.track.id).context("Parsing uuid")?;
         let coverage = value
             .audio
             .as_ref()
             .and_then(|audio| audio.coverage.query_coverage)
             .ok_or_else(|| anyhow!("Failed to get coverage"))?;
-        let artist = value.track.artist.clone();
-        let title = value.track.title.clone();
 
-        Ok(Self {
-            id,
-            coverage,
-            artist,
-            title,
-        })
+        let offsets = value.audio.and_then(|audio| audio.coverage.offsets());
+
+        Ok(Self::new(id, coverage, value.track.artist, value.track.title).with_offsets(offsets))
     }
 }
 
-const MIN_CONFIDENCE: f32 = 0.2f32;
+fn file_part(filename: &str, bytes: &Bytes) -> Part {
+    Part::bytes(bytes.to_vec()).file_name(filename.to_owned())
+}
 
-pub async fn query(filename: &str, bytes: &Bytes) -> anyhow::Result<Vec<QueryResult>> {
-    let source = MediaSource::Bytes(filename, bytes);
+#[async_trait(?Send)]
+impl FingerprintBackend for EmySound {
+    async fn query(&self, filename: &str, bytes: &Bytes) -> anyhow::Result<Vec<QueryResult>> {
+        self.request(reqwest::Method::POST, "Query")
+            .query(&[("mediaType", "Audio")])
+            .query(&[
+                ("minConfidence", self.min_confidence),
+                ("minCoverage", self.min_coverage),
+            ])
+            .multipart(Form::new().part("file", file_part(filename, bytes)))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("EmySound::query")?
+            .json::<Vec<QueryMatch>>()
+            .await
+            .context("EmySound::query")?
+            .into_iter()
+            .map(QueryResult::try_from)
+            .inspect(|result| log::debug!("{result:?}"))
+            .collect()
+    }
 
-    emycloud_client_rs::query(source, MIN_CONFIDENCE)
-        .await
-        .context("EmySound::query")?
-        .iter()
-        .map(|result| result.try_into())
-        .inspect(|result| log::debug!("{result:?}"))
-        .collect::<anyhow::Result<Vec<_>>>()
-        .map(best_results)
-}
+    async fn insert(&self, info: TrackInfo, filename: &str, bytes: &Bytes) -> anyhow::Result<()> {
+        let form = Form::new()
+            .part("file", file_part(filename, bytes))
+            .text("Id", info.id.to_string())
+            .text("Artist", info.artist)
+            .text("Title", info.title)
+            .text("MediaType", "Audio");
 
-#[derive(Debug)]
-pub struct TrackInfo {
-    id: Uuid,
-    artist: String,
-    title: String,
-}
+        self.request(reqwest::Method::POST, "Tracks")
+            .multipart(form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("EmySound::insert")?;
+        Ok(())
+    }
 
-impl TrackInfo {
-    pub fn new(id: Uuid, artist: String, title: String) -> Self {
-        Self { id, artist, title }
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        let response = self
+            .request(reqwest::Method::DELETE, &format!("Tracks/{id}"))
+            .send()
+            .await
+            .context("EmySound::delete")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            log::debug!("EmySound::delete: no track {id}");
+            return Ok(());
+        }
+        response.error_for_status().context("EmySound::delete")?;
+        Ok(())
+    }
+
+    async fn list(&self, offset: usize) -> anyhow::Result<Vec<TrackInfo>> {
+        self.request(reqwest::Method::GET, "Tracks")
+            .query(&[("offset", offset)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("EmySound::list")?
+            .json::<TracksPage>()
+            .await
+            .context("EmySound::list")?
+            .results
+            .into_iter()
+            .map(TrackInfo::try_from)
+            .collect()
     }
 }
 
-pub async fn insert(info: TrackInfo, filename: &str, bytes: &Bytes) -> anyhow::Result<()> {
-    let source = MediaSource::Bytes(filename, bytes);
+#[cfg(test)]
+mod tests {
+    use super::{QueryMatch, QueryResult};
+
+    #[test]
+    fn test_query_response() {
+        let response = r#"[
+            {
+                "track": {"id": "3db4a3bd-1bfe-4bc3-9297-417d645b8bda", "artist": "Artist", "title": "Title"},
+                "audio": {"coverage": {
+                    "queryCoverage": 0.85,
+                    "trackCoverage": 0.1,
+                    "queryMatchStartsAt": 0.5,
+                    "queryCoverageWithPermittedGapsLength": 8.5,
+                    "trackMatchStartsAt": 30.0,
+                    "trackCoverageWithPermittedGapsLength": 8.5
+                }}
+            },
+            {
+                "track": {"id": "0b7a4a63-5c0a-4d5a-8b9e-2f0f4d7e8a11", "artist": null, "title": null},
+                "audio": {"coverage": {"queryCoverage": 0.5}}
+            },
+            {
+                "track": {"id": "ea9ad425-013a-4150-9fa0-675361b82af1", "artist": null, "title": null},
+                "audio": null
+            }
+        ]"#;
+
+        let matches: Vec<QueryMatch> = serde_json::from_str(response).unwrap();
+        let results: Vec<anyhow::Result<QueryResult>> =
+            matches.into_iter().map(QueryResult::try_from).collect();
 
-    emycloud_client_rs::insert(source, info.id, info.artist, info.title)
-        .await
-        .context("EmySound::insert")
+        let result = results[0].as_ref().unwrap();
+        assert_eq!(result.score(), 85);
+        assert_eq!(result.artist().as_deref(), Some("Artist"));
+        let offsets = result.offsets().unwrap();
+        assert_eq!((offsets.query_start, offsets.query_end), (0.5, 9.0));
+        assert_eq!((offsets.track_start, offsets.track_end), (30.0, 38.5));
+
+        assert_eq!(results[1].as_ref().unwrap().score(), 50);
+        assert!(results[1].as_ref().unwrap().offsets().is_none());
+        assert!(results[2].is_err());
+    }
+
+    #[test]
+    fn test_query_offsets() {
+        let response = r#"[{
+            "id": "8d1d7c8e-2b49-4f0e-9d1a-6b3a3c1e2f10",
+            "track": {
+                "id": "5b0f3c7a-91d4-4e55-a0e2-1c7d9e8f6a42",
+                "artist": "Ennio Morricone",
+                "title": "Il Buono, il Cattivo, il Brutto",
+                "mediaType": "Audio",
+                "metaFields": {}
+            },
+            "audio": {
+                "score": 41.5,
+                "coverage": {
+                    "queryMatchStartsAt": 1.3003175,
+                    "trackMatchStartsAt": 104.4273,
+                    "queryCoverageWithPermittedGapsLength": 8.173424,
+                    "trackCoverageWithPermittedGapsLength": 8.173424,
+                    "queryDiscreteCoverageLength": 7.987664,
+                    "trackDiscreteCoverageLength": 7.987664,
+                    "queryLength": 9.98,
+                    "trackLength": 246.04,
+                    "queryCoverage": 0.81898,
+                    "trackCoverage": 0.03322,
+                    "queryGaps": [],
+                    "trackGaps": []
+                },
+                "queryMatchId": "4f0a2b61-3a9e-4c38-8f0e-5d1b6f2e9c77",
+                "matchedAt": "2022-05-11T07:13:54.541Z"
+            }
+        }]"#;
+
+        let matches: Vec<QueryMatch> = serde_json::from_str(response).unwrap();
+        let result = QueryResult::try_from(matches.into_iter().next().unwrap()).unwrap();
+
+        assert_eq!(result.score(), 81);
+        let offsets = result.offsets().unwrap();
+        assert_eq!(offsets.query_start, 1.3003175);
+        assert!((offsets.query_end - 9.473742).abs() < 1e-4);
+        assert_eq!(offsets.track_start, 104.4273);
+        assert!((offsets.track_end - 112.60072).abs() < 1e-4);
+    }
 }
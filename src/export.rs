@@ -0,0 +1,722 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use arrow_array::builder::TimestampMicrosecondBuilder;
+use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, SecondsFormat, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::json;
+
+use crate::storage::{Airplay, AirplayFilter, MatchData, MatchesStorage, Metadata};
+use crate::storage::{MetadataFilter, MetadataStorage};
+
+/// Rows read from the database at a time. Only one page is held in memory.
+const PAGE_SIZE: usize = 10_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+    Parquet,
+}
+
+impl TryFrom<&str> for ExportFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(anyhow::anyhow!("Invalid format value={value}")),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportTable {
+    Metadata,
+    Matches,
+    Airplays,
+    /// Times on a station between plays, when nothing was recognized.
+    Gaps,
+}
+
+impl TryFrom<&str> for ExportTable {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "metadata" => Ok(ExportTable::Metadata),
+            "matches" => Ok(ExportTable::Matches),
+            "airplays" => Ok(ExportTable::Airplays),
+            "gaps" => Ok(ExportTable::Gaps),
+            _ => Err(anyhow::anyhow!("Invalid table value={value}")),
+        }
+    }
+}
+
+impl ExportTable {
+    fn columns(&self) -> &'static [(&'static str, ColumnType)] {
+        use ColumnType::*;
+        match self {
+            ExportTable::Metadata => &[
+                ("id", Text),
+                ("date", Time),
+                ("kind", Text),
+                ("artist", Text),
+                ("title", Text),
+                ("station", Text),
+                ("segment_uri", Text),
+                ("media_sequence", Integer),
+                ("aired", Time),
+                ("extinf_duration_ms", Integer),
+                ("extinf_title", Text),
+                ("song_spot", Text),
+                ("media_base_id", Integer),
+                ("itunes_track_id", Integer),
+                ("amg_track_id", Integer),
+                ("amg_artist_id", Integer),
+                ("ta_id", Integer),
+                ("tp_id", Integer),
+                ("cartcut_id", Integer),
+                ("artwork_url", Text),
+                ("length_seconds", Integer),
+                ("uns_id", Integer),
+                ("spot_instance_id", Text),
+            ],
+            ExportTable::Matches => &[
+                ("id", Text),
+                ("timestamp", Time),
+                ("score", Integer),
+                ("query_start", Real),
+                ("query_end", Real),
+                ("track_start", Real),
+                ("track_end", Real),
+                ("station", Text),
+                ("media_sequence", Integer),
+                ("aired", Time),
+                ("query_id", Text),
+                // JSON array of the other results of the segment.
+                ("rejected", Text),
+            ],
+            ExportTable::Airplays => &[
+                ("id", Integer),
+                ("station", Text),
+                ("track_id", Text),
+                ("start", Time),
+                ("end", Time),
+                ("duration_seconds", Real),
+                ("segments", Integer),
+                ("mean_score", Real),
+                ("max_score", Integer),
+            ],
+            ExportTable::Gaps => &[
+                ("station", Text),
+                ("start", Time),
+                ("end", Time),
+                ("duration_seconds", Real),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ColumnType {
+    Integer,
+    Real,
+    Text,
+    Time,
+}
+
+/// A cell of an exported row, of the type of its column or null.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Time(DateTime<Utc>),
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+
+macro_rules! value_from {
+    ($variant:ident, $($type:ty),+) => {
+        $(impl From<$type> for Value {
+            fn from(value: $type) -> Self {
+                Value::$variant(value as _)
+            }
+        })+
+    };
+}
+value_from!(Integer, i64, u64, u32, u8);
+value_from!(Real, f64, f32);
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_owned())
+    }
+}
+
+impl From<DateTime<Utc>> for Value {
+    fn from(value: DateTime<Utc>) -> Self {
+        Value::Time(value)
+    }
+}
+
+impl Value {
+    /// The cell as CSV field, empty if null.
+    fn to_field(&self) -> String {
+        match self {
+            Value::Null => String::new(),
+            Value::Integer(value) => value.to_string(),
+            Value::Real(value) => value.to_string(),
+            Value::Text(value) => value.clone(),
+            Value::Time(value) => value.to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Null => serde_json::Value::Null,
+            Value::Integer(value) => json!(value),
+            Value::Real(value) => json!(value),
+            Value::Text(value) => json!(value),
+            Value::Time(value) => json!(value.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        }
+    }
+}
+
+type Row = Vec<Value>;
+
+fn metadata_row(item: &Metadata) -> Row {
+    let source = item.source();
+    let catalog = source.catalog.as_ref();
+    vec![
+        item.id.to_string().into(),
+        item.date().into(),
+        item.kind().to_string().into(),
+        item.artist().into(),
+        item.title().into(),
+        source.station.clone().into(),
+        source.uri.clone().into(),
+        source.media_sequence.into(),
+        source.aired.into(),
+        source.duration.map(|d| d.as_millis() as i64).into(),
+        source.raw_title.clone().into(),
+        catalog.map(|c| c.song_spot.clone()).into(),
+        catalog.map(|c| c.media_base_id).into(),
+        catalog.map(|c| c.itunes_track_id).into(),
+        catalog.map(|c| c.amg_track_id).into(),
+        catalog.map(|c| c.amg_artist_id).into(),
+        catalog.map(|c| c.ta_id).into(),
+        catalog.map(|c| c.tp_id).into(),
+        catalog.map(|c| c.cartcut_id).into(),
+        catalog.and_then(|c| c.artwork_url.clone()).into(),
+        catalog.map(|c| c.length.as_secs()).into(),
+        catalog.map(|c| c.uns_id).into(),
+        catalog
+            .and_then(|c| c.spot_instance_id)
+            .map(|id| id.to_string())
+            .into(),
+    ]
+}
+
+fn match_row(data: &MatchData) -> anyhow::Result<Row> {
+    let offsets = data.offsets();
+    let source = data.source();
+    Ok(vec![
+        data.id().to_string().into(),
+        data.timestamp().into(),
+        data.score().into(),
+        offsets.map(|o| o.query_start).into(),
+        offsets.map(|o| o.query_end).into(),
+        offsets.map(|o| o.track_start).into(),
+        offsets.map(|o| o.track_end).into(),
+        source.station.clone().into(),
+        source.media_sequence.into(),
+        source.aired.into(),
+        source.query_id.map(|id| id.to_string()).into(),
+        serde_json::to_string(&source.rejected)?.into(),
+    ])
+}
+
+fn airplay_row(airplay: &Airplay) -> Row {
+    vec![
+        airplay.id.into(),
+        airplay.station.as_str().into(),
+        airplay.track_id.to_string().into(),
+        airplay.start.into(),
+        airplay.end.into(),
+        seconds(airplay.duration()).into(),
+        airplay.segments.into(),
+        airplay.mean_score().into(),
+        airplay.max_score.into(),
+    ]
+}
+
+fn seconds(duration: chrono::Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+/// Writes rows in a format, the header or schema first.
+trait RowWriter {
+    fn write(&mut self, rows: &[Row]) -> anyhow::Result<()>;
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+struct CsvWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    fn new(out: W, columns: &[(&str, ColumnType)]) -> anyhow::Result<Self> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(columns.iter().map(|(name, _)| name))?;
+        Ok(Self { writer })
+    }
+}
+
+impl<W: Write> RowWriter for CsvWriter<W> {
+    fn write(&mut self, rows: &[Row]) -> anyhow::Result<()> {
+        for row in rows {
+            self.writer.write_record(row.iter().map(Value::to_field))?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct JsonlWriter<W: Write> {
+    out: W,
+    columns: &'static [(&'static str, ColumnType)],
+}
+
+impl<W: Write> RowWriter for JsonlWriter<W> {
+    fn write(&mut self, rows: &[Row]) -> anyhow::Result<()> {
+        for row in rows {
+            let object: serde_json::Map<String, serde_json::Value> = self
+                .columns
+                .iter()
+                .zip(row)
+                .map(|((name, _), value)| (name.to_string(), value.to_json()))
+                .collect();
+            serde_json::to_writer(&mut self.out, &object)?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes each page of rows as a record batch. Times are microseconds since the epoch in UTC.
+struct ParquetWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    columns: &'static [(&'static str, ColumnType)],
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(out: W, columns: &'static [(&'static str, ColumnType)]) -> anyhow::Result<Self> {
+        let schema = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|(name, column)| {
+                    let data_type = match column {
+                        ColumnType::Integer => DataType::Int64,
+                        ColumnType::Real => DataType::Float64,
+                        ColumnType::Text => DataType::Utf8,
+                        ColumnType::Time => {
+                            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+                        }
+                    };
+                    Field::new(*name, data_type, true)
+                })
+                .collect::<Vec<_>>(),
+        ));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(Self {
+            writer: ArrowWriter::try_new(out, schema.clone(), Some(properties))?,
+            schema,
+            columns,
+        })
+    }
+
+    fn column(column: ColumnType, index: usize, rows: &[Row]) -> anyhow::Result<ArrayRef> {
+        let cells = rows.iter().map(|row| &row[index]);
+        let mismatch = |value: &Value| anyhow::anyhow!("{value:?} in a {column:?} column");
+        Ok(match column {
+            ColumnType::Integer => {
+                let mut builder = Int64Builder::with_capacity(rows.len());
+                for value in cells {
+                    match value {
+                        Value::Integer(value) => builder.append_value(*value),
+                        Value::Null => builder.append_null(),
+                        value => return Err(mismatch(value)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Real => {
+                let mut builder = Float64Builder::with_capacity(rows.len());
+                for value in cells {
+                    match value {
+                        Value::Real(value) => builder.append_value(*value),
+                        Value::Null => builder.append_null(),
+                        value => return Err(mismatch(value)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Text => {
+                let mut builder = StringBuilder::new();
+                for value in cells {
+                    match value {
+                        Value::Text(value) => builder.append_value(value),
+                        Value::Null => builder.append_null(),
+                        value => return Err(mismatch(value)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Time => {
+                let mut builder =
+                    TimestampMicrosecondBuilder::with_capacity(rows.len()).with_timezone("UTC");
+                for value in cells {
+                    match value {
+                        Value::Time(value) => builder.append_value(value.timestamp_micros()),
+                        Value::Null => builder.append_null(),
+                        value => return Err(mismatch(value)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+        })
+    }
+}
+
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write(&mut self, rows: &[Row]) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let columns = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, (name, column))| {
+                Self::column(*column, index, rows).with_context(|| format!("Column {name}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()> {
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// Writes stored data to files, reading it page by page.
+pub struct Exporter<'a> {
+    metadata_storage: &'a MetadataStorage,
+    matches_storage: &'a MatchesStorage,
+    /// Shortest time between plays on a station reported as a gap.
+    min_gap: chrono::Duration,
+}
+
+impl<'a> Exporter<'a> {
+    pub fn new(
+        metadata_storage: &'a MetadataStorage,
+        matches_storage: &'a MatchesStorage,
+        min_gap: chrono::Duration,
+    ) -> Self {
+        Self {
+            metadata_storage,
+            matches_storage,
+            min_gap,
+        }
+    }
+
+    /// Writes the rows of `table` matching `filter` to a new file at `path`. Returns the number
+    /// of rows written.
+    ///
+    /// Items are filtered by their date, and gaps by the plays around them.
+    pub async fn export(
+        &self,
+        table: ExportTable,
+        filter: &AirplayFilter,
+        format: ExportFormat,
+        path: &Path,
+    ) -> anyhow::Result<usize> {
+        let file = BufWriter::new(
+            File::create(path).with_context(|| format!("Create {}", path.display()))?,
+        );
+        let columns = table.columns();
+        let mut writer: Box<dyn RowWriter> = match format {
+            ExportFormat::Csv => Box::new(CsvWriter::new(file, columns)?),
+            ExportFormat::Jsonl => Box::new(JsonlWriter { out: file, columns }),
+            ExportFormat::Parquet => Box::new(ParquetWriter::new(file, columns)?),
+        };
+
+        let rows = match table {
+            ExportTable::Metadata => self.metadata(filter, writer.as_mut()).await?,
+            ExportTable::Matches => self.matches(filter, writer.as_mut()).await?,
+            ExportTable::Airplays => self.airplays(filter, writer.as_mut()).await?,
+            ExportTable::Gaps => self.gaps(filter, writer.as_mut()).await?,
+        };
+        writer.finish()?;
+        Ok(rows)
+    }
+
+    async fn metadata(
+        &self,
+        filter: &AirplayFilter,
+        writer: &mut dyn RowWriter,
+    ) -> anyhow::Result<usize> {
+        let filter = MetadataFilter {
+            kinds: filter.kind.into_iter().collect(),
+            station: filter.station.clone(),
+            since: filter.since,
+            until: filter.until,
+            ..Default::default()
+        };
+        let mut rows = 0;
+        let mut after = None;
+        loop {
+            let page = self
+                .metadata_storage
+                .after(&filter, after.as_ref(), PAGE_SIZE)
+                .await?;
+            writer.write(&page.iter().map(metadata_row).collect::<Vec<_>>())?;
+            rows += page.len();
+            if page.len() < PAGE_SIZE {
+                return Ok(rows);
+            }
+            after = page.last().cloned();
+        }
+    }
+
+    async fn matches(
+        &self,
+        filter: &AirplayFilter,
+        writer: &mut dyn RowWriter,
+    ) -> anyhow::Result<usize> {
+        let mut rows = 0;
+        let mut after = None;
+        loop {
+            let page = self
+                .matches_storage
+                .matches_after(filter, after, PAGE_SIZE)
+                .await?;
+            writer.write(
+                &page
+                    .iter()
+                    .map(|(_, data)| match_row(data))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            )?;
+            rows += page.len();
+            if page.len() < PAGE_SIZE {
+                return Ok(rows);
+            }
+            after = page.last().map(|(rowid, data)| (data.timestamp(), *rowid));
+        }
+    }
+
+    async fn airplays(
+        &self,
+        filter: &AirplayFilter,
+        writer: &mut dyn RowWriter,
+    ) -> anyhow::Result<usize> {
+        let mut rows = 0;
+        let mut after = None;
+        loop {
+            let page = self
+                .matches_storage
+                .airplays_after(filter, after.as_ref(), PAGE_SIZE)
+                .await?;
+            writer.write(&page.iter().map(airplay_row).collect::<Vec<_>>())?;
+            rows += page.len();
+            if page.len() < PAGE_SIZE {
+                return Ok(rows);
+            }
+            after = page.last().cloned();
+        }
+    }
+
+    /// Plays come in order of start, so a gap on a station is a play starting more than
+    /// `min_gap` after every earlier play there ended.
+    async fn gaps(
+        &self,
+        filter: &AirplayFilter,
+        writer: &mut dyn RowWriter,
+    ) -> anyhow::Result<usize> {
+        let mut ends: HashMap<String, DateTime<Utc>> = HashMap::new();
+        let mut rows = 0;
+        let mut after = None;
+        loop {
+            let page = self
+                .matches_storage
+                .airplays_after(filter, after.as_ref(), PAGE_SIZE)
+                .await?;
+            let mut gaps = Vec::new();
+            for airplay in &page {
+                match ends.get_mut(&airplay.station) {
+                    Some(end) => {
+                        if airplay.start - *end > self.min_gap {
+                            gaps.push(vec![
+                                airplay.station.as_str().into(),
+                                (*end).into(),
+                                airplay.start.into(),
+                                seconds(airplay.start - *end).into(),
+                            ]);
+                        }
+                        *end = (*end).max(airplay.end);
+                    }
+                    None => {
+                        ends.insert(airplay.station.clone(), airplay.end);
+                    }
+                }
+            }
+            writer.write(&gaps)?;
+            rows += gaps.len();
+            if page.len() < PAGE_SIZE {
+                return Ok(rows);
+            }
+            after = page.last().cloned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use arrow_array::RecordBatch;
+    use chrono::{Duration, TimeZone, Utc};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use uuid::Uuid;
+
+    use super::{ExportFormat, ExportTable, Exporter};
+    use crate::storage::MetadataStorage;
+    use crate::storage::{AirplayFilter, Database, MatchData, MatchSource, MatchesStorage};
+
+    #[tokio::test]
+    async fn test_gaps() {
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let start = Utc.with_ymd_and_hms(2022, 6, 1, 10, 0, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let gap = Duration::seconds(30);
+
+        let (song, ad) = (Uuid::new_v4(), Uuid::new_v4());
+        for (station, id, seconds) in [
+            ("kosta", song, 0),
+            ("kosta", song, 20),
+            ("kosta", song, 40),
+            // Overlaps the song.
+            ("kosta", ad, 30),
+            ("kosta", ad, 60),
+            // Within the gap of the ad.
+            ("kosta", song, 80),
+            // Ten minutes later.
+            ("kosta", song, 700),
+            ("other", song, 500),
+        ] {
+            matches_storage
+                .record_airplay(station, id, at(seconds), 90, gap)
+                .await
+                .unwrap();
+        }
+        matches_storage
+            .insert(&MatchData::new(song, at(700), 90).with_source(MatchSource {
+                station: Some("kosta".to_owned()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("export-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let exporter = Exporter::new(&metadata_storage, &matches_storage, gap);
+        let filter = AirplayFilter::default();
+
+        let path = dir.join("gaps.csv");
+        let rows = exporter
+            .export(ExportTable::Gaps, &filter, ExportFormat::Csv, &path)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "station,start,end,duration_seconds\n\
+            kosta,2022-06-01T10:01:20.000Z,2022-06-01T10:11:40.000Z,620\n"
+        );
+
+        let path = dir.join("airplays.jsonl");
+        let rows = exporter
+            .export(ExportTable::Airplays, &filter, ExportFormat::Jsonl, &path)
+            .await
+            .unwrap();
+        assert_eq!(rows, 5);
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["station"], "kosta");
+        assert_eq!(lines[0]["start"], "2022-06-01T10:00:00.000Z");
+        assert_eq!(lines[0]["segments"], 3);
+
+        let path = dir.join("matches.parquet");
+        exporter
+            .export(ExportTable::Matches, &filter, ExportFormat::Parquet, &path)
+            .await
+            .unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 1);
+        assert_eq!(batches[0].schema().field(1).name(), "timestamp");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+//! In-band ID3 metadata of HLS audio segments.
+//!
+//! Packed audio segments (AAC, MP3) start with ID3v2 tags, MPEG-TS segments carry timed ID3 in
+//! a PES stream of type 0x15 (metadata). Only text, `TXXX` and `PRIV` frames are read.
+
+use std::collections::HashMap;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const TS_STREAM_TYPE_METADATA: u8 = 0x15;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Id3Metadata {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    /// `TXXX` frames, description to value.
+    pub user_text: Vec<(String, String)>,
+    /// `PRIV` frames, owner to data.
+    pub private: Vec<(String, Vec<u8>)>,
+}
+
+impl Id3Metadata {
+    fn is_empty(&self) -> bool {
+        self.artist.is_none()
+            && self.title.is_none()
+            && self.user_text.is_empty()
+            && self.private.is_empty()
+    }
+
+    /// Merges a later tag into this one; later values win.
+    fn merge(&mut self, other: Id3Metadata) {
+        if other.artist.is_some() {
+            self.artist = other.artist;
+        }
+        if other.title.is_some() {
+            self.title = other.title;
+        }
+        self.user_text.extend(other.user_text);
+        self.private.extend(other.private);
+    }
+}
+
+/// Extracts ID3 metadata from a segment, either packed audio or MPEG-TS.
+pub fn extract(bytes: &[u8]) -> Option<Id3Metadata> {
+    let tags = if is_transport_stream(bytes) {
+        demux_metadata(bytes)
+    } else {
+        vec![bytes.to_vec()]
+    };
+
+    let mut metadata = Id3Metadata::default();
+    for tag in tags {
+        let mut rest = tag.as_slice();
+        while let Some((parsed, size)) = parse_tag(rest) {
+            metadata.merge(parsed);
+            // The footer of a truncated tag may be missing.
+            rest = match rest.get(size..) {
+                Some(rest) => rest,
+                None => break,
+            };
+        }
+    }
+
+    (!metadata.is_empty()).then_some(metadata)
+}
+
+fn is_transport_stream(bytes: &[u8]) -> bool {
+    bytes.len() >= TS_PACKET_SIZE * 2
+        && bytes[0] == TS_SYNC_BYTE
+        && bytes[TS_PACKET_SIZE] == TS_SYNC_BYTE
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7f) as usize)
+}
+
+/// Parses one ID3v2.3/2.4 tag at the start of `bytes`, returning it and its total size.
+fn parse_tag(bytes: &[u8]) -> Option<(Id3Metadata, usize)> {
+    if bytes.len() < 10 || &bytes[0..3] != b"ID3" {
+        return None;
+    }
+    let version = bytes[3];
+    if version != 3 && version != 4 {
+        return None;
+    }
+    let flags = bytes[5];
+    let size = syncsafe(&bytes[6..10]);
+    let total = 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+    let body = bytes.get(10..10 + size)?;
+
+    let mut frames = body;
+    if flags & 0x40 != 0 {
+        // Extended header, its size includes itself in v2.4 but not in v2.3.
+        let ext = frames.get(0..4)?;
+        let ext_size = if version == 4 {
+            syncsafe(ext)
+        } else {
+            u32::from_be_bytes([ext[0], ext[1], ext[2], ext[3]]) as usize + 4
+        };
+        frames = frames.get(ext_size..)?;
+    }
+
+    let mut metadata = Id3Metadata::default();
+    while frames.len() >= 10 && frames[0] != 0 {
+        let id = &frames[0..4];
+        let frame_size = if version == 4 {
+            syncsafe(&frames[4..8])
+        } else {
+            u32::from_be_bytes([frames[4], frames[5], frames[6], frames[7]]) as usize
+        };
+        let data = match frames.get(10..10 + frame_size) {
+            Some(data) => data,
+            None => break,
+        };
+
+        match id {
+            b"TIT2" => metadata.title = decode_text(data),
+            b"TPE1" => metadata.artist = decode_text(data),
+            b"TXXX" => {
+                if let Some((description, value)) = decode_user_text(data) {
+                    match description.to_lowercase().as_str() {
+                        "title" if metadata.title.is_none() => metadata.title = Some(value.clone()),
+                        "artist" if metadata.artist.is_none() => {
+                            metadata.artist = Some(value.clone())
+                        }
+                        _ => {}
+                    }
+                    metadata.user_text.push((description, value));
+                }
+            }
+            b"PRIV" => {
+                if let Some(end) = data.iter().position(|&b| b == 0) {
+                    let owner = String::from_utf8_lossy(&data[..end]).into_owned();
+                    metadata.private.push((owner, data[end + 1..].to_vec()));
+                }
+            }
+            _ => {}
+        }
+
+        frames = &frames[10 + frame_size..];
+    }
+
+    Some((metadata, total))
+}
+
+/// Decodes a text frame: an encoding byte followed by the text.
+fn decode_text(data: &[u8]) -> Option<String> {
+    let (&encoding, text) = data.split_first()?;
+    let text = decode_string(encoding, text);
+    let text = text.trim_end_matches('\0').trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+/// Decodes a `TXXX` frame: an encoding byte, a terminated description and the value.
+fn decode_user_text(data: &[u8]) -> Option<(String, String)> {
+    let (&encoding, rest) = data.split_first()?;
+    let wide = encoding == 1 || encoding == 2;
+
+    let split = if wide {
+        rest.chunks_exact(2)
+            .position(|pair| pair == [0, 0])
+            .map(|i| (i * 2, i * 2 + 2))
+    } else {
+        rest.iter().position(|&b| b == 0).map(|i| (i, i + 1))
+    }?;
+
+    let description = decode_string(encoding, &rest[..split.0]);
+    let value = decode_string(encoding, &rest[split.1..]);
+    Some((
+        description.trim_end_matches('\0').to_owned(),
+        value.trim_end_matches('\0').to_owned(),
+    ))
+}
+
+fn decode_string(encoding: u8, bytes: &[u8]) -> String {
+    match encoding {
+        0 => bytes.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let (big_endian, bytes) = match bytes {
+                [0xfe, 0xff, rest @ ..] => (true, rest),
+                [0xff, 0xfe, rest @ ..] => (false, rest),
+                _ => (encoding == 2, bytes),
+            };
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| {
+                    if big_endian {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Returns the payloads of PES packets of all metadata streams in a transport stream.
+fn demux_metadata(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut pmt_pids = Vec::new();
+    let mut metadata_pids = Vec::new();
+    let mut pes: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut payloads = Vec::new();
+
+    for packet in bytes.chunks_exact(TS_PACKET_SIZE) {
+        if packet[0] != TS_SYNC_BYTE {
+            continue;
+        }
+        let pid = (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16;
+        let unit_start = packet[1] & 0x40 != 0;
+        let adaptation = (packet[3] >> 4) & 0x3;
+
+        let mut offset = 4;
+        if adaptation & 0x2 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation & 0x1 == 0 || offset >= TS_PACKET_SIZE {
+            continue;
+        }
+        let payload = &packet[offset..];
+
+        if pid == 0 && unit_start {
+            pmt_pids = parse_pat(payload);
+        } else if pmt_pids.contains(&pid) && unit_start {
+            for metadata_pid in parse_pmt(payload) {
+                if !metadata_pids.contains(&metadata_pid) {
+                    metadata_pids.push(metadata_pid);
+                }
+            }
+        } else if metadata_pids.contains(&pid) {
+            if unit_start {
+                if let Some(previous) = pes.remove(&pid) {
+                    payloads.extend(pes_payload(&previous));
+                }
+                pes.insert(pid, payload.to_vec());
+            } else if let Some(buffer) = pes.get_mut(&pid) {
+                buffer.extend_from_slice(payload);
+            }
+        }
+    }
+
+    payloads.extend(pes.values().filter_map(|buffer| pes_payload(buffer)));
+    payloads
+}
+
+/// Returns the table of a PSI section payload, after the pointer field.
+fn psi_table(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let table = payload.get(1 + pointer..)?;
+    let section_length = (((*table.get(1)? & 0x0f) as usize) << 8) | *table.get(2)? as usize;
+    // Section data up to, but not including, the CRC.
+    table.get(..(3 + section_length).checked_sub(4)?)
+}
+
+fn parse_pat(payload: &[u8]) -> Vec<u16> {
+    psi_table(payload)
+        .and_then(|table| table.get(8..))
+        .map(|programs| {
+            programs
+                .chunks_exact(4)
+                .filter(|entry| entry[0] != 0 || entry[1] != 0)
+                .map(|entry| (((entry[2] & 0x1f) as u16) << 8) | entry[3] as u16)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_pmt(payload: &[u8]) -> Vec<u16> {
+    let mut pids = Vec::new();
+    let table = match psi_table(payload) {
+        Some(table) if table.len() >= 12 => table,
+        _ => return pids,
+    };
+
+    let program_info_length = (((table[10] & 0x0f) as usize) << 8) | table[11] as usize;
+    let mut streams = table.get(12 + program_info_length..).unwrap_or_default();
+    while streams.len() >= 5 {
+        let stream_type = streams[0];
+        let pid = (((streams[1] & 0x1f) as u16) << 8) | streams[2] as u16;
+        let info_length = (((streams[3] & 0x0f) as usize) << 8) | streams[4] as usize;
+        if stream_type == TS_STREAM_TYPE_METADATA {
+            pids.push(pid);
+        }
+        streams = streams.get(5 + info_length..).unwrap_or_default();
+    }
+    pids
+}
+
+fn pes_payload(pes: &[u8]) -> Option<Vec<u8>> {
+    if pes.get(0..3)? != [0, 0, 1] {
+        return None;
+    }
+    let header_length = *pes.get(8)? as usize;
+    pes.get(9 + header_length..).map(|payload| payload.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, Id3Metadata, TS_PACKET_SIZE};
+
+    fn frame(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let size = data.len() as u32;
+        let size = [
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ];
+        [id, &size, &[0, 0], data].concat()
+    }
+
+    fn tag(frames: &[Vec<u8>]) -> Vec<u8> {
+        let body = frames.concat();
+        let size = body.len() as u32;
+        let size = [
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ];
+        [b"ID3".as_ref(), &[4, 0, 0], &size, &body].concat()
+    }
+
+    fn sample_tag() -> Vec<u8> {
+        tag(&[
+            frame(b"TIT2", b"\x03Title"),
+            frame(b"TPE1", b"\x01\xff\xfeA\0r\0t\0i\0s\0t\0"),
+            frame(b"TXXX", b"\x03cartcutId\x001234"),
+            frame(
+                b"PRIV",
+                b"com.apple.streaming.transportStreamTimestamp\x00\x01\x02",
+            ),
+        ])
+    }
+
+    fn expected() -> Id3Metadata {
+        Id3Metadata {
+            artist: Some("Artist".to_owned()),
+            title: Some("Title".to_owned()),
+            user_text: vec![("cartcutId".to_owned(), "1234".to_owned())],
+            private: vec![(
+                "com.apple.streaming.transportStreamTimestamp".to_owned(),
+                vec![1, 2],
+            )],
+        }
+    }
+
+    #[test]
+    fn test_packed_audio() {
+        let segment = [sample_tag(), vec![0xff, 0xf1, 0x50, 0x80]].concat();
+        assert_eq!(extract(&segment), Some(expected()));
+    }
+
+    #[test]
+    fn test_truncated_footer() {
+        let mut segment = sample_tag();
+        // Footer present flag, but the segment ends after the frames.
+        segment[5] |= 0x10;
+        assert_eq!(extract(&segment), Some(expected()));
+    }
+
+    #[test]
+    fn test_no_tags() {
+        assert_eq!(
+            extract(&[0xff, 0xf1, 0x50, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+    }
+
+    /// Builds a packet, stuffing short payloads with an adaptation field.
+    fn ts_packet(pid: u16, unit_start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            0x47,
+            ((pid >> 8) as u8 & 0x1f) | if unit_start { 0x40 } else { 0 },
+            pid as u8,
+            0x10,
+        ];
+        let stuffing = TS_PACKET_SIZE - 4 - payload.len();
+        if stuffing > 0 {
+            packet[3] |= 0x20;
+            packet.push(stuffing as u8 - 1);
+            if stuffing > 1 {
+                packet.push(0);
+                packet.resize(4 + stuffing, 0xff);
+            }
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn psi(table_id: u8, data: &[u8]) -> Vec<u8> {
+        let section_length = data.len() + 4;
+        [
+            &[
+                0,
+                table_id,
+                0xb0 | (section_length >> 8) as u8,
+                section_length as u8,
+            ],
+            data,
+            &[0, 0, 0, 0],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_transport_stream() {
+        let pat = psi(0x00, &[0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00]);
+        let pmt = psi(
+            0x02,
+            &[
+                0, 1, 0xc1, 0, 0, 0xe1, 0x00, 0xf0, 0x00, // program header
+                0x0f, 0xe1, 0x00, 0xf0, 0x00, // AAC on 0x100
+                0x15, 0xe1, 0x02, 0xf0, 0x00, // ID3 on 0x102
+            ],
+        );
+
+        let id3 = sample_tag();
+        let pes = [
+            &[0, 0, 1, 0xbd, 0, 0, 0x84, 0x80, 0x05, 0x21, 0, 1, 0, 1],
+            id3.as_slice(),
+        ]
+        .concat();
+        let (first, second) = pes.split_at(100);
+
+        let segment = [
+            ts_packet(0, true, &pat),
+            ts_packet(0x1000, true, &pmt),
+            ts_packet(0x100, true, &[0, 0, 1, 0xc0]),
+            ts_packet(0x102, true, first),
+            ts_packet(0x102, false, second),
+        ]
+        .concat();
+
+        assert_eq!(extract(&segment), Some(expected()));
+    }
+}
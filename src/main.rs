@@ -1,16 +1,14 @@
 use std::fmt::Display;
-use std::io::{BufReader, Cursor};
+use std::path::PathBuf;
 use std::time::Duration;
 // use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
-use bytes::{Buf, Bytes};
-use chrono::Utc;
-use clap::Parser;
-use emysound::QueryResult;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use hls_m3u8::{MediaPlaylist, MediaSegment};
 use lazy_static::lazy_static;
-use lofty::Probe;
 use regex::Regex;
 use reqwest::header::CONTENT_TYPE;
 use reqwest::{StatusCode, Url};
@@ -18,17 +16,311 @@ use storage::AudioKind;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
+mod adbreak;
+mod airplays;
+mod audio;
+mod check;
+mod config;
 mod emysound;
+mod export;
+mod id3;
+mod metadata;
+mod output;
+mod pipeline;
+mod queue;
+mod reconcile;
+mod registry;
+mod reindex;
+mod retention;
+mod stats;
 mod storage;
+mod tracks;
+mod transcode;
 
-use crate::emysound::TrackInfo;
-use crate::storage::{AudioData, MatchData, Metadata};
-use crate::storage::{AudioStorage, MatchesStorage, MetadataStorage};
+use crate::adbreak::AdBreakTracker;
+use crate::audio::AudioExporter;
+use crate::check::Checker;
+use crate::config::{AudioStorageConfig, Config, FingerprintConfig, MetadataSource};
+use crate::emysound::{EmySound, FingerprintBackend, LocalBackend};
+use crate::export::{ExportFormat, ExportTable, Exporter};
+use crate::output::Output;
+use crate::pipeline::{Pipeline, Segment};
+use crate::queue::QueueWorker;
+use crate::reconcile::Reconciler;
+use crate::registry::Registry;
+use crate::reindex::{ReindexFilter, Reindexer};
+use crate::retention::Pruner;
+use crate::storage::{AirplayFilter, Bucket, CatalogInfo, Database, LegacyFiles, SegmentSource};
+use crate::storage::{MatchesStorage, MetadataFilter, MetadataOrder, MetadataStorage};
+use crate::storage::{OutboxStorage, QueueStorage};
+use crate::tracks::Tracks;
+use crate::transcode::{AudioVariant, Transcoder};
+
+/// Station name of airplays when `--station` is not given.
+const DEFAULT_STATION: &str = "default";
 
 #[derive(Debug, Parser)]
 struct Args {
-    /// Stream URL (m3u8 file)
-    stream_url: String,
+    /// Config file (TOML)
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Station name: its overrides in the config file apply, and airplays are recorded and
+    /// listed for it
+    #[clap(long)]
+    station: Option<String>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Feed a stream into EmySound
+    Run {
+        /// Stream URL (m3u8 file)
+        stream_url: String,
+    },
+    /// Delete items past their retention period
+    Prune {
+        /// Only report what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Find tracks and local items out of sync, and optionally repair them
+    Reconcile {
+        /// Delete orphans, and missing tracks unless re-inserted
+        #[clap(long)]
+        repair: bool,
+        /// Re-insert items missing in the fingerprint backend from stored audio
+        #[clap(long, requires = "repair")]
+        reinsert: bool,
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Insert stored items into the fingerprint backend again, skipping those already there
+    Reindex {
+        /// Only items of this kind, may be repeated
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Vec<AudioKind>,
+        /// Only items stored at or after this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only items stored before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// Number of concurrent inserts
+        #[clap(long, default_value = "4")]
+        concurrency: usize,
+    },
+    /// List detected airplays
+    Airplays {
+        /// Only airplays of this track
+        #[clap(long)]
+        track: Option<Uuid>,
+        /// Only airplays of items of this kind
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Option<AudioKind>,
+        /// Only airplays ending at or after this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only airplays starting before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print plays per station, the most played tracks and plays over time
+    Stats {
+        /// Only plays of items of this kind
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Option<AudioKind>,
+        /// Only plays of this track, and list its matches
+        #[clap(long)]
+        track: Option<Uuid>,
+        /// Only plays ending at or after this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only plays starting before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// Number of most played tracks
+        #[clap(long, default_value = "10")]
+        top: usize,
+        /// Count plays per hour or day
+        #[clap(long, default_value = "day", parse(try_from_str = parse_bucket))]
+        bucket: Bucket,
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Write metadata, matches, airplays or gaps between airplays to a file
+    Export {
+        /// What to export: metadata, matches, airplays or gaps
+        #[clap(parse(try_from_str = parse_table))]
+        table: ExportTable,
+        /// File to write, replaced if it exists
+        path: PathBuf,
+        /// Format: csv, jsonl or parquet
+        #[clap(long, default_value = "csv", parse(try_from_str = parse_format))]
+        format: ExportFormat,
+        /// Only rows of items of this kind
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Option<AudioKind>,
+        /// Only rows at or after this time (RFC 3339), or plays ending then
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only rows before this time (RFC 3339), or plays starting before
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+    },
+    /// Query stored items
+    Metadata {
+        /// Print JSON instead of a table
+        #[clap(long, global = true)]
+        json: bool,
+
+        #[clap(subcommand)]
+        command: MetadataCommand,
+    },
+    /// Get stored audio out
+    Audio {
+        #[clap(subcommand)]
+        command: AudioCommand,
+    },
+    /// Back up and check the databases
+    Db {
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// Manage tracks in the fingerprint backend
+    Tracks {
+        /// Print JSON instead of a table
+        #[clap(long, global = true)]
+        json: bool,
+
+        #[clap(subcommand)]
+        command: TracksCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum MetadataCommand {
+    /// Search items, newest first unless ordered otherwise
+    Search {
+        /// Only items of this kind, may be repeated
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Vec<AudioKind>,
+        /// Only items stored at or after this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only items stored before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// Only items whose artist contains this, ignoring case
+        #[clap(long)]
+        artist: Option<String>,
+        /// Only items whose title contains this, ignoring case
+        #[clap(long)]
+        title: Option<String>,
+        /// Full-text query over artist and title (SQLite FTS5 syntax), e.g. 'beatl* NOT live'
+        #[clap(long)]
+        text: Option<String>,
+        /// Order: newest, oldest, artist or title
+        #[clap(long, default_value = "newest", parse(try_from_str = parse_order))]
+        order: MetadataOrder,
+        /// Number of items to skip
+        #[clap(long, default_value = "0")]
+        offset: usize,
+        /// Maximum number of items
+        #[clap(long, default_value = "50")]
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AudioCommand {
+    /// Write the audio of items to tagged files named after them, MP4 for AAC
+    Export {
+        /// Directory to write to, created if missing
+        dir: PathBuf,
+        /// Only this item, may be repeated; the other filters are ignored then
+        #[clap(long)]
+        id: Vec<Uuid>,
+        /// Only items of this kind, may be repeated
+        #[clap(long, parse(try_from_str = parse_kind))]
+        kind: Vec<AudioKind>,
+        /// Only items stored at or after this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only items stored before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// Only items whose artist contains this, ignoring case
+        #[clap(long)]
+        artist: Option<String>,
+        /// Only items whose title contains this, ignoring case
+        #[clap(long)]
+        title: Option<String>,
+        /// Full-text query over artist and title (SQLite FTS5 syntax)
+        #[clap(long)]
+        text: Option<String>,
+        /// Export only audio kept in the format it was received in, skipping items whose audio
+        /// was transcoded for storage
+        #[clap(long)]
+        original: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommand {
+    /// Copy the database to a new file while the feeder keeps running, and the audio database
+    /// next to it if audio is stored in its own SQLite file
+    Backup {
+        /// File to write, must not exist
+        dest: PathBuf,
+    },
+    /// Rebuild the databases to give free space back and to let the pruner vacuum databases
+    /// created before incremental vacuuming. Stop the feeder first, as writes wait until it is
+    /// done
+    Vacuum,
+    /// Check the databases for corruption, items without audio and the other way round,
+    /// unusable audio and matches of deleted items
+    Check {
+        /// Only check that audio is not empty, without decoding it
+        #[clap(long)]
+        skip_decode: bool,
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TracksCommand {
+    /// Add an audio file as a track
+    Add {
+        file: PathBuf,
+        artist: String,
+        title: String,
+        /// Track id, random if not given
+        #[clap(long)]
+        id: Option<Uuid>,
+    },
+    /// Find tracks matching an audio file
+    Query { file: PathBuf },
+    /// List all tracks
+    List,
+    /// Delete a track
+    Delete { id: Uuid },
+    /// Delete all tracks
+    DeleteAll {
+        /// Really delete, otherwise only count the tracks
+        #[clap(long)]
+        confirm: bool,
+    },
 }
 
 #[tokio::main]
@@ -42,18 +334,366 @@ async fn main() -> Result<()> {
         simplelog::ColorChoice::Auto,
     )?;
 
-    let stream_url: Url = args.stream_url.parse()?;
+    let config = match &args.config {
+        Some(path) => Config::load(path, args.station.as_deref())?,
+        None => Config::default(),
+    };
+
+    let shared_audio = matches!(config.audio, AudioStorageConfig::Sqlite { path: None });
+    let db = Database::open_importing(&config.database.path, &LegacyFiles::find(shared_audio))?;
+    let metadata_storage = MetadataStorage::new(&db);
+    let audio_storage = storage::audio::open(&config.audio, &db)?;
+    let matches_storage = MatchesStorage::new(&db);
+    let queue = QueueStorage::new(&db);
+    let outbox = OutboxStorage::new(&db);
+
+    let transcoder = Transcoder::new(&config.transcode);
+    let backend: Box<dyn FingerprintBackend + '_> = match &config.fingerprint {
+        FingerprintConfig::EmySound(config) => Box::new(EmySound::new(config)?),
+        FingerprintConfig::Local { path } => Box::new(LocalBackend::new(path, &transcoder)?),
+    };
+
+    let pruner = Pruner::new(
+        &config.retention,
+        backend.as_ref(),
+        &metadata_storage,
+        audio_storage.as_ref(),
+        &matches_storage,
+        &queue,
+        &outbox,
+    );
+
+    match args.command {
+        Command::Run { stream_url } => {
+            let station = args.station.as_deref().unwrap_or(DEFAULT_STATION);
+            let registry = Registry::new(
+                &config.queue,
+                backend.as_ref(),
+                &transcoder,
+                &metadata_storage,
+                audio_storage.as_ref(),
+                &outbox,
+            );
+            registry.recover().await?;
+
+            let pipeline = Pipeline::new(
+                backend.as_ref(),
+                &transcoder,
+                &registry,
+                audio_storage.as_ref(),
+                &matches_storage,
+                &queue,
+                &config.matching,
+            )
+            .with_airplay(station, &config.airplay);
+            let worker = QueueWorker::new(&config.queue, &pipeline, &registry, &queue);
 
+            tokio::try_join!(
+                run(
+                    stream_url.parse()?,
+                    station,
+                    &config,
+                    &transcoder,
+                    &pipeline,
+                    &pruner
+                ),
+                worker.run()
+            )
+            .map(|_| ())
+        }
+        Command::Prune { dry_run } => pruner.prune(dry_run).await.map(|_| ()),
+        Command::Reconcile {
+            repair,
+            reinsert,
+            json,
+        } => {
+            let reconciler = Reconciler::new(
+                backend.as_ref(),
+                &transcoder,
+                &metadata_storage,
+                audio_storage.as_ref(),
+                &matches_storage,
+                &queue,
+                &outbox,
+            );
+            let drift = reconciler.diff().await?;
+            reconcile::print(&drift, Output::new(json))?;
+            if repair {
+                reconciler.repair(&drift, reinsert).await?;
+            }
+            Ok(())
+        }
+        Command::Reindex {
+            kind,
+            since,
+            until,
+            concurrency,
+        } => {
+            let reindexer = Reindexer::new(
+                backend.as_ref(),
+                &transcoder,
+                &metadata_storage,
+                audio_storage.as_ref(),
+            );
+            let filter = ReindexFilter {
+                kinds: kind,
+                since,
+                until,
+            };
+            reindexer.run(&filter, concurrency).await.map(|_| ())
+        }
+        Command::Airplays {
+            track,
+            kind,
+            since,
+            until,
+            json,
+        } => {
+            let filter = AirplayFilter {
+                station: args.station,
+                track_id: track,
+                since,
+                until,
+                kind,
+            };
+            let airplays = matches_storage.airplays(&filter).await?;
+            airplays::print(&airplays, &metadata_storage, Output::new(json)).await
+        }
+        Command::Stats {
+            kind,
+            track,
+            since,
+            until,
+            top,
+            bucket,
+            json,
+        } => {
+            let filter = AirplayFilter {
+                station: args.station,
+                track_id: track,
+                since,
+                until,
+                kind,
+            };
+            stats::print(
+                &matches_storage,
+                &metadata_storage,
+                &filter,
+                top,
+                bucket,
+                Output::new(json),
+            )
+            .await
+        }
+        Command::Export {
+            table,
+            path,
+            format,
+            kind,
+            since,
+            until,
+        } => {
+            let filter = AirplayFilter {
+                station: args.station,
+                track_id: None,
+                since,
+                until,
+                kind,
+            };
+            let exporter = Exporter::new(
+                &metadata_storage,
+                &matches_storage,
+                chrono::Duration::from_std(config.airplay.max_gap)?,
+            );
+            let rows = exporter.export(table, &filter, format, &path).await?;
+            log::info!("Exported {rows} rows to {}", path.display());
+            Ok(())
+        }
+        Command::Metadata { json, command } => match command {
+            MetadataCommand::Search {
+                kind,
+                since,
+                until,
+                artist,
+                title,
+                text,
+                order,
+                offset,
+                limit,
+            } => {
+                let filter = MetadataFilter {
+                    kinds: kind,
+                    station: args.station,
+                    since,
+                    until,
+                    artist,
+                    title,
+                    text,
+                    order,
+                    offset,
+                    limit: Some(limit),
+                };
+                let items = metadata_storage.search(&filter).await?;
+                let total = metadata_storage.count(&filter).await?;
+                metadata::print(&items, offset, total, Output::new(json))
+            }
+        },
+        Command::Audio {
+            command:
+                AudioCommand::Export {
+                    dir,
+                    id,
+                    kind,
+                    since,
+                    until,
+                    artist,
+                    title,
+                    text,
+                    original,
+                },
+        } => {
+            let filter = MetadataFilter {
+                kinds: kind,
+                station: args.station,
+                since,
+                until,
+                artist,
+                title,
+                text,
+                ..Default::default()
+            };
+            let variant = if original {
+                AudioVariant::Original
+            } else {
+                AudioVariant::Stored
+            };
+            let exporter =
+                AudioExporter::new(&transcoder, &metadata_storage, audio_storage.as_ref())
+                    .with_variant(variant);
+            let written = exporter.export(&id, &filter, &dir).await?;
+            log::info!("Exported {written} files to {}", dir.display());
+            Ok(())
+        }
+        Command::Db { command } => {
+            let audio_db = match &config.audio {
+                AudioStorageConfig::Sqlite { path: Some(path) } => {
+                    Some(storage::audio::open_database(path)?)
+                }
+                _ => None,
+            };
+            match command {
+                DbCommand::Backup { dest } => {
+                    db.backup(&dest).await?;
+                    log::info!("Backed up to {}", dest.display());
+                    if let Some(audio_db) = &audio_db {
+                        let mut name = dest.file_stem().unwrap_or_default().to_owned();
+                        name.push(".audio");
+                        if let Some(extension) = dest.extension() {
+                            name.push(".");
+                            name.push(extension);
+                        }
+                        let audio_dest = dest.with_file_name(name);
+                        audio_db.backup(&audio_dest).await?;
+                        log::info!("Backed up audio to {}", audio_dest.display());
+                    } else if !matches!(config.audio, AudioStorageConfig::Sqlite { .. }) {
+                        log::warn!("Audio outside SQLite is not included in the backup");
+                    }
+                    Ok(())
+                }
+                DbCommand::Vacuum => {
+                    db.vacuum().await?;
+                    log::info!("Vacuumed {}", config.database.path.display());
+                    if let Some(audio_db) = &audio_db {
+                        audio_db.vacuum().await?;
+                        log::info!("Vacuumed the audio database");
+                    }
+                    Ok(())
+                }
+                DbCommand::Check { skip_decode, json } => {
+                    let mut checker = Checker::new(
+                        &transcoder,
+                        &metadata_storage,
+                        audio_storage.as_ref(),
+                        &matches_storage,
+                        &queue,
+                        &outbox,
+                    )
+                    .with_database("database", &db)
+                    .with_decode(!skip_decode);
+                    if let Some(audio_db) = &audio_db {
+                        checker = checker.with_database("audio", audio_db);
+                    }
+                    let findings = checker.check().await?;
+                    check::print(&findings, Output::new(json))?;
+                    if !findings.is_empty() {
+                        bail!("{} problems found", findings.len());
+                    }
+                    Ok(())
+                }
+            }
+        }
+        Command::Tracks { json, command } => {
+            let output = Output::new(json);
+            let tracks = Tracks::new(backend.as_ref(), output);
+            match command {
+                TracksCommand::Add {
+                    file,
+                    artist,
+                    title,
+                    id,
+                } => tracks.add(&file, artist, title, id).await,
+                TracksCommand::Query { file } => tracks.query(&file).await,
+                TracksCommand::List => tracks.list().await,
+                TracksCommand::Delete { id } => tracks.delete(id).await,
+                TracksCommand::DeleteAll { confirm } => {
+                    tracks.delete_all(confirm).await.map(|_| ())
+                }
+            }
+        }
+    }
+}
+
+fn parse_kind(value: &str) -> Result<AudioKind> {
+    value.try_into()
+}
+
+fn parse_order(value: &str) -> Result<MetadataOrder> {
+    value.try_into()
+}
+
+fn parse_bucket(value: &str) -> Result<Bucket> {
+    value.try_into()
+}
+
+fn parse_table(value: &str) -> Result<ExportTable> {
+    value.try_into()
+}
+
+fn parse_format(value: &str) -> Result<ExportFormat> {
+    value.try_into()
+}
+
+async fn run(
+    stream_url: Url,
+    station: &str,
+    config: &Config,
+    transcoder: &Transcoder<'_>,
+    pipeline: &Pipeline<'_>,
+    pruner: &Pruner<'_>,
+) -> Result<()> {
     log::debug!("Fetching {stream_url} ");
 
     let client = reqwest::Client::new();
     let mut segment_number_filter = SegmentNumberFilter::new();
-
-    let metadata_storage = MetadataStorage::new(&"./metadata.sqlite3")?;
-    let audio_storage = AudioStorage::new(&"./audio.sqlite3")?;
-    let matches_storage = MatchesStorage::new(&"./matches.sqlite3")?;
+    let mut ad_breaks = AdBreakTracker::new();
 
     loop {
+        if pruner.is_due() {
+            if let Err(e) = pruner.prune(false).await {
+                log::error!("Prune failed: {e:#}");
+            }
+        }
+
         let response = client.get(stream_url.clone()).send().await?;
 
         match response.status() {
@@ -85,6 +725,7 @@ async fn main() -> Result<()> {
                                                     artist: info.artist.clone(),
                                                     title: info.title.clone(),
                                                     kind,
+                                                    source: segment_source(station, segment, Some((&info).into())),
                                                 };
                                         match kind {
                                             SuggestedSegmentContentKind::None => {
@@ -112,91 +753,67 @@ async fn main() -> Result<()> {
                                         if let Some(title) = segment.duration.title() {
                                             if title.contains("adContext=") {
                                                 log::info!("Segment#{} DOWNLOAD: advertisment: title={title}", segment.number());
-                                                return Some(SegmentDownloadInfo{ url, artist: "Advertisement".to_string(), title: "Advertisement".to_string() , kind: SuggestedSegmentContentKind::Advertisement });
+                                                return Some(SegmentDownloadInfo{ url, artist: "Advertisement".to_string(), title: "Advertisement".to_string() , kind: SuggestedSegmentContentKind::Advertisement, source: segment_source(station, segment, None) });
                                             }
-                                            None
-                                        } else {
-                                            // Happens at the first download and sometimes in the middle then section changes. ignore.
-                                            log::info!("Segment#{} SKIPPED: no info: {e:#?}", segment.number());
-                                            log::debug!(
-                                                "Segment#{} title={:?}",
-                                                segment.number(),
-                                                segment.duration.title()
-                                            );
-                                            None
                                         }
+                                        // Other streams carry artist and title in ID3 only, read after the download.
+                                        log::info!("Segment#{} DOWNLOAD: no #EXTINF info: {e:#}", segment.number());
+                                        log::debug!(
+                                            "Segment#{} title={:?}",
+                                            segment.number(),
+                                            segment.duration.title()
+                                        );
+                                        Some(SegmentDownloadInfo {
+                                            url,
+                                            artist: String::new(),
+                                            title: String::new(),
+                                            kind: SuggestedSegmentContentKind::None,
+                                            source: segment_source(station, segment, None),
+                                        })
                                     }
                                 }
                             }).collect();
 
+                        let downloads = ad_breaks.annotate(downloads, |info| {
+                            matches!(info.kind, SuggestedSegmentContentKind::Advertisement)
+                        });
+
                         let mut stream = tokio_stream::iter(downloads);
-                        while let Some(info) = stream.next().await {
+                        while let Some((mut info, ad_edges)) = stream.next().await {
                             match download(&info).await {
                                 Ok((audio_format, bytes)) => {
-                                    let tagged_file = Probe::new(Cursor::new(&bytes))
-                                        .guess_file_type()?
-                                        .read(false)?;
-
-                                    for tag in tagged_file.tags() {
-                                        for item in tag.items() {
-                                            log::info!("{:?} {:?}", item.key(), item.value());
-                                        }
+                                    if let Some(id3) = id3::extract(&bytes) {
+                                        log::debug!("ID3: {id3:?}");
+                                        info.apply_id3(&id3, &config.segment_metadata.precedence);
+                                    }
+                                    if info.source.catalog.is_none()
+                                        && info.artist.is_empty()
+                                        && info.title.is_empty()
+                                    {
+                                        // Neither #EXTINF nor ID3 info. Happens at the first download and sometimes in the middle when the section changes.
+                                        log::info!("SKIPPED {}: no info", info.url);
+                                        continue;
                                     }
 
-                                    let filename = info.filename();
-                                    let matches = emysound::query(&filename, &bytes).await?;
-
-                                    if matches.is_empty() {
-                                        let id = Uuid::new_v4();
-
-                                        log::info!(
-                                            "Insert new audio segment `{}`/`{}` {id}",
-                                            &info.artist,
-                                            &info.title
-                                        );
+                                    let bytes = adbreak::trim(
+                                        transcoder,
+                                        &config.ad_trim,
+                                        bytes,
+                                        &audio_format,
+                                        ad_edges,
+                                    )
+                                    .await;
 
-                                        emysound::insert(info.to_track_info(id), &filename, &bytes)
-                                            .await?;
-
-                                        audio_storage
-                                            .insert(&AudioData::new(
-                                                id,
-                                                audio_format,
-                                                bytes.clone(),
-                                            ))
-                                            .context("Insert audio")?;
-
-                                        metadata_storage
-                                            .insert(&info.to_metadata(id))
-                                            .context("Insert metadata")?;
-                                    } else {
-                                        matches
-                                            .iter()
-                                            .inspect(|result| {
-                                                log::info!(
-                                                    "`{}`/`{}` matches  {} `{}`/`{}` {}",
-                                                    &info.artist,
-                                                    &info.title,
-                                                    result.id(),
-                                                    result
-                                                        .artist()
-                                                        .as_ref()
-                                                        .unwrap_or(&String::new()),
-                                                    result
-                                                        .title()
-                                                        .as_ref()
-                                                        .unwrap_or(&String::new()),
-                                                    result.score()
-                                                );
-
-                                                log::info!(
-                                                    "{:?}",
-                                                    metadata_storage.get(result.id()).map(|v| v.id)
-                                                )
-                                            })
-                                            .map(|result| matches_storage.insert(&result.into()))
-                                            .collect::<Result<Vec<_>>>()?;
-                                    }
+                                    let segment = Segment {
+                                        filename: info.filename(),
+                                        format: audio_format,
+                                        bytes,
+                                        kind: info.kind.into(),
+                                        artist: info.artist,
+                                        title: info.title,
+                                        source: info.source,
+                                    };
+                                    pipeline.process(segment).await?;
                                 }
                                 Err(e) => {
                                     log::error!("Failed to download {}: {e:#}", info.url)
@@ -217,11 +834,6 @@ async fn main() -> Result<()> {
     }
 }
 
-impl From<&QueryResult> for MatchData {
-    fn from(value: &QueryResult) -> Self {
-        MatchData::new(value.id(), Utc::now(), value.score())
-    }
-}
 async fn download(info: &SegmentDownloadInfo) -> Result<(String, Bytes)> {
     let response = reqwest::get(info.url.clone()).await?;
 
@@ -256,6 +868,26 @@ struct SegmentDownloadInfo {
     artist: String,
     title: String,
     kind: SuggestedSegmentContentKind,
+    source: SegmentSource,
+}
+
+/// Stream fields of `segment` from `station`, as received.
+fn segment_source(
+    station: &str,
+    segment: &MediaSegment,
+    catalog: Option<CatalogInfo>,
+) -> SegmentSource {
+    SegmentSource {
+        station: Some(station.to_owned()),
+        uri: Some(segment.uri().to_string()),
+        media_sequence: Some(segment.number() as u64),
+        aired: segment
+            .program_date_time
+            .map(|time| time.date_time.with_timezone(&Utc)),
+        duration: Some(segment.duration.duration()),
+        raw_title: segment.duration.title().as_ref().map(|t| t.to_string()),
+        catalog,
+    }
 }
 
 impl SegmentDownloadInfo {
@@ -268,23 +900,25 @@ impl SegmentDownloadInfo {
             self.title,
             self.url
                 .path_segments()
-                .and_then(|s| s.last())
+                .and_then(|mut s| s.next_back())
                 .unwrap_or("unknown")
         )
     }
 
-    fn to_track_info(&self, id: Uuid) -> TrackInfo {
-        TrackInfo::new(id, self.artist.clone(), self.title.clone())
-    }
+    /// Takes artist and title from `id3` where it precedes `#EXTINF`.
+    fn apply_id3(&mut self, id3: &id3::Id3Metadata, precedence: &[MetadataSource]) {
+        let pick = |extinf: &String, id3: &Option<String>| {
+            precedence
+                .iter()
+                .find_map(|source| match source {
+                    MetadataSource::Extinf => Some(extinf).filter(|v| !v.is_empty()).cloned(),
+                    MetadataSource::Id3 => id3.clone(),
+                })
+                .unwrap_or_else(|| extinf.clone())
+        };
 
-    fn to_metadata(&self, id: Uuid) -> Metadata {
-        Metadata::new(
-            id,
-            Utc::now(),
-            self.kind.into(),
-            self.artist.clone(),
-            self.title.clone(),
-        )
+        self.artist = pick(&self.artist, &id3.artist);
+        self.title = pick(&self.title, &id3.title);
     }
 }
 trait SegmentDownloadFilter {
@@ -419,7 +1053,7 @@ impl TryFrom<&str> for KostaRadioSegmentInfo {
             amg_artwork_url: caps[11].to_owned().parse().ok(),
             length: chrono::NaiveTime::signed_duration_since(
                 chrono::NaiveTime::parse_from_str(&caps[12], "%H:%M:%S")?,
-                chrono::NaiveTime::from_hms(0, 0, 0),
+                chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
             )
             .to_std()?,
             uns_id: caps[13].parse::<i64>()?,
@@ -428,6 +1062,25 @@ impl TryFrom<&str> for KostaRadioSegmentInfo {
     }
 }
 
+impl From<&KostaRadioSegmentInfo> for CatalogInfo {
+    fn from(info: &KostaRadioSegmentInfo) -> Self {
+        Self {
+            song_spot: info.song_spot.to_string(),
+            media_base_id: info.media_base_id,
+            itunes_track_id: info.itunes_track_id,
+            amg_track_id: info.amg_track_id,
+            amg_artist_id: info.amg_artist_id,
+            ta_id: info.ta_id,
+            tp_id: info.tp_id,
+            cartcut_id: info.cartcut_id,
+            artwork_url: info.amg_artwork_url.as_ref().map(Url::to_string),
+            length: info.length,
+            uns_id: info.uns_id,
+            spot_instance_id: info.spot_instance_id,
+        }
+    }
+}
+
 impl TryFrom<&MediaSegment<'_>> for KostaRadioSegmentInfo {
     type Error = anyhow::Error;
 
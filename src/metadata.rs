@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::output::{print_json, table, Output};
+use crate::storage::{Metadata, SegmentSource};
+
+#[derive(Debug, Serialize)]
+struct MetadataRow<'a> {
+    id: Uuid,
+    date: DateTime<Utc>,
+    kind: String,
+    artist: &'a str,
+    title: &'a str,
+    #[serde(flatten)]
+    source: &'a SegmentSource,
+}
+
+/// Prints `items`, a page starting at `offset` of `total` matching items.
+pub fn print(
+    items: &[Metadata],
+    offset: usize,
+    total: usize,
+    output: Output,
+) -> anyhow::Result<()> {
+    match output {
+        Output::Json => {
+            let rows: Vec<MetadataRow> = items
+                .iter()
+                .map(|item| MetadataRow {
+                    id: item.id,
+                    date: item.date(),
+                    kind: item.kind().to_string(),
+                    artist: item.artist(),
+                    title: item.title(),
+                    source: item.source(),
+                })
+                .collect();
+            print_json(&rows)
+        }
+        Output::Table => {
+            let rows = items
+                .iter()
+                .map(|item| {
+                    vec![
+                        item.date().format("%Y-%m-%d %H:%M:%S").to_string(),
+                        item.id.to_string(),
+                        item.kind().to_string(),
+                        item.source().station.clone().unwrap_or_default(),
+                        item.artist().to_owned(),
+                        item.title().to_owned(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print!(
+                "{}",
+                table(&["DATE", "ID", "KIND", "STATION", "ARTIST", "TITLE"], &rows)
+            );
+            log::info!("{} of {total} items from offset {offset}", items.len());
+            Ok(())
+        }
+    }
+}
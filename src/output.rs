@@ -0,0 +1,69 @@
+use serde::Serialize;
+
+/// How command results are printed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        if json {
+            Output::Json
+        } else {
+            Output::Table
+        }
+    }
+}
+
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Formats rows as left-aligned columns under a header.
+pub fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &mut dyn Iterator<Item = &str>| {
+        let mut line = cells
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        line.truncate(line.trim_end().len());
+        line + "\n"
+    };
+
+    let mut output = line(&mut headers.iter().copied());
+    for row in rows {
+        output += &line(&mut row.iter().map(String::as_str));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::table;
+
+    #[test]
+    fn test_table() {
+        let rows = vec![
+            vec!["1".to_owned(), "Artist".to_owned(), "Title".to_owned()],
+            vec!["22".to_owned(), "A".to_owned(), String::new()],
+        ];
+        assert_eq!(
+            table(&["ID", "ARTIST", "TITLE"], &rows),
+            "ID  ARTIST  TITLE\n1   Artist  Title\n22  A\n"
+        );
+    }
+}
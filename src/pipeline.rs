@@ -0,0 +1,488 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::config::{AirplayConfig, MatchConfig};
+use crate::emysound::matcher::best_results;
+use crate::emysound::{FingerprintBackend, QueryResult};
+use crate::registry::Registry;
+use crate::storage::{AudioData, AudioKind, AudioStorage, Candidate, MatchData, MatchSource};
+use crate::storage::{MatchesStorage, Metadata, QueueStorage, QueuedSegment, SegmentSource};
+use crate::transcode::{extension, AudioVariant, Transcoder};
+
+/// A downloaded segment, ready to be fingerprinted.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub filename: String,
+    pub format: String,
+    pub bytes: Bytes,
+    pub kind: AudioKind,
+    pub artist: String,
+    pub title: String,
+    pub source: SegmentSource,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum Outcome {
+    /// Nothing matched, the segment was added as a new item.
+    Inserted(Uuid),
+    /// The segment matched existing items.
+    Matched(Vec<QueryResult>),
+    /// The fingerprint backend failed, the segment is queued for a retry under the id.
+    Queued(Uuid),
+}
+
+/// Result of the fingerprint backend query for a segment.
+enum Fingerprinted {
+    New,
+    /// The accepted results, best first, and the other ones.
+    Matched {
+        matches: Vec<QueryResult>,
+        rejected: Vec<QueryResult>,
+    },
+}
+
+/// Matches segments against the fingerprint backend and stores the results.
+pub struct Pipeline<'a> {
+    backend: &'a dyn FingerprintBackend,
+    transcoder: &'a Transcoder<'a>,
+    registry: &'a Registry<'a>,
+    audio_storage: &'a dyn AudioStorage,
+    matches_storage: &'a MatchesStorage,
+    queue: &'a QueueStorage,
+    matching: &'a MatchConfig,
+    airplay: Option<(&'a str, &'a AirplayConfig)>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(
+        backend: &'a dyn FingerprintBackend,
+        transcoder: &'a Transcoder<'a>,
+        registry: &'a Registry<'a>,
+        audio_storage: &'a dyn AudioStorage,
+        matches_storage: &'a MatchesStorage,
+        queue: &'a QueueStorage,
+        matching: &'a MatchConfig,
+    ) -> Self {
+        Self {
+            backend,
+            transcoder,
+            registry,
+            audio_storage,
+            matches_storage,
+            queue,
+            matching,
+            airplay: None,
+        }
+    }
+
+    /// Merges matches of segments from `station` into airplay events.
+    pub fn with_airplay(mut self, station: &'a str, config: &'a AirplayConfig) -> Self {
+        self.airplay = Some((station, config));
+        self
+    }
+
+    /// Fingerprints the segment and stores the result.
+    ///
+    /// If the fingerprint backend fails, the segment is stored in the audio storage as received
+    /// and queued for [`Pipeline::retry`].
+    pub async fn process(&self, segment: Segment) -> anyhow::Result<Outcome> {
+        let id = Uuid::new_v4();
+        let received = Utc::now();
+
+        match self.fingerprint(&segment).await {
+            Ok(fingerprinted) => {
+                self.store(id, received, segment, fingerprinted, false)
+                    .await
+            }
+            Err(e) => {
+                log::warn!("Queue segment {} as {id}: {e:#}", &segment.filename);
+
+                self.audio_storage
+                    .insert(&AudioData::new(id, segment.format.clone(), segment.bytes))
+                    .await
+                    .context("Insert queued audio")?;
+                self.queue
+                    .insert(&QueuedSegment {
+                        id,
+                        created: received,
+                        attempts: 0,
+                        next_attempt: received,
+                        filename: segment.filename,
+                        format: segment.format,
+                        kind: segment.kind,
+                        artist: segment.artist,
+                        title: segment.title,
+                        source: segment.source,
+                    })
+                    .await
+                    .context("Queue segment")?;
+
+                Ok(Outcome::Queued(id))
+            }
+        }
+    }
+
+    /// Fingerprints a queued segment and stores the result, then removes it from the queue.
+    ///
+    /// Backend errors are returned with the segment left in the queue.
+    pub async fn retry(&self, queued: &QueuedSegment) -> anyhow::Result<Outcome> {
+        // Transcoded if an interrupted registration replaced it, and then kept as is.
+        let audio = self
+            .transcoder
+            .get(self.audio_storage, queued.id, AudioVariant::Stored)
+            .await
+            .context("Get queued audio")?;
+        let filename = if audio.is_transcoded() {
+            Path::new(&queued.filename)
+                .with_extension(extension(audio.format()))
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            queued.filename.clone()
+        };
+        let segment = Segment {
+            filename,
+            format: audio.format().to_owned(),
+            bytes: audio.bytes().clone(),
+            kind: queued.kind,
+            artist: queued.artist.clone(),
+            title: queued.title.clone(),
+            source: queued.source.clone(),
+        };
+
+        let fingerprinted = self.fingerprint(&segment).await?;
+        let outcome = self
+            .store(queued.id, queued.created, segment, fingerprinted, true)
+            .await?;
+        self.queue.delete(queued.id).await?;
+        Ok(outcome)
+    }
+
+    async fn fingerprint(&self, segment: &Segment) -> anyhow::Result<Fingerprinted> {
+        let results = self
+            .backend
+            .query(&segment.filename, &segment.bytes)
+            .await?;
+        let matches = best_results(self.matching, segment.kind, results.clone());
+        if matches.is_empty() {
+            Ok(Fingerprinted::New)
+        } else {
+            let rejected = results
+                .into_iter()
+                .filter(|r| !matches.iter().any(|m| m.id() == r.id()))
+                .collect();
+            Ok(Fingerprinted::Matched { matches, rejected })
+        }
+    }
+
+    /// Registers a new item, or stores the matches with the segment they were found in.
+    ///
+    /// `queued` segments already have their audio stored as received. The audio of matched
+    /// segments is kept under `id` if configured, and deleted otherwise.
+    async fn store(
+        &self,
+        id: Uuid,
+        received: DateTime<Utc>,
+        segment: Segment,
+        fingerprinted: Fingerprinted,
+        queued: bool,
+    ) -> anyhow::Result<Outcome> {
+        match fingerprinted {
+            Fingerprinted::New => {
+                log::info!(
+                    "Insert new audio segment `{}`/`{}` {id}",
+                    &segment.artist,
+                    &segment.title
+                );
+
+                self.registry
+                    .register(
+                        &Metadata::new(id, received, segment.kind, segment.artist, segment.title)
+                            .with_source(segment.source),
+                        AudioData::new(id, segment.format, segment.bytes),
+                        queued,
+                    )
+                    .await?;
+
+                Ok(Outcome::Inserted(id))
+            }
+            Fingerprinted::Matched { matches, rejected } => {
+                let query_id = if self.matching.store_query_audio {
+                    if !queued {
+                        self.audio_storage
+                            .insert(&AudioData::new(id, segment.format.clone(), segment.bytes))
+                            .await
+                            .context("Insert query audio")?;
+                    }
+                    Some(id)
+                } else {
+                    None
+                };
+                let source = MatchSource {
+                    station: segment.source.station.clone(),
+                    media_sequence: segment.source.media_sequence,
+                    aired: segment.source.aired,
+                    query_id,
+                    rejected: rejected.iter().map(Candidate::from).collect(),
+                };
+
+                for result in &matches {
+                    log::info!(
+                        "`{}`/`{}` matches  {} `{}`/`{}` {}",
+                        &segment.artist,
+                        &segment.title,
+                        result.id(),
+                        result.artist().as_ref().unwrap_or(&String::new()),
+                        result.title().as_ref().unwrap_or(&String::new()),
+                        result.score()
+                    );
+
+                    self.matches_storage
+                        .insert(
+                            &MatchData::new(result.id(), received, result.score())
+                                .with_offsets(result.offsets())
+                                .with_source(source.clone()),
+                        )
+                        .await?;
+                }
+
+                if let Some((station, config)) = self.airplay {
+                    let max_gap = chrono::Duration::from_std(config.max_gap)?;
+                    // Queued segments are processed long after they aired.
+                    let aired = source.aired.unwrap_or(received);
+                    for result in &matches {
+                        let airplay = self
+                            .matches_storage
+                            .record_airplay(station, result.id(), aired, result.score(), max_gap)
+                            .await?;
+                        log::debug!("Airplay: {airplay:?}");
+                    }
+                }
+
+                if queued && query_id.is_none() {
+                    self.audio_storage
+                        .delete(id)
+                        .await
+                        .context("Delete queued audio")?;
+                }
+
+                Ok(Outcome::Matched(matches))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{Outcome, Pipeline, Segment};
+    use crate::config::{AirplayConfig, MatchConfig, QueueConfig, TranscodeConfig};
+    use crate::emysound::{MockBackend, QueryResult};
+    use crate::registry::Registry;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioKind, AudioStorage, Database, MatchOffsets, SegmentSource};
+    use crate::storage::{MatchesStorage, MetadataStorage, OutboxStorage, QueueStorage};
+    use crate::transcode::Transcoder;
+
+    fn segment() -> Segment {
+        Segment {
+            filename: "segment.aac".to_owned(),
+            format: "audio/aac".to_owned(),
+            bytes: b"1234567890".as_ref().into(),
+            kind: AudioKind::Music,
+            artist: "Artist".to_owned(),
+            title: "Title".to_owned(),
+            source: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_then_match() {
+        let backend = MockBackend::new();
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let queue_config = QueueConfig::default();
+        let registry = Registry::new(
+            &queue_config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+        let airplay_config = AirplayConfig::default();
+        let match_config = MatchConfig::default();
+        let pipeline = Pipeline::new(
+            &backend,
+            &transcoder,
+            &registry,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &match_config,
+        )
+        .with_airplay("kosta", &airplay_config);
+
+        let id = match pipeline.process(segment()).await.unwrap() {
+            Outcome::Inserted(id) => id,
+            outcome => panic!("Unexpected {outcome:?}"),
+        };
+        assert_eq!(backend.tracks()[0].id(), id);
+        assert_eq!(audio_storage.get(id).await.unwrap().format(), "audio/aac");
+        assert_eq!(metadata_storage.get(id).await.unwrap().id, id);
+
+        let offsets = MatchOffsets {
+            query_start: 0.0,
+            query_end: 10.0,
+            track_start: 30.0,
+            track_end: 40.0,
+        };
+        backend.push_result(vec![
+            QueryResult::new(id, 0.9, Some("Artist".to_owned()), Some("Title".to_owned()))
+                .with_offsets(Some(offsets)),
+            QueryResult::new(uuid::Uuid::new_v4(), 0.1, None, None),
+        ]);
+        match pipeline.process(segment()).await.unwrap() {
+            Outcome::Matched(results) => {
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].id(), id);
+            }
+            outcome => panic!("Unexpected {outcome:?}"),
+        }
+        let matches = matches_storage.get(id).await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].offsets(), Some(offsets));
+        assert_eq!(matches[0].source().rejected[0].score, 10);
+        assert_eq!(matches[0].source().query_id, None);
+        assert_eq!(backend.tracks().len(), 1);
+
+        backend.push_result(vec![QueryResult::new(id, 0.8, None, None)]);
+        pipeline.process(segment()).await.unwrap();
+        let airplays = matches_storage.airplays(&Default::default()).await.unwrap();
+        assert_eq!(airplays.len(), 1);
+        assert_eq!(airplays[0].station, "kosta");
+        assert_eq!(airplays[0].segments, 2);
+        assert_eq!(airplays[0].max_score, 90);
+    }
+
+    #[tokio::test]
+    async fn test_query_audio() {
+        let backend = MockBackend::new();
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let queue_config = QueueConfig::default();
+        let registry = Registry::new(
+            &queue_config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+        let match_config = MatchConfig {
+            store_query_audio: true,
+            ..Default::default()
+        };
+        let pipeline = Pipeline::new(
+            &backend,
+            &transcoder,
+            &registry,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &match_config,
+        );
+
+        let track = uuid::Uuid::new_v4();
+        backend.push_result(vec![QueryResult::new(track, 0.9, None, None)]);
+        let aired = Utc::now();
+        let mut matched = segment();
+        matched.source = SegmentSource {
+            station: Some("kosta".to_owned()),
+            media_sequence: Some(42),
+            aired: Some(aired),
+            ..Default::default()
+        };
+        pipeline.process(matched).await.unwrap();
+
+        let matches = matches_storage.get(track).await.unwrap();
+        let source = matches[0].source();
+        assert_eq!(source.station.as_deref(), Some("kosta"));
+        assert_eq!(source.media_sequence, Some(42));
+        assert_eq!(source.aired, Some(aired));
+        assert!(source.rejected.is_empty());
+        let query = audio_storage.get(source.query_id.unwrap()).await.unwrap();
+        assert_eq!(query.bytes(), &segment().bytes);
+    }
+
+    #[tokio::test]
+    async fn test_airplay_aired() {
+        let backend = MockBackend::new();
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let queue_config = QueueConfig::default();
+        let registry = Registry::new(
+            &queue_config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+        let airplay_config = AirplayConfig::default();
+        let match_config = MatchConfig::default();
+        let pipeline = Pipeline::new(
+            &backend,
+            &transcoder,
+            &registry,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &match_config,
+        )
+        .with_airplay("kosta", &airplay_config);
+
+        // Segments retried an hour after they aired, 10s apart.
+        let track = uuid::Uuid::new_v4();
+        let aired = Utc.with_ymd_and_hms(2022, 6, 1, 10, 0, 0).unwrap();
+        for offset in [0, 10] {
+            backend.push_result(vec![QueryResult::new(track, 0.9, None, None)]);
+            let mut matched = segment();
+            matched.source.aired = Some(aired + chrono::Duration::seconds(offset));
+            pipeline.process(matched).await.unwrap();
+        }
+        // Without an air time, the time it is processed.
+        backend.push_result(vec![QueryResult::new(track, 0.9, None, None)]);
+        pipeline.process(segment()).await.unwrap();
+
+        let airplays = matches_storage.airplays(&Default::default()).await.unwrap();
+        assert_eq!(airplays.len(), 2);
+        let airplay = airplays.iter().find(|a| a.start == aired).unwrap();
+        assert_eq!(airplay.end, aired + chrono::Duration::seconds(10));
+        assert_eq!(airplay.segments, 2);
+    }
+}
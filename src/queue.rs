@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::config::QueueConfig;
+use crate::pipeline::Pipeline;
+use crate::registry::Registry;
+use crate::storage::{QueueStats, QueueStorage};
+
+/// Retries queued segments, and backend inserts of registered items, once the fingerprint
+/// backend is back.
+pub struct QueueWorker<'a> {
+    config: &'a QueueConfig,
+    pipeline: &'a Pipeline<'a>,
+    registry: &'a Registry<'a>,
+    queue: &'a QueueStorage,
+}
+
+impl<'a> QueueWorker<'a> {
+    pub fn new(
+        config: &'a QueueConfig,
+        pipeline: &'a Pipeline<'a>,
+        registry: &'a Registry<'a>,
+        queue: &'a QueueStorage,
+    ) -> Self {
+        Self {
+            config,
+            pipeline,
+            registry,
+            queue,
+        }
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        log_stats(&self.queue.stats().await?);
+        loop {
+            self.drain().await?;
+            self.registry.publish_due().await?;
+            tokio::time::sleep(self.config.poll_interval).await;
+        }
+    }
+
+    /// Retries due segments, oldest first, and stops at the first failure. Returns the number of
+    /// segments done.
+    pub async fn drain(&self) -> anyhow::Result<usize> {
+        let due = self.queue.due(Utc::now(), self.config.batch_size).await?;
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let mut done = 0;
+        for queued in due {
+            match self.pipeline.retry(&queued).await {
+                Ok(outcome) => {
+                    log::info!("Queue: retried {}, {outcome:?}", queued.id);
+                    done += 1;
+                }
+                Err(e) => {
+                    let delay = backoff(self.config, queued.attempts);
+                    log::warn!(
+                        "Queue: retry {} of {} failed, next in {}s: {e:#}",
+                        queued.attempts + 1,
+                        queued.id,
+                        delay.as_secs()
+                    );
+                    self.queue
+                        .postpone(queued.id, Utc::now() + chrono::Duration::from_std(delay)?)
+                        .await?;
+                    break;
+                }
+            }
+        }
+
+        log_stats(&self.queue.stats().await?);
+        Ok(done)
+    }
+}
+
+/// Delay before the next attempt after `attempts` failed retries.
+pub fn backoff(config: &QueueConfig, attempts: u32) -> Duration {
+    config
+        .min_backoff
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(config.max_backoff)
+}
+
+fn log_stats(stats: &QueueStats) {
+    match stats.oldest {
+        Some(oldest) => log::info!(
+            "Queue: {} segments, oldest {}s old",
+            stats.depth,
+            (Utc::now() - oldest).num_seconds()
+        ),
+        None => log::info!("Queue: empty"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{backoff, QueueWorker};
+    use crate::config::{MatchConfig, QueueConfig, TranscodeConfig};
+    use crate::emysound::{MockBackend, QueryResult};
+    use crate::pipeline::{Outcome, Pipeline, Segment};
+    use crate::registry::Registry;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioKind, AudioStorage, Database};
+    use crate::storage::{MatchesStorage, MetadataStorage, OutboxStorage, QueueStorage};
+    use crate::transcode::Transcoder;
+
+    #[test]
+    fn test_backoff() {
+        let config = QueueConfig::default();
+        assert_eq!(backoff(&config, 0), Duration::from_secs(10));
+        assert_eq!(backoff(&config, 2), Duration::from_secs(40));
+        assert_eq!(backoff(&config, 40), Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let backend = MockBackend::new();
+        let transcode_config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&transcode_config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let queue_config = QueueConfig::default();
+        let registry = Registry::new(
+            &queue_config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+        let match_config = MatchConfig::default();
+        let pipeline = Pipeline::new(
+            &backend,
+            &transcoder,
+            &registry,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &match_config,
+        );
+        let worker = QueueWorker::new(&queue_config, &pipeline, &registry, &queue);
+
+        backend.set_unavailable(true);
+        let segment = Segment {
+            filename: "segment.aac".to_owned(),
+            format: "audio/aac".to_owned(),
+            bytes: b"1234567890".as_ref().into(),
+            kind: AudioKind::Music,
+            artist: "Artist".to_owned(),
+            title: "Title".to_owned(),
+            source: Default::default(),
+        };
+        let id = match pipeline.process(segment).await.unwrap() {
+            Outcome::Queued(id) => id,
+            outcome => panic!("Unexpected {outcome:?}"),
+        };
+        assert_eq!(queue.stats().await.unwrap().depth, 1);
+        assert!(audio_storage.get(id).await.is_ok());
+        assert!(metadata_storage.get(id).await.is_err());
+
+        assert_eq!(worker.drain().await.unwrap(), 0);
+        let stats = queue.stats().await.unwrap();
+        assert_eq!(stats.depth, 1);
+        // Postponed, so not due again yet.
+        assert!(queue.due(chrono::Utc::now(), 10).await.unwrap().is_empty());
+
+        backend.set_unavailable(false);
+        queue
+            .postpone(id, chrono::Utc::now() - chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(worker.drain().await.unwrap(), 1);
+        assert_eq!(queue.stats().await.unwrap().depth, 0);
+        assert_eq!(backend.tracks()[0].id(), id);
+        assert_eq!(metadata_storage.get(id).await.unwrap().id, id);
+        assert_eq!(audio_storage.get(id).await.unwrap().format(), "audio/aac");
+
+        // A queued segment that matches keeps no audio.
+        backend.set_unavailable(true);
+        let segment = Segment {
+            filename: "segment2.aac".to_owned(),
+            format: "audio/aac".to_owned(),
+            bytes: b"1234567890".as_ref().into(),
+            kind: AudioKind::Music,
+            artist: "Artist".to_owned(),
+            title: "Title".to_owned(),
+            source: Default::default(),
+        };
+        let queued_id = match pipeline.process(segment).await.unwrap() {
+            Outcome::Queued(id) => id,
+            outcome => panic!("Unexpected {outcome:?}"),
+        };
+        backend.set_unavailable(false);
+        backend.push_result(vec![QueryResult::new(id, 0.9, None, None)]);
+        queue
+            .postpone(queued_id, chrono::Utc::now() - chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(worker.drain().await.unwrap(), 1);
+        assert!(audio_storage.get(queued_id).await.is_err());
+        assert_eq!(matches_storage.get(id).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::emysound::{FingerprintBackend, TrackInfo};
+use crate::output::{print_json, table, Output};
+use crate::reindex;
+use crate::storage::{AudioStorage, MatchesStorage, MetadataStorage, OutboxStorage, QueueStorage};
+use crate::transcode::Transcoder;
+
+/// How an id differs between the fingerprint backend and the local stores.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// A track in the backend without local metadata.
+    RemoteOnly,
+    /// Local metadata without a track in the backend.
+    LocalOnly,
+    /// Local metadata without stored audio.
+    MissingAudio,
+    /// Stored audio of neither an item nor a queued segment.
+    OrphanAudio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Drift {
+    pub id: Uuid,
+    pub problem: Problem,
+}
+
+/// Finds and repairs drift between the fingerprint backend and the local stores, which have no
+/// transaction across them.
+pub struct Reconciler<'a> {
+    backend: &'a dyn FingerprintBackend,
+    transcoder: &'a Transcoder<'a>,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+    matches_storage: &'a MatchesStorage,
+    queue: &'a QueueStorage,
+    outbox: &'a OutboxStorage,
+}
+
+impl<'a> Reconciler<'a> {
+    pub fn new(
+        backend: &'a dyn FingerprintBackend,
+        transcoder: &'a Transcoder<'a>,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+        matches_storage: &'a MatchesStorage,
+        queue: &'a QueueStorage,
+        outbox: &'a OutboxStorage,
+    ) -> Self {
+        Self {
+            backend,
+            transcoder,
+            metadata_storage,
+            audio_storage,
+            matches_storage,
+            queue,
+            outbox,
+        }
+    }
+
+    pub async fn diff(&self) -> anyhow::Result<Vec<Drift>> {
+        let remote: HashSet<Uuid> = self
+            .backend
+            .list_all()
+            .await?
+            .iter()
+            .map(TrackInfo::id)
+            .collect();
+        let local: HashSet<Uuid> = self.metadata_storage.ids().await?.into_iter().collect();
+        let audio: HashSet<Uuid> = self.audio_storage.ids().await?.into_iter().collect();
+        let queued: HashSet<Uuid> = self.queue.ids().await?.into_iter().collect();
+        // Registrations in progress are finished or rolled back by the registry.
+        let registering: HashSet<Uuid> = self.outbox.ids().await?.into_iter().collect();
+        // Audio of matched segments, kept for their matches.
+        let queries: HashSet<Uuid> = self
+            .matches_storage
+            .query_ids(None)
+            .await?
+            .into_iter()
+            .collect();
+        log::info!(
+            "Reconcile: {} tracks, {} items, {} audio, {} queued, {} registering",
+            remote.len(),
+            local.len(),
+            audio.len(),
+            queued.len(),
+            registering.len()
+        );
+
+        let mut drift = Vec::new();
+        let mut push = |ids: Vec<&Uuid>, problem| {
+            let mut ids = ids;
+            ids.sort();
+            drift.extend(ids.into_iter().map(|&id| Drift { id, problem }));
+        };
+        push(remote.difference(&local).collect(), Problem::RemoteOnly);
+        push(
+            local
+                .difference(&remote)
+                .filter(|id| !registering.contains(id))
+                .collect(),
+            Problem::LocalOnly,
+        );
+        push(local.difference(&audio).collect(), Problem::MissingAudio);
+        push(
+            audio
+                .iter()
+                .filter(|id| {
+                    !local.contains(id)
+                        && !queued.contains(id)
+                        && !registering.contains(id)
+                        && !queries.contains(id)
+                })
+                .collect(),
+            Problem::OrphanAudio,
+        );
+        Ok(drift)
+    }
+
+    /// Repairs `drift` found by [`Reconciler::diff`]:
+    ///
+    /// - tracks without local metadata are deleted from the backend;
+    /// - items missing in the backend are re-inserted from stored audio if `reinsert` is set and
+    ///   the audio is there, and deleted locally otherwise;
+    /// - orphan audio is deleted.
+    ///
+    /// Items with metadata and a track but no audio cannot be repaired and are only reported.
+    pub async fn repair(&self, drift: &[Drift], reinsert: bool) -> anyhow::Result<()> {
+        let missing_audio: HashSet<Uuid> = drift
+            .iter()
+            .filter(|d| d.problem == Problem::MissingAudio)
+            .map(|d| d.id)
+            .collect();
+
+        for Drift { id, problem } in drift {
+            let id = *id;
+            match problem {
+                Problem::RemoteOnly => {
+                    log::info!("Reconcile: delete track {id}");
+                    self.backend.delete(id).await?;
+                }
+                Problem::LocalOnly if reinsert && !missing_audio.contains(&id) => {
+                    log::info!("Reconcile: re-insert {id}");
+                    self.reinsert(id)
+                        .await
+                        .with_context(|| format!("Re-insert {id}"))?;
+                }
+                Problem::LocalOnly => {
+                    log::info!("Reconcile: delete item {id}");
+                    self.matches_storage.delete(id).await?;
+                    self.metadata_storage.delete(id).await?;
+                    if !missing_audio.contains(&id) {
+                        self.audio_storage.delete(id).await?;
+                    }
+                }
+                Problem::MissingAudio => {
+                    log::warn!("Reconcile: no audio for {id}, cannot repair");
+                }
+                Problem::OrphanAudio => {
+                    log::info!("Reconcile: delete audio {id}");
+                    self.audio_storage.delete(id).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn reinsert(&self, id: Uuid) -> anyhow::Result<()> {
+        let item = self.metadata_storage.get(id).await?;
+        reindex::insert(self.backend, self.transcoder, self.audio_storage, &item).await
+    }
+}
+
+pub fn print(drift: &[Drift], output: Output) -> anyhow::Result<()> {
+    match output {
+        Output::Json => print_json(drift),
+        Output::Table => {
+            let rows = drift
+                .iter()
+                .map(|d| vec![d.id.to_string(), format!("{:?}", d.problem)])
+                .collect::<Vec<_>>();
+            print!("{}", table(&["ID", "PROBLEM"], &rows));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::{Drift, Problem, Reconciler};
+    use crate::config::TranscodeConfig;
+    use crate::emysound::{FingerprintBackend, MockBackend, TrackInfo};
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, MatchData, MatchSource};
+    use crate::storage::{MatchesStorage, Metadata, MetadataStorage, OutboxStorage, QueueStorage};
+    use crate::transcode::Transcoder;
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let backend = MockBackend::new();
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let reconciler = Reconciler::new(
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &outbox,
+        );
+
+        let item = |id| {
+            Metadata::new(
+                id,
+                Utc::now(),
+                AudioKind::Music,
+                "Artist".to_owned(),
+                "Title".to_owned(),
+            )
+        };
+        let audio = |id| AudioData::new(id, "audio/aac".to_owned(), b"123".as_ref().into());
+        let track = |id| TrackInfo::new(id, "Artist".to_owned(), "Title".to_owned());
+
+        // In sync.
+        let synced = Uuid::new_v4();
+        metadata_storage.insert(&item(synced)).await.unwrap();
+        audio_storage.insert(&audio(synced)).await.unwrap();
+        backend
+            .insert(track(synced), "", &Default::default())
+            .await
+            .unwrap();
+
+        // Backend insert succeeded, local insert failed.
+        let remote_only = Uuid::new_v4();
+        backend
+            .insert(track(remote_only), "", &Default::default())
+            .await
+            .unwrap();
+
+        // Local insert succeeded, backend insert lost.
+        let local_only = Uuid::new_v4();
+        metadata_storage.insert(&item(local_only)).await.unwrap();
+        audio_storage.insert(&audio(local_only)).await.unwrap();
+
+        let orphan = Uuid::new_v4();
+        audio_storage.insert(&audio(orphan)).await.unwrap();
+
+        // Registered, waiting for the backend.
+        let registering = Uuid::new_v4();
+        outbox
+            .reserve(registering, Utc::now(), false)
+            .await
+            .unwrap();
+        audio_storage.insert(&audio(registering)).await.unwrap();
+        outbox.register(&item(registering)).await.unwrap();
+
+        // Audio of a segment that matched.
+        let query = Uuid::new_v4();
+        audio_storage.insert(&audio(query)).await.unwrap();
+        matches_storage
+            .insert(
+                &MatchData::new(synced, Utc::now(), 90).with_source(MatchSource {
+                    query_id: Some(query),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+
+        let drift = reconciler.diff().await.unwrap();
+        assert_eq!(
+            drift,
+            vec![
+                Drift {
+                    id: remote_only,
+                    problem: Problem::RemoteOnly
+                },
+                Drift {
+                    id: local_only,
+                    problem: Problem::LocalOnly
+                },
+                Drift {
+                    id: orphan,
+                    problem: Problem::OrphanAudio
+                },
+            ]
+        );
+
+        reconciler.repair(&drift, true).await.unwrap();
+        assert!(reconciler.diff().await.unwrap().is_empty());
+
+        let ids: Vec<Uuid> = backend.tracks().iter().map(TrackInfo::id).collect();
+        assert_eq!(ids, vec![synced, local_only]);
+        assert!(audio_storage.get(orphan).await.is_err());
+        assert!(audio_storage.get(registering).await.is_ok());
+        assert!(audio_storage.get(query).await.is_ok());
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::Context;
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::config::QueueConfig;
+use crate::emysound::FingerprintBackend;
+use crate::queue::backoff;
+use crate::reindex;
+use crate::storage::{AudioData, AudioStorage, Metadata, MetadataStorage};
+use crate::storage::{OutboxEntry, OutboxState, OutboxStorage};
+use crate::transcode::Transcoder;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Registrations interrupted before their metadata was stored.
+    pub rolled_back: usize,
+    /// Registered items inserted into the backend.
+    pub published: usize,
+    /// Registered items still waiting for the backend.
+    pub pending: usize,
+}
+
+/// Registers new items: stores their audio and metadata, then inserts their track into the
+/// fingerprint backend.
+///
+/// The outbox records each registration until it is done. Metadata is stored with the
+/// registration marked pending in one transaction, so an item either has metadata and is
+/// inserted into the backend eventually, or is rolled back with its audio by
+/// [`Registry::recover`]. A crash right after the backend insert may insert the track again.
+pub struct Registry<'a> {
+    config: &'a QueueConfig,
+    backend: &'a dyn FingerprintBackend,
+    transcoder: &'a Transcoder<'a>,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+    outbox: &'a OutboxStorage,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new(
+        config: &'a QueueConfig,
+        backend: &'a dyn FingerprintBackend,
+        transcoder: &'a Transcoder<'a>,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+        outbox: &'a OutboxStorage,
+    ) -> Self {
+        Self {
+            config,
+            backend,
+            transcoder,
+            metadata_storage,
+            audio_storage,
+            outbox,
+        }
+    }
+
+    /// Registers `metadata` with the audio as received, transcoded if configured for its kind.
+    ///
+    /// A `queued` segment already has its audio stored as received, and is removed from the
+    /// queue with the metadata stored. Backend failures are logged and retried by
+    /// [`Registry::publish_due`], the item is registered locally all the same.
+    pub async fn register(
+        &self,
+        metadata: &Metadata,
+        original: AudioData,
+        queued: bool,
+    ) -> anyhow::Result<()> {
+        let id = metadata.id;
+        let audio = self
+            .transcoder
+            .encode(metadata.kind(), original.clone())
+            .await;
+
+        self.outbox
+            .reserve(id, Utc::now(), queued)
+            .await
+            .context("Reserve id")?;
+        if let Err(e) = self.store_audio(&audio, queued).await {
+            // Nothing else is stored yet, recovery finishes the rollback if this fails too.
+            self.outbox.delete(id).await?;
+            return Err(e);
+        }
+        self.outbox
+            .register(metadata)
+            .await
+            .context("Insert metadata")?;
+
+        if let Err(e) = self.publish(metadata, Some(&original)).await {
+            self.postpone(id, 0, e).await?;
+        }
+        Ok(())
+    }
+
+    /// The queued audio is replaced in one step, so a failure keeps it for the retry.
+    async fn store_audio(&self, audio: &AudioData, queued: bool) -> anyhow::Result<()> {
+        match (queued, audio.is_transcoded()) {
+            (false, _) => self
+                .audio_storage
+                .insert(audio)
+                .await
+                .context("Insert audio"),
+            (true, true) => self
+                .audio_storage
+                .replace(audio)
+                .await
+                .context("Replace queued audio"),
+            (true, false) => Ok(()),
+        }
+    }
+
+    /// Rolls back registrations interrupted before their metadata was stored, then inserts
+    /// pending items into the backend. Run at startup, before new registrations.
+    pub async fn recover(&self) -> anyhow::Result<RecoveryStats> {
+        let mut stats = RecoveryStats::default();
+        for entry in self.outbox.entries().await? {
+            match entry.state {
+                OutboxState::Reserved => {
+                    self.rollback(&entry).await?;
+                    stats.rolled_back += 1;
+                }
+                OutboxState::Pending => match self.publish_entry(&entry).await? {
+                    true => stats.published += 1,
+                    false => stats.pending += 1,
+                },
+            }
+        }
+        if stats != RecoveryStats::default() {
+            log::info!("Registry: recovered {stats:?}");
+        }
+        Ok(stats)
+    }
+
+    /// Inserts pending items due for a retry into the backend, oldest first, and stops at the
+    /// first failure. Returns the number of items done.
+    pub async fn publish_due(&self) -> anyhow::Result<usize> {
+        let mut done = 0;
+        for entry in self.outbox.due(Utc::now(), self.config.batch_size).await? {
+            if !self.publish_entry(&entry).await? {
+                break;
+            }
+            done += 1;
+        }
+        Ok(done)
+    }
+
+    /// Audio of a queued segment stays, the segment is retried from the queue.
+    async fn rollback(&self, entry: &OutboxEntry) -> anyhow::Result<()> {
+        log::warn!(
+            "Registry: roll back interrupted registration of {}",
+            entry.id
+        );
+        if !entry.queued {
+            // The audio may not have been stored yet.
+            if let Err(e) = self.audio_storage.delete(entry.id).await {
+                log::debug!("Registry: delete audio {}: {e:#}", entry.id);
+            }
+        }
+        self.outbox.delete(entry.id).await
+    }
+
+    /// Returns whether the item is in the backend now.
+    async fn publish_entry(&self, entry: &OutboxEntry) -> anyhow::Result<bool> {
+        let metadata = self.metadata_storage.get(entry.id).await?;
+        match self.publish(&metadata, None).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                self.postpone(entry.id, entry.attempts, e).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Inserts the item into the backend from `original`, or its stored audio, and finishes
+    /// the registration.
+    async fn publish(
+        &self,
+        metadata: &Metadata,
+        original: Option<&AudioData>,
+    ) -> anyhow::Result<()> {
+        match original {
+            Some(audio) => reindex::insert_audio(self.backend, metadata, audio).await?,
+            None => {
+                reindex::insert(self.backend, self.transcoder, self.audio_storage, metadata).await?
+            }
+        }
+        self.outbox.delete(metadata.id).await
+    }
+
+    async fn postpone(&self, id: Uuid, attempts: u32, e: anyhow::Error) -> anyhow::Result<()> {
+        let delay = backoff(self.config, attempts);
+        log::warn!(
+            "Registry: insert {id} into the backend failed, next in {}s: {e:#}",
+            delay.as_secs()
+        );
+        self.outbox
+            .postpone(id, Utc::now() + chrono::Duration::from_std(delay)?)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::{RecoveryStats, Registry};
+    use crate::config::{QueueConfig, TranscodeConfig};
+    use crate::emysound::MockBackend;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, Metadata};
+    use crate::storage::{MetadataStorage, OutboxStorage};
+    use crate::transcode::Transcoder;
+
+    fn item() -> (Metadata, AudioData) {
+        let id = Uuid::new_v4();
+        (
+            Metadata::new(
+                id,
+                Utc::now(),
+                AudioKind::Music,
+                "Artist".to_owned(),
+                "Title".to_owned(),
+            ),
+            AudioData::new(id, "audio/aac".to_owned(), b"1234567890".as_ref().into()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let config = QueueConfig::default();
+        let backend = MockBackend::new();
+        let transcode_config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&transcode_config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let registry = Registry::new(
+            &config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+
+        let (metadata, audio) = item();
+        registry.register(&metadata, audio, false).await.unwrap();
+        assert_eq!(backend.tracks()[0].id(), metadata.id);
+        assert!(audio_storage.get(metadata.id).await.is_ok());
+        assert!(outbox.entries().await.unwrap().is_empty());
+
+        // Registered locally while the backend is down, inserted on recovery.
+        backend.set_unavailable(true);
+        let (metadata, audio) = item();
+        registry.register(&metadata, audio, false).await.unwrap();
+        assert_eq!(metadata_storage.get(metadata.id).await.unwrap(), metadata);
+        assert_eq!(outbox.entries().await.unwrap()[0].attempts, 1);
+        assert_eq!(registry.publish_due().await.unwrap(), 0);
+
+        backend.set_unavailable(false);
+        let stats = registry.recover().await.unwrap();
+        assert_eq!(
+            stats,
+            RecoveryStats {
+                published: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(backend.tracks()[1].id(), metadata.id);
+        assert!(outbox.entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recover_reserved() {
+        let config = QueueConfig::default();
+        let backend = MockBackend::new();
+        let transcode_config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&transcode_config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let registry = Registry::new(
+            &config,
+            &backend,
+            &transcoder,
+            &metadata_storage,
+            &audio_storage,
+            &outbox,
+        );
+
+        // Interrupted after storing the audio.
+        let (_, audio) = item();
+        outbox.reserve(audio.id(), Utc::now(), false).await.unwrap();
+        audio_storage.insert(&audio).await.unwrap();
+        // Interrupted before storing the audio.
+        let (_, other) = item();
+        outbox.reserve(other.id(), Utc::now(), false).await.unwrap();
+        // A queued segment keeps its audio.
+        let (_, queued) = item();
+        audio_storage.insert(&queued).await.unwrap();
+        outbox.reserve(queued.id(), Utc::now(), true).await.unwrap();
+
+        let stats = registry.recover().await.unwrap();
+        assert_eq!(stats.rolled_back, 3);
+        assert!(audio_storage.get(audio.id()).await.is_err());
+        assert!(audio_storage.get(queued.id()).await.is_ok());
+        assert!(outbox.entries().await.unwrap().is_empty());
+        assert!(metadata_storage.ids().await.unwrap().is_empty());
+        assert!(backend.tracks().is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use uuid::Uuid;
+
+use crate::emysound::{FingerprintBackend, TrackInfo};
+use crate::storage::{AudioData, AudioKind, AudioStorage, Metadata, MetadataStorage};
+use crate::transcode::{extension, AudioVariant, Transcoder};
+
+/// Which items to reindex. Empty `kinds` means all kinds.
+#[derive(Debug, Default)]
+pub struct ReindexFilter {
+    pub kinds: Vec<AudioKind>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReindexStats {
+    pub inserted: usize,
+    /// Already in the backend.
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Rebuilds the fingerprint backend from stored audio and metadata, keeping item ids.
+///
+/// Items already in the backend are skipped, so an interrupted run resumes where it stopped
+/// when started again.
+pub struct Reindexer<'a> {
+    backend: &'a dyn FingerprintBackend,
+    transcoder: &'a Transcoder<'a>,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+}
+
+impl<'a> Reindexer<'a> {
+    pub fn new(
+        backend: &'a dyn FingerprintBackend,
+        transcoder: &'a Transcoder<'a>,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+    ) -> Self {
+        Self {
+            backend,
+            transcoder,
+            metadata_storage,
+            audio_storage,
+        }
+    }
+
+    /// Inserts matching items with up to `concurrency` backend calls in flight.
+    pub async fn run(
+        &self,
+        filter: &ReindexFilter,
+        concurrency: usize,
+    ) -> anyhow::Result<ReindexStats> {
+        let indexed: HashSet<Uuid> = self
+            .backend
+            .list_all()
+            .await?
+            .iter()
+            .map(TrackInfo::id)
+            .collect();
+
+        let items: Vec<Metadata> = self
+            .metadata_storage
+            .between(filter.since, filter.until)
+            .await?
+            .into_iter()
+            .filter(|item| filter.kinds.is_empty() || filter.kinds.contains(&item.kind()))
+            .collect();
+        let total = items.len();
+
+        let mut stats = ReindexStats::default();
+        let (pending, done): (Vec<_>, Vec<_>) = items
+            .into_iter()
+            .partition(|item| !indexed.contains(&item.id));
+        stats.skipped = done.len();
+        log::info!(
+            "Reindex: {} items, {} already indexed",
+            total,
+            stats.skipped
+        );
+
+        let mut results = futures::stream::iter(pending)
+            .map(|item| async move {
+                let result = insert(self.backend, self.transcoder, self.audio_storage, &item).await;
+                (item.id, result)
+            })
+            .buffer_unordered(concurrency.max(1));
+
+        while let Some((id, result)) = results.next().await {
+            match result {
+                Ok(()) => stats.inserted += 1,
+                Err(e) => {
+                    log::error!("Reindex: failed to insert {id}: {e:#}");
+                    stats.failed += 1;
+                }
+            }
+            let progress = stats.inserted + stats.failed + stats.skipped;
+            if progress % 100 == 0 {
+                log::info!("Reindex: {progress}/{total}");
+            }
+        }
+
+        log::info!("Reindex: {stats:?}");
+        if stats.failed > 0 {
+            bail!("Reindex: {} items failed, run again to retry", stats.failed);
+        }
+        Ok(stats)
+    }
+}
+
+/// Inserts an item into the backend from its stored audio, with its original id, artist and
+/// title.
+pub async fn insert(
+    backend: &dyn FingerprintBackend,
+    transcoder: &Transcoder<'_>,
+    audio_storage: &dyn AudioStorage,
+    item: &Metadata,
+) -> anyhow::Result<()> {
+    let audio = transcoder
+        .get(audio_storage, item.id, AudioVariant::Stored)
+        .await?;
+    insert_audio(backend, item, &audio).await
+}
+
+/// Inserts an item into the backend from `audio`, with its id, artist and title.
+pub async fn insert_audio(
+    backend: &dyn FingerprintBackend,
+    item: &Metadata,
+    audio: &AudioData,
+) -> anyhow::Result<()> {
+    backend
+        .insert(
+            TrackInfo::new(item.id, item.artist().to_owned(), item.title().to_owned()),
+            &format!("{}.{}", item.id, extension(audio.format())),
+            audio.bytes(),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::{ReindexFilter, ReindexStats, Reindexer};
+    use crate::config::TranscodeConfig;
+    use crate::emysound::{FingerprintBackend, MockBackend, TrackInfo};
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, Metadata, MetadataStorage};
+    use crate::transcode::Transcoder;
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let backend = MockBackend::new();
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let reindexer = Reindexer::new(&backend, &transcoder, &metadata_storage, &audio_storage);
+
+        let now = Utc::now();
+        let mut items = Vec::new();
+        for (days, kind) in [
+            (3, AudioKind::Music),
+            (2, AudioKind::Talk),
+            (1, AudioKind::Music),
+            (0, AudioKind::Music),
+        ] {
+            let item = Metadata::new(
+                Uuid::new_v4(),
+                now - Duration::days(days),
+                kind,
+                format!("Artist {days}"),
+                "Title".to_owned(),
+            );
+            metadata_storage.insert(&item).await.unwrap();
+            audio_storage
+                .insert(&AudioData::new(
+                    item.id,
+                    "audio/aac".to_owned(),
+                    b"123".as_ref().into(),
+                ))
+                .await
+                .unwrap();
+            items.push(item);
+        }
+        // Already indexed before the wipe was interrupted.
+        backend
+            .insert(
+                TrackInfo::new(items[3].id, "Artist 0".to_owned(), "Title".to_owned()),
+                "",
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+
+        let filter = ReindexFilter {
+            kinds: vec![AudioKind::Music],
+            since: Some(now - Duration::hours(60)),
+            until: None,
+        };
+        assert_eq!(
+            reindexer.run(&filter, 2).await.unwrap(),
+            ReindexStats {
+                inserted: 1,
+                skipped: 1,
+                failed: 0
+            }
+        );
+        let track = backend
+            .tracks()
+            .into_iter()
+            .find(|track| track.id() == items[2].id)
+            .unwrap();
+        assert_eq!(track.artist(), "Artist 1");
+
+        assert_eq!(
+            reindexer
+                .run(&ReindexFilter::default(), 2)
+                .await
+                .unwrap()
+                .inserted,
+            2
+        );
+        assert_eq!(backend.tracks().len(), 4);
+    }
+}
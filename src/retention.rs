@@ -0,0 +1,314 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::time::Instant;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::config::RetentionConfig;
+use crate::emysound::FingerprintBackend;
+use crate::storage::QueueStorage;
+use crate::storage::{AudioKind, AudioStorage, MatchesStorage, MetadataStorage, OutboxStorage};
+
+const DEFAULT_VACUUM_PAGES: u32 = 1000;
+
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub items: usize,
+    pub matches: usize,
+    /// Audio without an item.
+    pub orphan_audio: usize,
+    /// Items that could not be deleted, retried on the next prune.
+    pub failed: usize,
+}
+
+/// Deletes items past the retention period of their kind from all stores, and orphans left by
+/// interrupted writes if configured.
+pub struct Pruner<'a> {
+    config: &'a RetentionConfig,
+    backend: &'a dyn FingerprintBackend,
+    metadata_storage: &'a MetadataStorage,
+    audio_storage: &'a dyn AudioStorage,
+    matches_storage: &'a MatchesStorage,
+    queue: &'a QueueStorage,
+    outbox: &'a OutboxStorage,
+    last_run: Cell<Option<Instant>>,
+}
+
+impl<'a> Pruner<'a> {
+    pub fn new(
+        config: &'a RetentionConfig,
+        backend: &'a dyn FingerprintBackend,
+        metadata_storage: &'a MetadataStorage,
+        audio_storage: &'a dyn AudioStorage,
+        matches_storage: &'a MatchesStorage,
+        queue: &'a QueueStorage,
+        outbox: &'a OutboxStorage,
+    ) -> Self {
+        Self {
+            config,
+            backend,
+            metadata_storage,
+            audio_storage,
+            matches_storage,
+            queue,
+            outbox,
+            last_run: Cell::new(None),
+        }
+    }
+
+    /// Returns `true` if background pruning is configured and the interval has passed.
+    pub fn is_due(&self) -> bool {
+        match (self.config.interval, self.last_run.get()) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last_run)) => last_run.elapsed() >= interval,
+        }
+    }
+
+    pub async fn prune(&self, dry_run: bool) -> anyhow::Result<PruneStats> {
+        self.last_run.set(Some(Instant::now()));
+
+        let mut stats = PruneStats::default();
+        for kind in [
+            AudioKind::Advertisement,
+            AudioKind::Music,
+            AudioKind::Talk,
+            AudioKind::Unknown,
+        ] {
+            let period = match self.config.period(kind) {
+                Some(period) => chrono::Duration::from_std(period)?,
+                None => continue,
+            };
+
+            let expired = self
+                .metadata_storage
+                .older_than(kind, Utc::now() - period)
+                .await?;
+            log::info!("Prune: {} expired {kind} items", expired.len());
+            if dry_run {
+                stats.items += expired.len();
+                continue;
+            }
+
+            for id in expired {
+                self.try_prune_item(id, &mut stats).await;
+            }
+        }
+
+        if self.config.orphans {
+            self.prune_orphans(dry_run, &mut stats).await?;
+        }
+
+        if let Some(period) = self.config.matches {
+            let before = Utc::now() - chrono::Duration::from_std(period)?;
+            if !dry_run {
+                // All matches of a segment are recorded at once, so its audio goes with them.
+                let queries = self.matches_storage.query_ids(Some(before)).await?;
+                stats.matches += self.matches_storage.delete_before(before).await?;
+                for id in queries {
+                    if let Err(e) = self.audio_storage.delete(id).await {
+                        log::warn!("Prune: no query audio for {id}: {e:#}");
+                    }
+                }
+            }
+        }
+
+        log::info!(
+            "Prune: deleted {} items, {} matches, {} orphan audio{}",
+            stats.items,
+            stats.matches,
+            stats.orphan_audio,
+            if dry_run { " (dry run)" } else { "" }
+        );
+        if stats.failed > 0 {
+            log::warn!("Prune: {} items failed, retrying next time", stats.failed);
+        }
+
+        if !dry_run {
+            let pages = self.config.vacuum_pages.unwrap_or(DEFAULT_VACUUM_PAGES);
+            self.audio_storage.vacuum(pages).await?;
+            self.metadata_storage.vacuum(pages).await?;
+            self.matches_storage.vacuum(pages).await?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Deletes metadata without audio, and audio of neither an item, a queued segment, a
+    /// registration in progress nor a match.
+    ///
+    /// Audio is written before the metadata of an item, so listing items before audio never
+    /// takes an item being registered for one without audio. Audio listed without an item may
+    /// have been registered since the items were listed, so that is checked again before it is
+    /// deleted.
+    async fn prune_orphans(&self, dry_run: bool, stats: &mut PruneStats) -> anyhow::Result<()> {
+        let items = self.metadata_storage.ids().await?;
+        let audio = self.audio_storage.ids().await?;
+        let stored: HashSet<Uuid> = audio.iter().copied().collect();
+        let mut referenced: HashSet<Uuid> = items.iter().copied().collect();
+        referenced.extend(self.queue.ids().await?);
+        let registering: HashSet<Uuid> = self.outbox.ids().await?.into_iter().collect();
+        referenced.extend(&registering);
+        referenced.extend(self.matches_storage.query_ids(None).await?);
+
+        let orphan_items: Vec<Uuid> = items
+            .into_iter()
+            .filter(|id| !stored.contains(id) && !registering.contains(id))
+            .collect();
+        let orphan_audio: Vec<Uuid> = audio
+            .into_iter()
+            .filter(|id| !referenced.contains(id))
+            .collect();
+        log::info!(
+            "Prune: {} items without audio, {} audio without item",
+            orphan_items.len(),
+            orphan_audio.len()
+        );
+        if dry_run {
+            stats.items += orphan_items.len();
+            stats.orphan_audio += orphan_audio.len();
+            return Ok(());
+        }
+
+        for id in orphan_items {
+            self.try_prune_item(id, stats).await;
+        }
+        for id in orphan_audio {
+            if self.metadata_storage.contains(id).await? {
+                continue;
+            }
+            match self.audio_storage.delete(id).await {
+                Ok(()) => stats.orphan_audio += 1,
+                Err(e) => {
+                    log::error!("Prune: failed to delete audio {id}: {e:#}");
+                    stats.failed += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prunes `id`, logging failures so one item cannot stop the others.
+    async fn try_prune_item(&self, id: Uuid, stats: &mut PruneStats) {
+        if let Err(e) = self.prune_item(id, stats).await {
+            log::error!("Prune: failed to delete {id}: {e:#}");
+            stats.failed += 1;
+        }
+    }
+
+    async fn prune_item(&self, id: Uuid, stats: &mut PruneStats) -> anyhow::Result<()> {
+        if self.config.delete_tracks {
+            self.backend.delete(id).await?;
+        }
+
+        // Audio may already be gone after an earlier, interrupted prune.
+        if let Err(e) = self.audio_storage.delete(id).await {
+            log::warn!("Prune: no audio for {id}: {e:#}");
+        }
+
+        stats.matches += self.matches_storage.delete(id).await?;
+        self.metadata_storage.delete(id).await?;
+        stats.items += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::Pruner;
+    use crate::config::RetentionConfig;
+    use crate::emysound::{FingerprintBackend, MockBackend, TrackInfo};
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database, MatchesStorage, Metadata};
+    use crate::storage::{MetadataStorage, OutboxStorage, QueueStorage};
+
+    #[tokio::test]
+    async fn test_prune() {
+        let config = RetentionConfig {
+            talk: Some(Duration::from_secs(24 * 3600)),
+            delete_tracks: true,
+            orphans: true,
+            ..Default::default()
+        };
+        let backend = MockBackend::new();
+        let db = Database::open(&":memory:").unwrap();
+        let metadata_storage = MetadataStorage::new(&db);
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let matches_storage = MatchesStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let outbox = OutboxStorage::new(&db);
+        let pruner = Pruner::new(
+            &config,
+            &backend,
+            &metadata_storage,
+            &audio_storage,
+            &matches_storage,
+            &queue,
+            &outbox,
+        );
+
+        let item = |id, days| {
+            Metadata::new(
+                id,
+                Utc::now() - chrono::Duration::days(days),
+                AudioKind::Talk,
+                "Artist".to_owned(),
+                "Title".to_owned(),
+            )
+        };
+        let audio = |id| AudioData::new(id, "audio/aac".to_owned(), b"123".as_ref().into());
+        let store = |id, days| {
+            let item = item(id, days);
+            let audio = audio(id);
+            let (metadata_storage, audio_storage) = (&metadata_storage, &audio_storage);
+            async move {
+                metadata_storage.insert(&item).await.unwrap();
+                audio_storage.insert(&audio).await.unwrap();
+            }
+        };
+
+        let kept = Uuid::new_v4();
+        store(kept, 0).await;
+        // The track is already gone from the backend.
+        let expired = Uuid::new_v4();
+        store(expired, 2).await;
+        let tracked = Uuid::new_v4();
+        store(tracked, 2).await;
+        backend
+            .insert(
+                TrackInfo::new(tracked, "Artist".to_owned(), "Title".to_owned()),
+                "",
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        let no_audio = Uuid::new_v4();
+        metadata_storage.insert(&item(no_audio, 0)).await.unwrap();
+        let orphan = Uuid::new_v4();
+        audio_storage.insert(&audio(orphan)).await.unwrap();
+
+        let stats = pruner.prune(true).await.unwrap();
+        assert_eq!((stats.items, stats.orphan_audio), (3, 1));
+        assert_eq!(metadata_storage.ids().await.unwrap().len(), 4);
+
+        // Failures are counted and retried on the next prune.
+        backend.set_unavailable(true);
+        let stats = pruner.prune(false).await.unwrap();
+        assert_eq!((stats.items, stats.orphan_audio, stats.failed), (0, 1, 3));
+
+        backend.set_unavailable(false);
+        let stats = pruner.prune(false).await.unwrap();
+        assert_eq!((stats.items, stats.failed), (3, 0));
+        assert_eq!(metadata_storage.ids().await.unwrap(), vec![kept]);
+        let mut audio_ids = audio_storage.ids().await.unwrap();
+        audio_ids.sort();
+        assert_eq!(audio_ids, vec![kept]);
+        assert!(backend.tracks().is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::output::{print_json, table, Output};
+use crate::storage::{AirplayFilter, Bucket, MatchOffsets, MatchSource, MatchesStorage};
+use crate::storage::{MetadataStorage, PlayCount, StationStats};
+
+#[derive(Debug, Serialize)]
+struct TopRow {
+    track_id: Uuid,
+    artist: Option<String>,
+    title: Option<String>,
+    plays: u32,
+    segments: u32,
+    /// First and last match ever, not only within the period.
+    first_seen: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct MatchRow {
+    timestamp: DateTime<Utc>,
+    score: u8,
+    offsets: Option<MatchOffsets>,
+    #[serde(flatten)]
+    source: MatchSource,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    stations: Vec<StationStats>,
+    top: Vec<TopRow>,
+    plays: Vec<PlayCount>,
+    /// Matches of the track filtered by, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<MatchRow>>,
+}
+
+/// Prints plays matching `filter` per station, the `top` most played tracks and plays per
+/// `bucket`, and the matches of the track filtered by.
+pub async fn print(
+    matches_storage: &MatchesStorage,
+    metadata_storage: &MetadataStorage,
+    filter: &AirplayFilter,
+    top: usize,
+    bucket: Bucket,
+    output: Output,
+) -> anyhow::Result<()> {
+    let mut top_rows = Vec::new();
+    for track in matches_storage.top_tracks(filter, top).await? {
+        let metadata = metadata_storage.get(track.track_id).await.ok();
+        let seen = matches_storage.seen(track.track_id).await?;
+        top_rows.push(TopRow {
+            track_id: track.track_id,
+            artist: metadata.as_ref().map(|m| m.artist().to_owned()),
+            title: metadata.as_ref().map(|m| m.title().to_owned()),
+            plays: track.plays,
+            segments: track.segments,
+            first_seen: seen.as_ref().map(|s| s.first),
+            last_seen: seen.as_ref().map(|s| s.last),
+        });
+    }
+
+    let matches = match filter.track_id {
+        Some(id) => Some(
+            matches_storage
+                .between(Some(id), filter.since, filter.until)
+                .await?
+                .iter()
+                .map(|m| MatchRow {
+                    timestamp: m.timestamp(),
+                    score: m.score(),
+                    offsets: m.offsets(),
+                    source: m.source().clone(),
+                })
+                .collect(),
+        ),
+        None => None,
+    };
+
+    let stats = Stats {
+        stations: matches_storage.station_stats(filter).await?,
+        top: top_rows,
+        plays: matches_storage.plays_per(bucket, filter).await?,
+        matches,
+    };
+
+    match output {
+        Output::Json => print_json(&stats),
+        Output::Table => {
+            print_table(&stats);
+            Ok(())
+        }
+    }
+}
+
+fn print_table(stats: &Stats) {
+    let time = |time: DateTime<Utc>| time.format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let rows = stats
+        .stations
+        .iter()
+        .map(|s| {
+            vec![
+                s.station.clone(),
+                s.plays.to_string(),
+                s.segments.to_string(),
+                s.tracks.to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!(
+        "{}",
+        table(&["STATION", "PLAYS", "SEGMENTS", "TRACKS"], &rows)
+    );
+
+    let rows = stats
+        .top
+        .iter()
+        .enumerate()
+        .map(|(rank, t)| {
+            vec![
+                (rank + 1).to_string(),
+                t.track_id.to_string(),
+                t.plays.to_string(),
+                t.segments.to_string(),
+                t.first_seen.map(time).unwrap_or_default(),
+                t.last_seen.map(time).unwrap_or_default(),
+                t.artist.clone().unwrap_or_default(),
+                t.title.clone().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!(
+        "\n{}",
+        table(
+            &[
+                "RANK",
+                "TRACK",
+                "PLAYS",
+                "SEGMENTS",
+                "FIRST SEEN",
+                "LAST SEEN",
+                "ARTIST",
+                "TITLE"
+            ],
+            &rows
+        )
+    );
+
+    let rows = stats
+        .plays
+        .iter()
+        .map(|p| vec![time(p.start), p.plays.to_string()])
+        .collect::<Vec<_>>();
+    print!("\n{}", table(&["FROM", "PLAYS"], &rows));
+
+    if let Some(matches) = &stats.matches {
+        let rows = matches
+            .iter()
+            .map(|m| {
+                let source = &m.source;
+                vec![
+                    time(m.timestamp),
+                    m.score.to_string(),
+                    source.station.clone().unwrap_or_default(),
+                    source
+                        .media_sequence
+                        .map(|s| s.to_string())
+                        .unwrap_or_default(),
+                    source.aired.map(time).unwrap_or_default(),
+                    source.query_id.map(|id| id.to_string()).unwrap_or_default(),
+                    source.rejected.len().to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        print!(
+            "\n{}",
+            table(
+                &["MATCHED", "SCORE", "STATION", "SEQUENCE", "AIRED", "QUERY", "REJECTED"],
+                &rows
+            )
+        );
+    }
+}
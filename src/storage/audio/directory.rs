@@ -0,0 +1,293 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use super::{AudioData, AudioStorage};
+
+/// Content-addressed audio storage in a plain directory.
+///
+/// Audio bytes are stored once per SHA-256 digest under `objects/ab/cd/<digest>`, and every id
+/// gets a small index file under `ids/<uu>/<id>` holding the format, the digest and the original
+/// format. References to an object are tracked as empty files under `refs/ab/cd/<digest>/<id>`,
+/// and the object is removed together with its last reference. All files are written to `tmp/`
+/// first and renamed into place, so readers never see partial writes. Objects and references
+/// change under a lock per shard, see [`DirectoryAudioStorage::lock`].
+pub struct DirectoryAudioStorage {
+    root: PathBuf,
+}
+
+impl DirectoryAudioStorage {
+    pub fn new<P>(path: &P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let root = path.as_ref().to_path_buf();
+        for dir in ["objects", "ids", "refs", "tmp"] {
+            std::fs::create_dir_all(root.join(dir))
+                .with_context(|| format!("Create {}", root.join(dir).display()))?;
+        }
+        Ok(Self { root })
+    }
+
+    /// `<dir>/ab/cd/<digest>`, so entries are spread over 65536 directories.
+    fn sharded(&self, dir: &str, digest: &str) -> PathBuf {
+        self.root
+            .join(dir)
+            .join(&digest[0..2])
+            .join(&digest[2..4])
+            .join(digest)
+    }
+
+    fn object_path(&self, digest: &str) -> PathBuf {
+        self.sharded("objects", digest)
+    }
+
+    fn ref_path(&self, digest: &str, id: Uuid) -> PathBuf {
+        self.sharded("refs", digest).join(id.to_string())
+    }
+
+    fn index_path(&self, id: Uuid) -> PathBuf {
+        let id = id.to_string();
+        self.root.join("ids").join(&id[0..2]).join(id)
+    }
+
+    /// Locks the shard of `digest` until the returned file is dropped. Whether an object is
+    /// written or removed is decided from its references under this lock, so an insert never
+    /// relies on an object that the release of its last other reference is removing. It is a
+    /// `flock`, which holds against other processes on the directory too.
+    async fn lock(&self, digest: &str) -> anyhow::Result<std::fs::File> {
+        let path = self
+            .root
+            .join("locks")
+            .join(&digest[0..2])
+            .join(&digest[2..4]);
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Open lock {}", path.display()))?;
+            file.lock()
+                .with_context(|| format!("Lock {}", path.display()))?;
+            Ok(file)
+        })
+        .await?
+    }
+
+    async fn write_atomic(&self, path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+        let tmp = self.root.join("tmp").join(Uuid::new_v4().to_string());
+
+        let mut file = fs::File::create(&tmp).await.context("Create temp file")?;
+        file.write_all(bytes).await.context("Write temp file")?;
+        file.sync_all().await.context("Sync temp file")?;
+        drop(file);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("Rename to {}", path.display()))
+    }
+
+    /// Writes the object, its reference and the index of `data`, replacing an index there is.
+    async fn write(&self, data: &AudioData) -> anyhow::Result<String> {
+        let digest = hex::encode(Sha256::digest(&data.bytes));
+        let lock = self.lock(&digest).await?;
+        let object_path = self.object_path(&digest);
+        if fs::metadata(&object_path).await.is_err() {
+            self.write_atomic(&object_path, &data.bytes).await?;
+        }
+        self.write_atomic(&self.ref_path(&digest, data.id), &[])
+            .await?;
+        drop(lock);
+
+        let index = format!("{}\n{digest}\n{}\n", data.format, data.original_format);
+        self.write_atomic(&self.index_path(data.id), index.as_bytes())
+            .await?;
+        Ok(digest)
+    }
+
+    /// Removes the reference of `id` to `digest`, and the object with its last reference.
+    async fn release(&self, digest: &str, id: Uuid) -> anyhow::Result<()> {
+        let _lock = self.lock(digest).await?;
+        let ref_path = self.ref_path(digest, id);
+        fs::remove_file(&ref_path).await?;
+
+        let refs = ref_path.parent().expect("ref has a parent");
+        if fs::read_dir(refs).await?.next_entry().await?.is_none() {
+            fs::remove_dir(refs).await?;
+            fs::remove_file(self.object_path(digest)).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the format, the digest and the original format of `id`.
+    async fn read_index(&self, id: Uuid) -> anyhow::Result<(String, String, String)> {
+        let index = fs::read_to_string(self.index_path(id))
+            .await
+            .with_context(|| format!("Read index of {id}"))?;
+        let mut lines = index.lines();
+        let format = lines
+            .next()
+            .ok_or_else(|| anyhow!("Missing format in index of {id}"))?;
+        let digest = lines
+            .next()
+            .ok_or_else(|| anyhow!("Missing digest in index of {id}"))?;
+        let original_format = lines.next().unwrap_or(format);
+        Ok((
+            format.to_owned(),
+            digest.to_owned(),
+            original_format.to_owned(),
+        ))
+    }
+}
+
+#[async_trait]
+impl AudioStorage for DirectoryAudioStorage {
+    async fn insert(&self, data: &AudioData) -> anyhow::Result<()> {
+        let index_path = self.index_path(data.id);
+        if fs::metadata(&index_path).await.is_ok() {
+            bail!("Audio {} already exists", data.id);
+        }
+        self.write(data).await.map(|_| ())
+    }
+
+    /// The index is renamed over the old one. A crash before the old object is released
+    /// leaves it behind, referenced by nothing but its stale reference.
+    async fn replace(&self, data: &AudioData) -> anyhow::Result<()> {
+        let old = self
+            .read_index(data.id)
+            .await
+            .ok()
+            .map(|(_, digest, _)| digest);
+        let digest = self.write(data).await?;
+        match old {
+            Some(old) if old != digest => self.release(&old, data.id).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<AudioData> {
+        let (format, digest, original_format) = self.read_index(id).await?;
+
+        let bytes = fs::read(self.object_path(&digest))
+            .await
+            .with_context(|| format!("Read object {digest}"))?;
+
+        Ok(AudioData::new(id, format, bytes.into()).with_original_format(original_format))
+    }
+
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        let (_, digest, _) = self.read_index(id).await?;
+
+        fs::remove_file(self.index_path(id)).await?;
+        self.release(&digest, id).await
+    }
+
+    async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut ids = Vec::new();
+        let mut dirs = fs::read_dir(self.root.join("ids")).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            let mut files = fs::read_dir(dir.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name();
+                let name = name.to_string_lossy();
+                ids.push(Uuid::try_parse(&name).with_context(|| format!("Index file {name}"))?);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use super::DirectoryAudioStorage;
+    use crate::storage::{AudioData, AudioStorage};
+
+    #[tokio::test]
+    async fn test() {
+        let bytes: bytes::Bytes = b"1234567890".as_ref().into();
+        let data1 = AudioData::new(Uuid::new_v4(), "audio/aac".to_owned(), bytes.clone());
+        let data2 = AudioData::new(Uuid::new_v4(), "audio/ogg".to_owned(), bytes)
+            .with_original_format("audio/mpeg".to_owned());
+
+        let dir = std::env::temp_dir().join(format!("test_audio_dir_{}", Uuid::new_v4()));
+        let storage = DirectoryAudioStorage::new(&dir).unwrap();
+        storage.insert(&data1).await.unwrap();
+        storage.insert(&data2).await.unwrap();
+        assert!(storage.insert(&data1).await.is_err());
+
+        assert_eq!(storage.get(data1.id).await.unwrap(), data1);
+        assert_eq!(storage.get(data2.id).await.unwrap(), data2);
+        assert!(storage.get(Uuid::new_v4()).await.is_err());
+        let ids = storage.ids().await.unwrap();
+        assert!(ids.contains(&data1.id) && ids.contains(&data2.id));
+
+        storage.delete(data1.id).await.unwrap();
+        assert!(storage.get(data1.id).await.is_err());
+        assert_eq!(storage.get(data2.id).await.unwrap(), data2);
+
+        let replaced = AudioData::new(data2.id, "audio/ogg".to_owned(), b"123".as_ref().into());
+        storage.replace(&replaced).await.unwrap();
+        assert_eq!(storage.get(data2.id).await.unwrap(), replaced);
+
+        storage.delete(data2.id).await.unwrap();
+        assert!(storage.get(data2.id).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_object() {
+        let dir = std::env::temp_dir().join(format!("test_audio_dir_{}", Uuid::new_v4()));
+        let storage = Arc::new(DirectoryAudioStorage::new(&dir).unwrap());
+        let bytes: bytes::Bytes = b"1234567890".as_ref().into();
+        let audio = |id| AudioData::new(id, "audio/aac".to_owned(), bytes.clone());
+
+        // Every round deletes the last references to the object while new ones are inserted.
+        let mut stored = vec![Uuid::new_v4()];
+        storage.insert(&audio(stored[0])).await.unwrap();
+        for _ in 0..50 {
+            let inserted: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+            let mut tasks = Vec::new();
+            for id in stored.drain(..) {
+                let storage = storage.clone();
+                tasks.push(tokio::spawn(async move { storage.delete(id).await }));
+            }
+            for &id in &inserted {
+                let storage = storage.clone();
+                let data = audio(id);
+                tasks.push(tokio::spawn(async move { storage.insert(&data).await }));
+            }
+            for task in tasks {
+                task.await.unwrap().unwrap();
+            }
+            for &id in &inserted {
+                assert_eq!(storage.get(id).await.unwrap().bytes(), &bytes);
+            }
+            stored = inserted;
+        }
+
+        for id in stored {
+            storage.delete(id).await.unwrap();
+        }
+        assert!(storage.ids().await.unwrap().is_empty());
+        let digest = hex::encode(Sha256::digest(&bytes));
+        assert!(!storage.object_path(&digest).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
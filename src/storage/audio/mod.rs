@@ -0,0 +1,90 @@
+mod directory;
+mod s3;
+mod sqlite;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::config::AudioStorageConfig;
+use crate::storage::Database;
+
+pub use directory::DirectoryAudioStorage;
+pub use s3::S3AudioStorage;
+pub use sqlite::{open_database, SqliteAudioStorage};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioData {
+    id: Uuid,
+    /// Format of `bytes`.
+    format: String,
+    /// Format the audio was received in, before any transcoding.
+    original_format: String,
+    bytes: Bytes,
+}
+
+impl AudioData {
+    pub fn new(id: Uuid, format: String, bytes: Bytes) -> Self {
+        Self {
+            id,
+            original_format: format.clone(),
+            format,
+            bytes,
+        }
+    }
+
+    pub fn with_original_format(mut self, original_format: String) -> Self {
+        self.original_format = original_format;
+        self
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    pub fn original_format(&self) -> &str {
+        &self.original_format
+    }
+
+    pub fn is_transcoded(&self) -> bool {
+        self.format != self.original_format
+    }
+
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+}
+
+/// Storage for the raw bytes of audio segments, keyed by the segment id.
+#[async_trait]
+pub trait AudioStorage: Send + Sync {
+    async fn insert(&self, data: &AudioData) -> anyhow::Result<()>;
+    /// Stores `data` in place of the audio of its id, or inserts it if there is none. Readers
+    /// see either the old or the new audio, never neither.
+    async fn replace(&self, data: &AudioData) -> anyhow::Result<()>;
+    async fn get(&self, id: Uuid) -> anyhow::Result<AudioData>;
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()>;
+    /// Returns the ids of all stored audio, in no particular order.
+    async fn ids(&self) -> anyhow::Result<Vec<Uuid>>;
+
+    /// Gives up to `pages` freed pages back to the filesystem, if the backend needs it.
+    async fn vacuum(&self, _pages: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens the audio storage backend selected in `config`, in `db` for SQLite without a path.
+pub fn open(config: &AudioStorageConfig, db: &Database) -> anyhow::Result<Box<dyn AudioStorage>> {
+    Ok(match config {
+        AudioStorageConfig::Sqlite { path: None } => Box::new(SqliteAudioStorage::new(db)),
+        AudioStorageConfig::Sqlite { path: Some(path) } => {
+            Box::new(SqliteAudioStorage::new(&open_database(path)?))
+        }
+        AudioStorageConfig::Directory { path } => Box::new(DirectoryAudioStorage::new(path)?),
+        AudioStorageConfig::S3(config) => Box::new(S3AudioStorage::new(config)?),
+    })
+}
@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path;
+use object_store::{Attribute, Attributes, ObjectStore, PutOptions, PutPayload};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use super::{AudioData, AudioStorage};
+use crate::config::S3Config;
+
+const ORIGINAL_FORMAT: Attribute =
+    Attribute::Metadata(std::borrow::Cow::Borrowed("original-format"));
+
+/// Keeps audio bytes as objects in an S3-compatible bucket, one object per id.
+///
+/// The audio format is stored as the object content type and the original format as the
+/// `original-format` user metadata. Credentials are taken from the usual
+/// `AWS_*` environment variables.
+pub struct S3AudioStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl S3AudioStorage {
+    pub fn new(config: &S3Config) -> anyhow::Result<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_allow_http(config.allow_http);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+
+        let store = builder.build().context("Build S3 client")?;
+        Ok(Self::with_store(Arc::new(store), &config.prefix))
+    }
+
+    pub fn with_store(store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            store,
+            prefix: Path::from(prefix),
+        }
+    }
+
+    fn path(&self, id: Uuid) -> Path {
+        self.prefix.child(id.to_string())
+    }
+
+    async fn put(&self, data: &AudioData) -> anyhow::Result<()> {
+        let mut attributes = Attributes::new();
+        attributes.insert(Attribute::ContentType, data.format.clone().into());
+        attributes.insert(ORIGINAL_FORMAT, data.original_format.clone().into());
+
+        self.store
+            .put_opts(
+                &self.path(data.id),
+                PutPayload::from_bytes(data.bytes.clone()),
+                PutOptions {
+                    attributes,
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("S3::put")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AudioStorage for S3AudioStorage {
+    async fn insert(&self, data: &AudioData) -> anyhow::Result<()> {
+        let path = self.path(data.id);
+        match self.store.head(&path).await {
+            Ok(_) => bail!("Audio {} already exists", data.id),
+            Err(object_store::Error::NotFound { .. }) => {}
+            Err(e) => return Err(e).context("S3::head"),
+        }
+        self.put(data).await
+    }
+
+    /// A put replaces the object at once.
+    async fn replace(&self, data: &AudioData) -> anyhow::Result<()> {
+        self.put(data).await
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<AudioData> {
+        let result = self.store.get(&self.path(id)).await.context("S3::get")?;
+        let format = result
+            .attributes
+            .get(&Attribute::ContentType)
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Missing content type of {id}"))?;
+        let original_format = result
+            .attributes
+            .get(&ORIGINAL_FORMAT)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format.clone());
+        let bytes = result.bytes().await.context("S3::get bytes")?;
+
+        Ok(AudioData::new(id, format, bytes).with_original_format(original_format))
+    }
+
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.store
+            .delete(&self.path(id))
+            .await
+            .context("S3::delete")
+    }
+
+    async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut ids = Vec::new();
+        let mut objects = self.store.list(Some(&self.prefix));
+        while let Some(object) = objects.next().await {
+            let object = object.context("S3::list")?;
+            if let Some(name) = object.location.filename() {
+                ids.push(Uuid::try_parse(name).with_context(|| format!("Object {name}"))?);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use object_store::memory::InMemory;
+    use uuid::Uuid;
+
+    use super::S3AudioStorage;
+    use crate::config::S3Config;
+    use crate::storage::{AudioData, AudioStorage};
+
+    async fn roundtrip(storage: &S3AudioStorage) {
+        let data = AudioData::new(
+            Uuid::new_v4(),
+            "audio/ogg".to_owned(),
+            b"1234567890".as_ref().into(),
+        )
+        .with_original_format("audio/aac".to_owned());
+
+        storage.insert(&data).await.unwrap();
+        assert!(storage.insert(&data).await.is_err());
+        assert_eq!(storage.get(data.id).await.unwrap(), data);
+        assert!(storage.get(Uuid::new_v4()).await.is_err());
+        assert!(storage.ids().await.unwrap().contains(&data.id));
+
+        let replaced = AudioData::new(data.id, "audio/ogg".to_owned(), b"123".as_ref().into());
+        storage.replace(&replaced).await.unwrap();
+        assert_eq!(storage.get(data.id).await.unwrap(), replaced);
+
+        storage.delete(data.id).await.unwrap();
+        assert!(storage.get(data.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        roundtrip(&S3AudioStorage::with_store(
+            Arc::new(InMemory::new()),
+            "audio",
+        ))
+        .await;
+    }
+
+    /// Runs against a local MinIO, e.g.
+    /// `docker run -p 9000:9000 minio/minio server /data` with a `feeder` bucket and
+    /// `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` set.
+    #[tokio::test]
+    #[ignore]
+    async fn test_minio() {
+        let config = S3Config {
+            bucket: "feeder".to_owned(),
+            endpoint: Some("http://localhost:9000".to_owned()),
+            region: Some("us-east-1".to_owned()),
+            prefix: "test".to_owned(),
+            allow_http: true,
+        };
+        roundtrip(&S3AudioStorage::new(&config).unwrap()).await;
+    }
+}
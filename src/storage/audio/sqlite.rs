@@ -0,0 +1,210 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use async_trait::async_trait;
+use rusqlite::types::FromSqlError;
+use rusqlite::{params, DatabaseName, Transaction};
+use uuid::Uuid;
+
+use super::{AudioData, AudioStorage};
+use crate::storage::database::Migration;
+use crate::storage::{add_column, incremental_vacuum, Database};
+
+/// Schema migrations of a database of audio only, see [`Database`].
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "Audio table",
+    up: audio_table,
+}];
+
+/// Keeps audio bytes as BLOBs in a SQLite database: by default `audio.sqlite3` of its own,
+/// or the one shared by all stores.
+pub struct SqliteAudioStorage {
+    db: Database,
+}
+
+impl SqliteAudioStorage {
+    pub fn new(db: &Database) -> Self {
+        Self { db: db.clone() }
+    }
+}
+
+/// Opens a database of audio only at `path`, without the tables of the other stores.
+pub fn open_database<P>(path: &P) -> anyhow::Result<Database>
+where
+    P: AsRef<Path>,
+{
+    Database::open_with(path, MIGRATIONS)
+}
+
+/// The audio table as the store created it before migrations, so an `audio.sqlite3` of an
+/// older feeder is adopted in place.
+fn audio_table(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS audio(
+            id STRING PRIMARY KEY,
+            format STRING NOT NULL,
+            bytes BLOB NOT NULL
+        );"#,
+    )?;
+    add_column(tx, "audio", "original_format", "STRING")?;
+    Ok(())
+}
+
+fn write(tx: &Transaction, data: &AudioData) -> anyhow::Result<()> {
+    tx.execute(
+        &format!(
+            "INSERT INTO audio(id, format, original_format, bytes) VALUES(?, ?, ?, ZEROBLOB({}))",
+            data.bytes.len()
+        ),
+        params![data.id.to_string(), data.format, data.original_format],
+    )?;
+
+    tx.blob_open(
+        DatabaseName::Main,
+        "audio",
+        "bytes",
+        tx.last_insert_rowid(),
+        false,
+    )?
+    .write_all(data.bytes.as_ref())
+    .map_err(|_| rusqlite::Error::BlobSizeError)?;
+    Ok(())
+}
+
+#[async_trait]
+impl AudioStorage for SqliteAudioStorage {
+    async fn insert(&self, data: &AudioData) -> anyhow::Result<()> {
+        let data = data.clone();
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                write(&tx, &data)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn replace(&self, data: &AudioData) -> anyhow::Result<()> {
+        let data = data.clone();
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM audio WHERE id=?", [data.id.to_string()])?;
+                write(&tx, &data)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<AudioData> {
+        self.db
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT rowid, format, original_format FROM audio WHERE id=?")?;
+                let data = stmt.query_row([id.to_string()], |row| {
+                    let rowid = row.get(0)?;
+                    let format: String = row.get(1)?;
+                    let original_format: Option<String> = row.get(2)?;
+
+                    let mut blob =
+                        conn.blob_open(DatabaseName::Main, "audio", "bytes", rowid, true)?;
+                    let mut buffer = Vec::new();
+                    blob.read_to_end(&mut buffer)
+                        .map_err(|e| FromSqlError::Other(Box::new(e)))?;
+                    let original_format = original_format.unwrap_or_else(|| format.clone());
+                    Ok(AudioData::new(id, format, buffer.into())
+                        .with_original_format(original_format))
+                })?;
+                Ok(data)
+            })
+            .await
+    }
+
+    async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM audio WHERE id=?", [id.to_string()])?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM audio")?;
+                let rows = stmt.query([])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
+
+    async fn vacuum(&self, pages: u32) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| incremental_vacuum(conn, pages))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{open_database, SqliteAudioStorage};
+    use crate::storage::{AudioData, AudioStorage, Database};
+
+    #[tokio::test]
+    async fn test() {
+        let data = AudioData::new(
+            Uuid::new_v4(),
+            "audio/ogg".to_owned(),
+            b"1234567890".as_ref().into(),
+        )
+        .with_original_format("audio/aac".to_owned());
+
+        let db = SqliteAudioStorage::new(&Database::open(&":memory:").unwrap());
+        db.insert(&data).await.unwrap();
+
+        let result = db.get(data.id).await.unwrap();
+        assert_eq!(result, data);
+        assert!(db.ids().await.unwrap().contains(&data.id));
+
+        let replaced = AudioData::new(data.id, "audio/ogg".to_owned(), b"123".as_ref().into());
+        db.replace(&replaced).await.unwrap();
+        assert_eq!(db.get(data.id).await.unwrap(), replaced);
+
+        db.delete(data.id).await.unwrap();
+        assert!(db.get(data.id).await.is_err());
+        db.vacuum(100).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_own_database() {
+        let db = open_database(&":memory:").unwrap();
+        let tables: Vec<String> = db
+            .call(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%'",
+                )?;
+                let rows = stmt.query([])?;
+                Ok(rows.mapped(|row| row.get(0)).collect::<rusqlite::Result<_>>()?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(tables, ["audio"]);
+
+        let storage = SqliteAudioStorage::new(&db);
+        let data = AudioData::new(
+            Uuid::new_v4(),
+            "audio/aac".to_owned(),
+            b"123".as_ref().into(),
+        );
+        storage.insert(&data).await.unwrap();
+        assert_eq!(storage.get(data.id).await.unwrap(), data);
+    }
+}
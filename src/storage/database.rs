@@ -0,0 +1,771 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+
+use super::{add_column, AUTO_VACUUM};
+
+/// A schema change, applied once in a transaction.
+pub(super) struct Migration {
+    pub(super) description: &'static str,
+    pub(super) up: fn(&Transaction) -> anyhow::Result<()>,
+}
+
+/// Schema migrations in order. `user_version` of a database is the number of them applied.
+///
+/// Applied migrations must never change; schema changes go into a new one at the end.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Tables of all stores",
+        up: baseline,
+    },
+    Migration {
+        description: "Import per-store databases",
+        up: import_legacy,
+    },
+    Migration {
+        description: "Outbox of item registrations",
+        up: outbox,
+    },
+    Migration {
+        description: "Source fields of items",
+        up: segment_source,
+    },
+    Migration {
+        description: "Metadata search",
+        up: metadata_search,
+    },
+    Migration {
+        description: "Match history indexes",
+        up: match_indexes,
+    },
+    Migration {
+        description: "Air times and match sources",
+        up: match_source,
+    },
+    Migration {
+        description: "Progress of legacy audio imports",
+        up: legacy_audio_import,
+    },
+];
+
+/// Attempts at a backup while another process holds a write lock, and the pause between them.
+const BACKUP_ATTEMPTS: u32 = 100;
+const BACKUP_PAUSE: Duration = Duration::from_millis(100);
+
+/// Rows of a legacy audio database copied per transaction.
+const LEGACY_AUDIO_BATCH: i64 = 100;
+
+/// Schemas the per-store databases are attached as while migrating, with their tables.
+const LEGACY_SCHEMAS: [(&str, &[&str]); 3] = [
+    ("legacy_metadata", &["metadata"]),
+    ("legacy_matches", &["matches", "airplays"]),
+    ("legacy_queue", &["queue"]),
+];
+
+/// Databases of the stores from before they shared one, imported by the migration that
+/// introduced it.
+///
+/// Audio is copied after the migrations instead, in batches of their own transactions, so a
+/// large store never ends up in one transaction. An interrupted copy resumes on the next open.
+#[derive(Debug, Default)]
+pub struct LegacyFiles {
+    pub metadata: Option<PathBuf>,
+    pub matches: Option<PathBuf>,
+    pub queue: Option<PathBuf>,
+    pub audio: Option<PathBuf>,
+}
+
+impl LegacyFiles {
+    /// The per-store databases at their old fixed paths, those that exist. `audio.sqlite3` is
+    /// only imported into a database that keeps the audio too; otherwise it stays the audio
+    /// database.
+    pub fn find(audio: bool) -> Self {
+        let existing = |path: &str| Some(PathBuf::from(path)).filter(|path| path.exists());
+        Self {
+            metadata: existing("./metadata.sqlite3"),
+            matches: existing("./matches.sqlite3"),
+            queue: existing("./queue.sqlite3"),
+            audio: existing("./audio.sqlite3").filter(|_| audio),
+        }
+    }
+
+    fn schemas(&self) -> impl Iterator<Item = (&str, &Path)> {
+        [&self.metadata, &self.matches, &self.queue]
+            .into_iter()
+            .zip(LEGACY_SCHEMAS)
+            .filter_map(|(path, (schema, _))| Some((schema, path.as_deref()?)))
+    }
+}
+
+/// The SQLite database shared by the stores, or of a store kept apart, migrated to the current
+/// schema when opened.
+///
+/// It is `Send` and `Sync` and cheap to clone. Statements run one at a time on a single
+/// connection, on tokio's blocking thread pool, so they never block the async runtime.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    #[cfg(test)]
+    pub fn open<P>(path: &P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_importing(path, &LegacyFiles::default())
+    }
+
+    /// Opens the database, importing `legacy` databases if it predates the shared database.
+    pub fn open_importing<P>(path: &P, legacy: &LegacyFiles) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_migrating(path, MIGRATIONS, legacy)
+    }
+
+    /// Opens a database with a schema of its own, given by `migrations`.
+    pub(super) fn open_with<P>(path: &P, migrations: &[Migration]) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_migrating(path, migrations, &LegacyFiles::default())
+    }
+
+    fn open_migrating<P>(
+        path: &P,
+        migrations: &[Migration],
+        legacy: &LegacyFiles,
+    ) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
+        )
+        .with_context(|| format!("Open database {}", path.as_ref().display()))?;
+
+        conn.execute_batch(AUTO_VACUUM)?;
+        migrate(&mut conn, migrations, legacy)
+            .with_context(|| format!("Migrate database {}", path.as_ref().display()))?;
+        if let Some(audio) = &legacy.audio {
+            import_legacy_audio(&mut conn, audio)
+                .with_context(|| format!("Import audio from {}", audio.display()))?;
+        }
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Copies the database to a new file at `path` with SQLite's online backup API.
+    ///
+    /// All pages are copied in one step under a read lock, so the copy is consistent. Writes of
+    /// other processes wait for it rather than restart it, and this process is blocked anyway
+    /// as the step holds the connection.
+    pub async fn backup(&self, path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+        let dest_path = path.to_owned();
+        let result = self
+            .call(move |conn| {
+                let mut dest = Connection::open(&dest_path)?;
+                let backup = Backup::new(conn, &mut dest)?;
+                for _ in 0..BACKUP_ATTEMPTS {
+                    match backup.step(-1)? {
+                        StepResult::Done => return Ok(()),
+                        StepResult::Busy | StepResult::Locked => std::thread::sleep(BACKUP_PAUSE),
+                        result => bail!("Unexpected backup step result {result:?}"),
+                    }
+                }
+                bail!("Database stayed locked")
+            })
+            .await;
+        if result.is_err() {
+            // Never leave a partial copy that looks like a backup.
+            let _ = std::fs::remove_file(path);
+        }
+        result.with_context(|| format!("Back up to {}", path.display()))
+    }
+
+    /// Rebuilds the database with a full `VACUUM`, which gives all free pages back to the
+    /// filesystem and turns on incremental auto-vacuum for databases created without it.
+    ///
+    /// This rewrites the whole file and needs as much free disk space again. Writes wait
+    /// until it is done, for minutes on a large database.
+    pub async fn vacuum(&self) -> anyhow::Result<()> {
+        self.call(|conn| {
+            conn.execute_batch(&format!("{AUTO_VACUUM} VACUUM;"))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Runs `PRAGMA integrity_check`. Returns the problems found, none if the database is
+    /// intact.
+    pub async fn integrity_check(&self) -> anyhow::Result<Vec<String>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+            let rows = stmt.query([])?;
+            let messages: Vec<String> = rows
+                .mapped(|row| row.get(0))
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(messages.into_iter().filter(|m| m != "ok").collect())
+        })
+        .await
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool.
+    pub(super) async fn call<F, T>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| anyhow!("Database connection poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn version(conn: &Connection) -> anyhow::Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// Applies pending `migrations`. Fails on a schema newer than this build knows.
+fn migrate(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    legacy: &LegacyFiles,
+) -> anyhow::Result<()> {
+    let current = version(conn)?;
+    if current > migrations.len() {
+        bail!(
+            "Schema version {current} is newer than {}, the latest this build supports",
+            migrations.len()
+        );
+    }
+    if current == migrations.len() {
+        return Ok(());
+    }
+
+    // Databases cannot be attached inside a transaction.
+    let attached: Vec<&str> = legacy.schemas().map(|(schema, _)| schema).collect();
+    for (schema, path) in legacy.schemas() {
+        conn.execute(
+            &format!("ATTACH DATABASE ? AS {schema}"),
+            [path.to_string_lossy()],
+        )
+        .with_context(|| format!("Attach {}", path.display()))?;
+    }
+
+    let result = (|| {
+        for (index, migration) in migrations.iter().enumerate().skip(current) {
+            let version = index + 1;
+            log::info!("Migrate to schema {version}: {}", migration.description);
+            let tx = conn.transaction()?;
+            (migration.up)(&tx).with_context(|| format!("Migration {version}"))?;
+            tx.pragma_update(None, "user_version", version as i64)?;
+            tx.commit()?;
+        }
+        anyhow::Ok(())
+    })();
+
+    for schema in attached {
+        conn.execute_batch(&format!("DETACH DATABASE {schema}"))?;
+    }
+    result
+}
+
+/// The tables as the stores created them before migrations. Tables and columns that already
+/// exist are kept, so per-store databases are adopted in place.
+fn baseline(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS metadata(
+            id STRING PRIMARY KEY,
+            date DATETIME NOT NULL,
+            kind STRING NOT NULL,
+            artist STRING NOT NULL,
+            title STRING NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS matches(
+            id STRING NOT NULL,
+            timestamp DATETIME NOT NULL,
+            score INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS airplays(
+            id INTEGER PRIMARY KEY,
+            station STRING NOT NULL,
+            track_id STRING NOT NULL,
+            start DATETIME NOT NULL,
+            end DATETIME NOT NULL,
+            segments INTEGER NOT NULL,
+            score_sum INTEGER NOT NULL,
+            max_score INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS airplays_track ON airplays(station, track_id, end);
+        CREATE INDEX IF NOT EXISTS airplays_start ON airplays(start);
+        CREATE TABLE IF NOT EXISTS queue(
+            id STRING PRIMARY KEY,
+            created DATETIME NOT NULL,
+            attempts INTEGER NOT NULL,
+            next_attempt DATETIME NOT NULL,
+            filename STRING NOT NULL,
+            format STRING NOT NULL,
+            kind STRING NOT NULL,
+            artist STRING NOT NULL,
+            title STRING NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS audio(
+            id STRING PRIMARY KEY,
+            format STRING NOT NULL,
+            bytes BLOB NOT NULL
+        );"#,
+    )?;
+    for column in ["query_start", "query_end", "track_start", "track_end"] {
+        add_column(tx, "matches", column, "REAL")?;
+    }
+    add_column(tx, "audio", "original_format", "STRING")?;
+    Ok(())
+}
+
+/// Copies the rows of the attached per-store databases. Columns the old files lack are left
+/// at their defaults.
+fn import_legacy(tx: &Transaction) -> anyhow::Result<()> {
+    for (schema, tables) in LEGACY_SCHEMAS {
+        let attached = tx
+            .prepare("SELECT 1 FROM pragma_database_list WHERE name=?")?
+            .exists([schema])?;
+        if !attached {
+            continue;
+        }
+
+        for table in tables {
+            let legacy = columns(tx, schema, table)?;
+            let columns = columns(tx, "main", table)?
+                .into_iter()
+                .filter(|column| legacy.contains(column))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if columns.is_empty() {
+                continue;
+            }
+            let rows = tx.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO main.{table}({columns})
+                    SELECT {columns} FROM {schema}.{table}"
+                ),
+                [],
+            )?;
+            log::info!("Imported {rows} rows of {table} from {schema}");
+        }
+    }
+    Ok(())
+}
+
+/// Copies the rows of a per-store audio database that are not copied yet, in batches.
+fn import_legacy_audio(conn: &mut Connection, path: &Path) -> anyhow::Result<()> {
+    conn.execute(
+        "ATTACH DATABASE ? AS legacy_audio",
+        [path.to_string_lossy()],
+    )?;
+    let result = copy_legacy_audio(conn, &path.to_string_lossy());
+    conn.execute_batch("DETACH DATABASE legacy_audio")?;
+    result
+}
+
+fn copy_legacy_audio(conn: &mut Connection, path: &str) -> anyhow::Result<()> {
+    let legacy = columns(conn, "legacy_audio", "audio")?;
+    let columns = columns(conn, "main", "audio")?
+        .into_iter()
+        .filter(|column| legacy.contains(column))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if columns.is_empty() {
+        return Ok(());
+    }
+
+    let mut imported = 0;
+    loop {
+        let tx = conn.transaction()?;
+        let last: i64 = tx
+            .query_row(
+                "SELECT last_rowid FROM legacy_audio_import WHERE path=?",
+                [path],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+        let end: Option<i64> = tx.query_row(
+            "SELECT MAX(rowid) FROM (
+                SELECT rowid FROM legacy_audio.audio WHERE rowid>? ORDER BY rowid LIMIT ?)",
+            params![last, LEGACY_AUDIO_BATCH],
+            |row| row.get(0),
+        )?;
+        let end = match end {
+            Some(end) => end,
+            None => break,
+        };
+
+        imported += tx.execute(
+            &format!(
+                "INSERT OR IGNORE INTO main.audio({columns})
+                SELECT {columns} FROM legacy_audio.audio WHERE rowid>? AND rowid<=?"
+            ),
+            params![last, end],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO legacy_audio_import(path, last_rowid) VALUES(?, ?)",
+            params![path, end],
+        )?;
+        tx.commit()?;
+    }
+    if imported > 0 {
+        log::info!("Imported {imported} rows of audio from {path}");
+    }
+    Ok(())
+}
+
+/// Registrations of new items, until their track is in the fingerprint backend.
+fn outbox(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE outbox(
+            id STRING PRIMARY KEY,
+            created DATETIME NOT NULL,
+            state STRING NOT NULL,
+            queued INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt DATETIME NOT NULL
+        ) WITHOUT ROWID;"#,
+    )?;
+    Ok(())
+}
+
+/// Stream and catalogue fields of items, kept as received. Queued segments keep theirs as JSON
+/// until registered.
+fn segment_source(tx: &Transaction) -> anyhow::Result<()> {
+    for (column, definition) in [
+        ("station", "STRING"),
+        ("segment_uri", "STRING"),
+        ("media_sequence", "INTEGER"),
+        ("extinf_duration_ms", "INTEGER"),
+        ("extinf_title", "STRING"),
+        ("song_spot", "STRING"),
+        ("media_base_id", "INTEGER"),
+        ("itunes_track_id", "INTEGER"),
+        ("amg_track_id", "INTEGER"),
+        ("amg_artist_id", "INTEGER"),
+        ("ta_id", "INTEGER"),
+        ("tp_id", "INTEGER"),
+        ("cartcut_id", "INTEGER"),
+        ("artwork_url", "STRING"),
+        ("length_seconds", "INTEGER"),
+        ("uns_id", "INTEGER"),
+        ("spot_instance_id", "STRING"),
+    ] {
+        add_column(tx, "metadata", column, definition)?;
+    }
+    add_column(tx, "queue", "source", "STRING")?;
+    Ok(())
+}
+
+/// Indexes of item searches, and a full-text index of artists and titles kept up to date by
+/// triggers. `metadata` has no rowid, so the rowids of the index are mapped to item ids.
+fn metadata_search(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE INDEX metadata_date ON metadata(date);
+        CREATE INDEX metadata_kind ON metadata(kind, date);
+        CREATE INDEX metadata_station ON metadata(station, date);
+
+        CREATE VIRTUAL TABLE metadata_fts USING fts5(id UNINDEXED, artist, title);
+        CREATE TABLE metadata_fts_rowids(
+            id STRING PRIMARY KEY,
+            fts_rowid INTEGER NOT NULL
+        ) WITHOUT ROWID;
+        INSERT INTO metadata_fts(id, artist, title) SELECT id, artist, title FROM metadata;
+        INSERT INTO metadata_fts_rowids SELECT id, rowid FROM metadata_fts;
+
+        CREATE TRIGGER metadata_fts_insert AFTER INSERT ON metadata BEGIN
+            INSERT INTO metadata_fts(id, artist, title) VALUES(new.id, new.artist, new.title);
+            INSERT INTO metadata_fts_rowids VALUES(new.id, last_insert_rowid());
+        END;
+        CREATE TRIGGER metadata_fts_update AFTER UPDATE OF artist, title ON metadata BEGIN
+            UPDATE metadata_fts SET artist=new.artist, title=new.title
+            WHERE rowid=(SELECT fts_rowid FROM metadata_fts_rowids WHERE id=old.id);
+        END;
+        CREATE TRIGGER metadata_fts_delete AFTER DELETE ON metadata BEGIN
+            DELETE FROM metadata_fts
+            WHERE rowid=(SELECT fts_rowid FROM metadata_fts_rowids WHERE id=old.id);
+            DELETE FROM metadata_fts_rowids WHERE id=old.id;
+        END;"#,
+    )?;
+    Ok(())
+}
+
+/// Indexes of match history queries and of pruning by time.
+fn match_indexes(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE INDEX matches_id ON matches(id, timestamp);
+        CREATE INDEX matches_timestamp ON matches(timestamp);"#,
+    )?;
+    Ok(())
+}
+
+/// Air times of items, and the segments matches were found in with their rejected results.
+fn match_source(tx: &Transaction) -> anyhow::Result<()> {
+    add_column(tx, "metadata", "aired", "STRING")?;
+    for (column, definition) in [
+        ("station", "STRING"),
+        ("media_sequence", "INTEGER"),
+        ("aired", "STRING"),
+        ("query_id", "STRING"),
+        ("candidates", "STRING"),
+    ] {
+        add_column(tx, "matches", column, definition)?;
+    }
+    Ok(())
+}
+
+/// The last row copied from each legacy audio database.
+fn legacy_audio_import(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE legacy_audio_import(
+            path STRING PRIMARY KEY,
+            last_rowid INTEGER NOT NULL
+        ) WITHOUT ROWID;"#,
+    )?;
+    Ok(())
+}
+
+fn columns(conn: &Connection, schema: &str, table: &str) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?, ?)")?;
+    let rows = stmt.query([table, schema])?;
+    rows.mapped(|row| row.get(0))
+        .map(|column| column.map_err(|e| e.into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use rusqlite::Connection;
+    use uuid::Uuid;
+
+    use super::{migrate, version, Database, LegacyFiles, LEGACY_AUDIO_BATCH, MIGRATIONS};
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::incremental_vacuum;
+    use crate::storage::{AudioKind, AudioStorage, MatchesStorage, Metadata, MetadataStorage};
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let db = Database::open(&":memory:").unwrap();
+        assert_eq!(
+            db.call(|conn| version(conn)).await.unwrap(),
+            MIGRATIONS.len()
+        );
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, MIGRATIONS, &LegacyFiles::default()).unwrap();
+        migrate(&mut conn, MIGRATIONS, &LegacyFiles::default()).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+            .unwrap();
+        assert!(migrate(&mut conn, MIGRATIONS, &LegacyFiles::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared() {
+        let db = Database::open(&":memory:").unwrap();
+        let storage = Arc::new(MetadataStorage::new(&db));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let item = Metadata::new(
+                        Uuid::new_v4(),
+                        Utc::now(),
+                        AudioKind::Music,
+                        format!("Artist {i}"),
+                        "Title".to_owned(),
+                    );
+                    storage.insert(&item).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(storage.ids().await.unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_backup() {
+        let dir = std::env::temp_dir().join(format!("test_backup_{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = &dir.join("backup.sqlite3");
+
+        let db = Database::open(&":memory:").unwrap();
+        let item = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            AudioKind::Music,
+            "Artist".to_owned(),
+            "Title".to_owned(),
+        );
+        MetadataStorage::new(&db).insert(&item).await.unwrap();
+        assert!(db.integrity_check().await.unwrap().is_empty());
+
+        db.backup(path).await.unwrap();
+        // Never overwritten.
+        assert!(db.backup(path).await.is_err());
+
+        let backup = Database::open(&path).unwrap();
+        assert!(backup.integrity_check().await.unwrap().is_empty());
+        assert_eq!(
+            MetadataStorage::new(&backup).get(item.id).await.unwrap(),
+            item
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vacuum() {
+        let dir = std::env::temp_dir().join(format!("test_vacuum_{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("feeder.sqlite3");
+        // A database from before incremental auto-vacuum.
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE t(x);")
+            .unwrap();
+
+        let db = Database::open(&path).unwrap();
+        let mode = || {
+            db.call(
+                |conn| Ok(conn.query_row("PRAGMA auto_vacuum", [], |row| row.get::<_, i64>(0))?),
+            )
+        };
+        db.call(|conn| incremental_vacuum(conn, 100)).await.unwrap();
+        assert_eq!(mode().await.unwrap(), 0);
+
+        db.vacuum().await.unwrap();
+        assert_eq!(mode().await.unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let dir = std::env::temp_dir().join(format!("test_import_{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let metadata_path = dir.join("metadata.sqlite3");
+        let matches_path = dir.join("matches.sqlite3");
+        let audio_path = dir.join("audio.sqlite3");
+
+        let id = Uuid::new_v4();
+        let legacy = Connection::open(&metadata_path).unwrap();
+        legacy
+            .execute_batch(&format!(
+                "CREATE TABLE metadata(id STRING PRIMARY KEY, date DATETIME NOT NULL,
+                    kind STRING NOT NULL, artist STRING NOT NULL, title STRING NOT NULL)
+                    WITHOUT ROWID;
+                INSERT INTO metadata VALUES('{id}', '2022-06-01T10:00:00Z', 'music', 'A', 'T');"
+            ))
+            .unwrap();
+        // Matches from before match offsets were kept.
+        let legacy = Connection::open(&matches_path).unwrap();
+        legacy
+            .execute_batch(&format!(
+                "CREATE TABLE matches(id STRING NOT NULL, timestamp DATETIME NOT NULL,
+                    score INTEGER NOT NULL);
+                INSERT INTO matches VALUES('{id}', '2022-06-01T10:05:00Z', 90);"
+            ))
+            .unwrap();
+        // Audio from before original formats were kept, more than one batch of it.
+        let legacy_audio = Connection::open(&audio_path).unwrap();
+        legacy_audio
+            .execute_batch(
+                "CREATE TABLE audio(id STRING PRIMARY KEY, format STRING NOT NULL,
+                    bytes BLOB NOT NULL);",
+            )
+            .unwrap();
+        let insert_audio = |id: Uuid| {
+            legacy_audio
+                .execute(
+                    "INSERT INTO audio VALUES(?, 'audio/aac', x'010203')",
+                    [id.to_string()],
+                )
+                .unwrap();
+        };
+        insert_audio(id);
+        for _ in 0..LEGACY_AUDIO_BATCH * 2 {
+            insert_audio(Uuid::new_v4());
+        }
+
+        let legacy_files = LegacyFiles {
+            metadata: Some(metadata_path),
+            matches: Some(matches_path),
+            audio: Some(audio_path),
+            ..Default::default()
+        };
+        let db_path = dir.join("feeder.sqlite3");
+        let db = Database::open_importing(&db_path, &legacy_files).unwrap();
+
+        let metadata_storage = MetadataStorage::new(&db);
+        assert_eq!(metadata_storage.get(id).await.unwrap().artist(), "A");
+        let matches = MatchesStorage::new(&db).get(id).await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].offsets().is_none());
+        let audio_storage = SqliteAudioStorage::new(&db);
+        let audio = audio_storage.get(id).await.unwrap();
+        assert_eq!(audio.bytes().as_ref(), [1, 2, 3]);
+        assert_eq!(audio.original_format(), "audio/aac");
+        assert_eq!(
+            audio_storage.ids().await.unwrap().len() as i64,
+            LEGACY_AUDIO_BATCH * 2 + 1
+        );
+        let attached: i64 = db
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(*) FROM pragma_database_list", [], |row| {
+                        row.get(0)
+                    })?,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(attached, 1);
+        drop(db);
+
+        // Later opens copy only audio added since.
+        let added = Uuid::new_v4();
+        insert_audio(added);
+        audio_storage.delete(id).await.unwrap();
+        let db = Database::open_importing(&db_path, &legacy_files).unwrap();
+        let audio_storage = SqliteAudioStorage::new(&db);
+        assert!(audio_storage.get(added).await.is_ok());
+        assert!(audio_storage.get(id).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
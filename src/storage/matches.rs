@@ -1,18 +1,67 @@
 #![allow(dead_code)]
 
-use std::cell::RefCell;
-use std::path::Path;
-
 use anyhow::Context;
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection, OpenFlags};
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, OptionalExtension, Row, ToSql};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+use super::{incremental_vacuum, AudioKind, Database};
+
+/// Condition of plays matching an [`AirplayFilter`], with its fields bound to `?1`..`?5`.
+const AIRPLAY_CONDITION: &str = "(?1 IS NULL OR station=?1) AND (?2 IS NULL OR track_id=?2)
+    AND (?3 IS NULL OR end>=?3) AND (?4 IS NULL OR start<?4)
+    AND (?5 IS NULL OR track_id IN (SELECT id FROM metadata WHERE kind=?5))";
+
+/// Condition of matches matching an [`AirplayFilter`], with its fields bound to `?1`..`?5`.
+const MATCH_CONDITION: &str = "(?1 IS NULL OR station=?1) AND (?2 IS NULL OR id=?2)
+    AND (?3 IS NULL OR timestamp>=?3) AND (?4 IS NULL OR timestamp<?4)
+    AND (?5 IS NULL OR id IN (SELECT id FROM metadata WHERE kind=?5))";
+
+/// Columns of a match, in the order [`MatchData::from_row`] reads them.
+const COLUMNS: &str = "timestamp, score, query_start, query_end, track_start, track_end, station,
+    media_sequence, aired, query_id, candidates";
+
+/// Where a match lies in the query segment and in the matched track, in seconds from their
+/// starts.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct MatchOffsets {
+    pub query_start: f32,
+    pub query_end: f32,
+    pub track_start: f32,
+    pub track_end: f32,
+}
+
+/// A fingerprint result of a segment that was not accepted as a match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub id: Uuid,
+    pub score: u8,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+}
+
+/// The segment a match was found in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MatchSource {
+    pub station: Option<String>,
+    pub media_sequence: Option<u64>,
+    /// When the segment started airing, if the stream tells.
+    pub aired: Option<DateTime<Utc>>,
+    /// Id the segment audio is kept under in the audio storage, if it is.
+    pub query_id: Option<Uuid>,
+    /// The other results of the segment.
+    pub rejected: Vec<Candidate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MatchData {
     id: Uuid,
     timestamp: DateTime<Utc>,
     score: u8,
+    offsets: Option<MatchOffsets>,
+    source: MatchSource,
 }
 
 impl MatchData {
@@ -21,80 +70,911 @@ impl MatchData {
             id,
             timestamp,
             score,
+            offsets: None,
+            source: MatchSource::default(),
         }
     }
+
+    pub fn with_source(mut self, source: MatchSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn source(&self) -> &MatchSource {
+        &self.source
+    }
+
+    pub fn with_offsets(mut self, offsets: Option<MatchOffsets>) -> Self {
+        self.offsets = offsets;
+        self
+    }
+
+    pub fn offsets(&self) -> Option<MatchOffsets> {
+        self.offsets
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn score(&self) -> u8 {
+        self.score
+    }
+
+    /// Reads [`COLUMNS`] of `id`.
+    fn from_row(id: Uuid, row: &Row) -> rusqlite::Result<Self> {
+        let offsets = match (row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?) {
+            (Some(query_start), Some(query_end), Some(track_start), Some(track_end)) => {
+                Some(MatchOffsets {
+                    query_start,
+                    query_end,
+                    track_start,
+                    track_end,
+                })
+            }
+            _ => None,
+        };
+        let source = MatchSource {
+            station: row.get(6)?,
+            media_sequence: row.get(7)?,
+            aired: row.get(8)?,
+            query_id: row
+                .get::<_, Option<String>>(9)?
+                .map(|id| {
+                    Uuid::try_parse(&id).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(9, Type::Text, e.into())
+                    })
+                })
+                .transpose()?,
+            rejected: row
+                .get::<_, Option<String>>(10)?
+                .map(|candidates| serde_json::from_str(&candidates))
+                .transpose()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(10, Type::Text, e.into()))?
+                .unwrap_or_default(),
+        };
+        Ok(MatchData::new(id, row.get(0)?, row.get(1)?)
+            .with_offsets(offsets)
+            .with_source(source))
+    }
 }
 
-pub struct MatchesStorage {
-    conn: RefCell<Connection>,
+/// Consecutive matches of one track on one station, merged into a single play.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Airplay {
+    pub id: i64,
+    pub station: String,
+    pub track_id: Uuid,
+    /// Time of the first matched segment.
+    pub start: DateTime<Utc>,
+    /// Time of the last matched segment.
+    pub end: DateTime<Utc>,
+    pub segments: u32,
+    score_sum: u32,
+    pub max_score: u8,
 }
 
-impl MatchesStorage {
-    pub fn new<P>(path: &P) -> anyhow::Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let conn = Connection::open_with_flags(
-            path,
-            OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
-        )?;
-
-        conn.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS matches(
-                id STRING NOT NULL,
-                timestamp DATETIME NOT NULL,
-                score INTEGER NOT NULL
-            )"#,
-        )?;
+impl Airplay {
+    pub fn duration(&self) -> chrono::Duration {
+        self.end - self.start
+    }
 
+    pub fn mean_score(&self) -> f32 {
+        self.score_sum as f32 / self.segments.max(1) as f32
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let track_id: String = row.get(2)?;
         Ok(Self {
-            conn: RefCell::new(conn),
+            id: row.get(0)?,
+            station: row.get(1)?,
+            track_id: Uuid::try_parse(&track_id)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?,
+            start: row.get(3)?,
+            end: row.get(4)?,
+            segments: row.get(5)?,
+            score_sum: row.get(6)?,
+            max_score: row.get(7)?,
         })
     }
+}
 
-    pub fn insert(&self, data: &MatchData) -> anyhow::Result<()> {
-        let conn = self.conn.borrow_mut();
-        conn.prepare_cached("INSERT INTO matches VALUES(?, ?, ?)")
-            .context("Prepare statement")?
-            .execute(params![data.id.to_string(), data.timestamp, data.score])
-            .context("Execute statement")?;
-        Ok(())
-    }
-
-    pub fn get(&self, id: Uuid) -> anyhow::Result<Vec<MatchData>> {
-        let conn = self.conn.borrow();
-        let mut stmt = conn
-            .prepare("SELECT timestamp, score FROM matches WHERE id=? ORDER BY timestamp DESC")?;
-        let rows = stmt.query([id.to_string()])?;
-        rows.mapped(|row| {
-            let timestamp: DateTime<Utc> = row.get(0)?;
-            let score: u8 = row.get(1)?;
-            Ok(MatchData::new(id, timestamp, score))
-        })
-        .map(|m| m.map_err(|e| e.into()))
-        .collect()
+/// Filter of [`MatchesStorage::airplays`], play statistics and match exports. `None` fields
+/// match everything. The time range of matches is that of their timestamps.
+#[derive(Debug, Default, Clone)]
+pub struct AirplayFilter {
+    pub station: Option<String>,
+    pub track_id: Option<Uuid>,
+    /// Plays that end at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Plays that start before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Plays of items of this kind.
+    pub kind: Option<AudioKind>,
+}
+
+impl AirplayFilter {
+    /// Parameters of [`AIRPLAY_CONDITION`].
+    fn params(&self) -> Vec<Box<dyn ToSql>> {
+        vec![
+            Box::new(self.station.clone()),
+            Box::new(self.track_id.map(|id| id.to_string())),
+            Box::new(self.since),
+            Box::new(self.until),
+            Box::new(self.kind),
+        ]
+    }
+}
+
+/// Plays on a station.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StationStats {
+    pub station: String,
+    pub plays: u32,
+    /// Matched segments of the plays.
+    pub segments: u32,
+    /// Distinct tracks played.
+    pub tracks: u32,
+}
+
+/// Plays of a track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrackStats {
+    pub track_id: Uuid,
+    pub plays: u32,
+    /// Matched segments of the plays.
+    pub segments: u32,
+}
+
+/// When an id was matched first and last, and how often.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Seen {
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub matches: u32,
+}
+
+/// Period that plays are counted by.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    /// Format that truncates a time to the start of its bucket with SQLite's `strftime`.
+    fn format(&self) -> &'static str {
+        match self {
+            Bucket::Hour => "%Y-%m-%d %H:00:00",
+            Bucket::Day => "%Y-%m-%d 00:00:00",
+        }
+    }
+}
+
+impl TryFrom<&str> for Bucket {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            _ => Err(anyhow::anyhow!("Invalid bucket value={value}")),
+        }
+    }
+}
+
+/// Plays started within a bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlayCount {
+    pub start: DateTime<Utc>,
+    pub plays: u32,
+}
+
+pub struct MatchesStorage {
+    db: Database,
+}
+
+impl MatchesStorage {
+    pub fn new(db: &Database) -> Self {
+        Self { db: db.clone() }
+    }
+
+    pub async fn insert(&self, data: &MatchData) -> anyhow::Result<()> {
+        let data = data.clone();
+        // No results rejected is the common case, stored as NULL.
+        let candidates = if data.source.rejected.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&data.source.rejected)?)
+        };
+        self.db
+            .call(move |conn| {
+                let offsets = data.offsets;
+                let source = &data.source;
+                conn.prepare_cached(&format!(
+                    "INSERT INTO matches(id, {COLUMNS}) VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                ))
+                .context("Prepare statement")?
+                .execute(params![
+                    data.id.to_string(),
+                    data.timestamp,
+                    data.score,
+                    offsets.map(|o| o.query_start),
+                    offsets.map(|o| o.query_end),
+                    offsets.map(|o| o.track_start),
+                    offsets.map(|o| o.track_end),
+                    source.station,
+                    source.media_sequence,
+                    source.aired,
+                    source.query_id.map(|id| id.to_string()),
+                    candidates
+                ])
+                .context("Execute statement")?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn get(&self, id: Uuid) -> anyhow::Result<Vec<MatchData>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS} FROM matches WHERE id=? ORDER BY timestamp DESC"
+                ))?;
+                let rows = stmt.query([id.to_string()])?;
+                rows.mapped(|row| MatchData::from_row(id, row))
+                    .map(|m| m.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns matches recorded within `since..until`, of `id` if given, oldest first. Open ends
+    /// are unbounded.
+    pub async fn between(
+        &self,
+        id: Option<Uuid>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Vec<MatchData>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS}, id FROM matches
+                    WHERE (?1 IS NULL OR id=?1) AND (?2 IS NULL OR timestamp>=?2)
+                        AND (?3 IS NULL OR timestamp<?3)
+                    ORDER BY timestamp"
+                ))?;
+                let rows = stmt.query(params![id.map(|id| id.to_string()), since, until])?;
+                rows.mapped(|row| {
+                    let id: String = row.get(11)?;
+                    let id = Uuid::try_parse(&id).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(11, Type::Text, e.into())
+                    })?;
+                    MatchData::from_row(id, row)
+                })
+                .map(|m| m.map_err(|e| e.into()))
+                .collect()
+            })
+            .await
+    }
+
+    /// Returns up to `limit` matches matching `filter` that come after `after`, a timestamp and
+    /// rowid, in order of timestamp and rowid, to read all of them page by page. Matches come
+    /// with their rowids.
+    pub async fn matches_after(
+        &self,
+        filter: &AirplayFilter,
+        after: Option<(DateTime<Utc>, i64)>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i64, MatchData)>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let mut params = filter.params();
+                // Spelled out rather than `?6 IS NULL OR ...` so the range uses the index.
+                let after = match after {
+                    Some((timestamp, rowid)) => {
+                        params.push(Box::new(timestamp));
+                        params.push(Box::new(rowid));
+                        "AND (timestamp, rowid) > (?6, ?7)"
+                    }
+                    None => "",
+                };
+                params.push(Box::new(limit));
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS}, id, rowid FROM matches WHERE {MATCH_CONDITION} {after}
+                    ORDER BY timestamp, rowid LIMIT ?{}",
+                    params.len()
+                ))?;
+                let rows = stmt.query(params_from_iter(params))?;
+                rows.mapped(|row| {
+                    let id: String = row.get(11)?;
+                    let id = Uuid::try_parse(&id).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(11, Type::Text, e.into())
+                    })?;
+                    Ok((row.get(12)?, MatchData::from_row(id, row)?))
+                })
+                .map(|m| m.map_err(|e| e.into()))
+                .collect()
+            })
+            .await
+    }
+
+    /// Returns when `id` was matched first and last, or `None` if never.
+    pub async fn seen(&self, id: Uuid) -> anyhow::Result<Option<Seen>> {
+        self.db
+            .call(move |conn| {
+                let seen = conn.query_row(
+                    "SELECT MIN(timestamp), MAX(timestamp), COUNT(*) FROM matches WHERE id=?",
+                    [id.to_string()],
+                    |row| {
+                        Ok(match (row.get(0)?, row.get(1)?) {
+                            (Some(first), Some(last)) => Some(Seen {
+                                first,
+                                last,
+                                matches: row.get(2)?,
+                            }),
+                            _ => None,
+                        })
+                    },
+                )?;
+                Ok(seen)
+            })
+            .await
+    }
+
+    /// Returns the ids segment audio is kept under, of matches recorded before `timestamp` if
+    /// given.
+    pub async fn query_ids(&self, before: Option<DateTime<Utc>>) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT DISTINCT query_id FROM matches
+                    WHERE query_id IS NOT NULL AND (?1 IS NULL OR timestamp<?1)",
+                )?;
+                let rows = stmt.query([before])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns ids with matches or plays but no item, with their numbers of matches and plays.
+    pub async fn orphans(&self) -> anyhow::Result<Vec<(Uuid, u32, u32)>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, SUM(matches), SUM(plays) FROM (
+                        SELECT id, 1 AS matches, 0 AS plays FROM matches
+                        UNION ALL SELECT track_id, 0, 1 FROM airplays
+                    )
+                    WHERE id NOT IN (SELECT id FROM metadata)
+                    GROUP BY id ORDER BY id",
+                )?;
+                let rows = stmt.query([])?;
+                rows.mapped(|row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)))
+                    .map(|orphan| {
+                        let (id, matches, plays) = orphan?;
+                        Ok((Uuid::try_parse(&id)?, matches, plays))
+                    })
+                    .collect()
+            })
+            .await
+    }
+
+    /// Deletes all matches and plays of `id`. Returns the number of matches deleted.
+    pub async fn delete(&self, id: Uuid) -> anyhow::Result<usize> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM airplays WHERE track_id=?", [id.to_string()])?;
+                Ok(conn.execute("DELETE FROM matches WHERE id=?", [id.to_string()])?)
+            })
+            .await
+    }
+
+    /// Deletes matches recorded, and plays ended, before `timestamp`. Returns the number of
+    /// matches deleted.
+    pub async fn delete_before(&self, timestamp: DateTime<Utc>) -> anyhow::Result<usize> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM airplays WHERE end<?", [timestamp])?;
+                Ok(conn.execute("DELETE FROM matches WHERE timestamp<?", [timestamp])?)
+            })
+            .await
+    }
+
+    /// Adds a match of `track_id` on `station` to the play it continues, or starts a new play.
+    ///
+    /// A match continues a play if it is at most `max_gap` before its start or after its end, so
+    /// matches may arrive out of order, e.g. from the retry queue.
+    pub async fn record_airplay(
+        &self,
+        station: &str,
+        track_id: Uuid,
+        timestamp: DateTime<Utc>,
+        score: u8,
+        max_gap: chrono::Duration,
+    ) -> anyhow::Result<Airplay> {
+        let station = station.to_owned();
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let existing = tx
+                    .query_row(
+                        "SELECT * FROM airplays WHERE station=? AND track_id=? AND start<=? AND end>=?
+                        ORDER BY end DESC LIMIT 1",
+                        params![
+                            station,
+                            track_id.to_string(),
+                            timestamp + max_gap,
+                            timestamp - max_gap
+                        ],
+                        Airplay::from_row,
+                    )
+                    .optional()?;
+
+                let airplay = match existing {
+                    Some(mut airplay) => {
+                        airplay.start = airplay.start.min(timestamp);
+                        airplay.end = airplay.end.max(timestamp);
+                        airplay.segments += 1;
+                        airplay.score_sum += score as u32;
+                        airplay.max_score = airplay.max_score.max(score);
+                        tx.execute(
+                            "UPDATE airplays SET start=?, end=?, segments=?, score_sum=?, max_score=?
+                            WHERE id=?",
+                            params![
+                                airplay.start,
+                                airplay.end,
+                                airplay.segments,
+                                airplay.score_sum,
+                                airplay.max_score,
+                                airplay.id
+                            ],
+                        )?;
+                        airplay
+                    }
+                    None => {
+                        tx.execute(
+                            "INSERT INTO airplays(station, track_id, start, end, segments, score_sum, max_score)
+                            VALUES(?, ?, ?, ?, 1, ?, ?)",
+                            params![station, track_id.to_string(), timestamp, timestamp, score, score],
+                        )?;
+                        Airplay {
+                            id: tx.last_insert_rowid(),
+                            station,
+                            track_id,
+                            start: timestamp,
+                            end: timestamp,
+                            segments: 1,
+                            score_sum: score as u32,
+                            max_score: score,
+                        }
+                    }
+                };
+
+                tx.commit()?;
+                Ok(airplay)
+            })
+            .await
+    }
+
+    /// Returns plays matching `filter`, in order of start.
+    pub async fn airplays(&self, filter: &AirplayFilter) -> anyhow::Result<Vec<Airplay>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT * FROM airplays WHERE {AIRPLAY_CONDITION} ORDER BY start"
+                ))?;
+                let rows = stmt.query(params_from_iter(filter.params()))?;
+                rows.mapped(Airplay::from_row)
+                    .map(|airplay| airplay.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns up to `limit` plays matching `filter` that come after `after` in order of start and
+    /// id, to read all of them page by page.
+    pub async fn airplays_after(
+        &self,
+        filter: &AirplayFilter,
+        after: Option<&Airplay>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Airplay>> {
+        let filter = filter.clone();
+        let after = after.map(|airplay| (airplay.start, airplay.id));
+        self.db
+            .call(move |conn| {
+                let mut params = filter.params();
+                let after = match after {
+                    Some((start, id)) => {
+                        params.push(Box::new(start));
+                        params.push(Box::new(id));
+                        "AND (start, id) > (?6, ?7)"
+                    }
+                    None => "",
+                };
+                params.push(Box::new(limit));
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT * FROM airplays WHERE {AIRPLAY_CONDITION} {after}
+                    ORDER BY start, id LIMIT ?{}",
+                    params.len()
+                ))?;
+                let rows = stmt.query(params_from_iter(params))?;
+                rows.mapped(Airplay::from_row)
+                    .map(|airplay| airplay.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns plays matching `filter` per station, most played first.
+    pub async fn station_stats(&self, filter: &AirplayFilter) -> anyhow::Result<Vec<StationStats>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT station, COUNT(*), SUM(segments), COUNT(DISTINCT track_id)
+                    FROM airplays WHERE {AIRPLAY_CONDITION}
+                    GROUP BY station ORDER BY 2 DESC, station"
+                ))?;
+                let rows = stmt.query(params_from_iter(filter.params()))?;
+                rows.mapped(|row| {
+                    Ok(StationStats {
+                        station: row.get(0)?,
+                        plays: row.get(1)?,
+                        segments: row.get(2)?,
+                        tracks: row.get(3)?,
+                    })
+                })
+                .map(|stats| stats.map_err(|e| e.into()))
+                .collect()
+            })
+            .await
+    }
+
+    /// Returns the `limit` most played tracks among plays matching `filter`, most played first.
+    pub async fn top_tracks(
+        &self,
+        filter: &AirplayFilter,
+        limit: usize,
+    ) -> anyhow::Result<Vec<TrackStats>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT track_id, COUNT(*), SUM(segments)
+                    FROM airplays WHERE {AIRPLAY_CONDITION}
+                    GROUP BY track_id ORDER BY 2 DESC, 3 DESC, track_id LIMIT ?6"
+                ))?;
+                let mut params = filter.params();
+                params.push(Box::new(limit));
+                let rows = stmt.query(params_from_iter(params))?;
+                rows.mapped(|row| {
+                    let track_id: String = row.get(0)?;
+                    Ok(TrackStats {
+                        track_id: Uuid::try_parse(&track_id).map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into())
+                        })?,
+                        plays: row.get(1)?,
+                        segments: row.get(2)?,
+                    })
+                })
+                .map(|stats| stats.map_err(|e| e.into()))
+                .collect()
+            })
+            .await
+    }
+
+    /// Returns the number of plays matching `filter` started in each `bucket`, oldest first.
+    /// Buckets without plays are left out.
+    pub async fn plays_per(
+        &self,
+        bucket: Bucket,
+        filter: &AirplayFilter,
+    ) -> anyhow::Result<Vec<PlayCount>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT strftime('{}', start) AS bucket, COUNT(*)
+                    FROM airplays WHERE {AIRPLAY_CONDITION}
+                    GROUP BY bucket ORDER BY bucket",
+                    bucket.format()
+                ))?;
+                let rows = stmt.query(params_from_iter(filter.params()))?;
+                rows.mapped(|row| {
+                    Ok(PlayCount {
+                        start: row.get(0)?,
+                        plays: row.get(1)?,
+                    })
+                })
+                .map(|count| count.map_err(|e| e.into()))
+                .collect()
+            })
+            .await
+    }
+
+    pub async fn vacuum(&self, pages: u32) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| incremental_vacuum(conn, pages))
+            .await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::Utc;
+    use chrono::{TimeZone, Utc};
     use uuid::Uuid;
 
-    use crate::storage::matches::{MatchData, MatchesStorage};
+    use crate::storage::matches::{AirplayFilter, MatchData, MatchOffsets, MatchesStorage};
+    use crate::storage::matches::{Bucket, Candidate, MatchSource, PlayCount, Seen, StationStats};
+    use crate::storage::{AudioKind, Database, Metadata, MetadataStorage};
 
-    #[test]
-    fn test() {
+    #[tokio::test]
+    async fn test() {
         let id = Uuid::new_v4();
-        let data1 = MatchData::new(id, Utc::now(), 25);
+        let data1 = MatchData::new(id, Utc::now(), 25)
+            .with_offsets(Some(MatchOffsets {
+                query_start: 0.5,
+                query_end: 10.0,
+                track_start: 30.5,
+                track_end: 40.0,
+            }))
+            .with_source(MatchSource {
+                station: Some("kosta".to_owned()),
+                media_sequence: Some(123),
+                aired: Some(Utc::now()),
+                query_id: Some(Uuid::new_v4()),
+                rejected: vec![Candidate {
+                    id: Uuid::new_v4(),
+                    score: 40,
+                    artist: Some("Artist".to_owned()),
+                    title: None,
+                }],
+            });
         let data2 = MatchData::new(id, Utc::now() - chrono::Duration::seconds(1), 95);
 
-        let db = MatchesStorage::new(&"./test_matches.db").unwrap();
-        db.insert(&data1).unwrap();
-        db.insert(&data2).unwrap();
+        let db = MatchesStorage::new(&Database::open(&":memory:").unwrap());
+        db.insert(&data1).await.unwrap();
+        db.insert(&data2).await.unwrap();
+
+        let result = db.get(id).await.unwrap();
+        assert_eq!(&result, &[data1.clone(), data2]);
+        assert!(db
+            .query_ids(None)
+            .await
+            .unwrap()
+            .contains(&data1.source().query_id.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let id = Uuid::new_v4();
+        let old = MatchData::new(id, Utc::now() - chrono::Duration::days(2), 80);
+        let new = MatchData::new(id, Utc::now(), 90);
+
+        let db = MatchesStorage::new(&Database::open(&":memory:").unwrap());
+        db.insert(&old).await.unwrap();
+        db.insert(&new).await.unwrap();
+
+        assert_eq!(
+            db.delete_before(Utc::now() - chrono::Duration::days(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(&db.get(id).await.unwrap(), &[new]);
+
+        assert_eq!(db.delete(id).await.unwrap(), 1);
+        assert!(db.get(id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_airplay() {
+        let db = MatchesStorage::new(&Database::open(&":memory:").unwrap());
+        let track = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let start = Utc::now();
+        let at = |seconds| start + chrono::Duration::seconds(seconds);
+        let gap = chrono::Duration::seconds(30);
+
+        for (seconds, score) in [(0, 80), (10, 90), (20, 100)] {
+            db.record_airplay("kosta", track, at(seconds), score, gap)
+                .await
+                .unwrap();
+        }
+        // A different station, a different track, and the same track much later.
+        db.record_airplay("other", track, at(10), 90, gap)
+            .await
+            .unwrap();
+        db.record_airplay("kosta", other, at(15), 90, gap)
+            .await
+            .unwrap();
+        db.record_airplay("kosta", track, at(600), 70, gap)
+            .await
+            .unwrap();
+        // Late arrival from the retry queue, before the first match.
+        db.record_airplay("kosta", track, at(-10), 90, gap)
+            .await
+            .unwrap();
+
+        let airplays = db
+            .airplays(&AirplayFilter {
+                station: Some("kosta".to_owned()),
+                track_id: Some(track),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(airplays.len(), 2);
+        assert_eq!(airplays[0].start, at(-10));
+        assert_eq!(airplays[0].end, at(20));
+        assert_eq!(airplays[0].duration(), chrono::Duration::seconds(30));
+        assert_eq!(airplays[0].segments, 4);
+        assert_eq!(airplays[0].mean_score(), 90.0);
+        assert_eq!(airplays[0].max_score, 100);
+        assert_eq!(airplays[1].segments, 1);
+
+        assert_eq!(
+            db.airplays(&AirplayFilter::default()).await.unwrap().len(),
+            4
+        );
+        let since = db
+            .airplays(&AirplayFilter {
+                since: Some(at(100)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].start, at(600));
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let db = Database::open(&":memory:").unwrap();
+        let storage = MatchesStorage::new(&db);
+        let metadata_storage = MetadataStorage::new(&db);
+        let song = Uuid::new_v4();
+        let ad = Uuid::new_v4();
+        for (id, kind) in [(song, AudioKind::Music), (ad, AudioKind::Advertisement)] {
+            metadata_storage
+                .insert(&Metadata::new(
+                    id,
+                    Utc::now(),
+                    kind,
+                    "Artist".to_owned(),
+                    "Title".to_owned(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let day = Utc.with_ymd_and_hms(2022, 6, 1, 0, 0, 0).unwrap();
+        let at = |hours: i64, minutes: i64| day + chrono::Duration::minutes(hours * 60 + minutes);
+        let gap = chrono::Duration::seconds(30);
+        for (station, id, time) in [
+            ("kosta", song, at(10, 0)),
+            ("kosta", song, at(10, 30)),
+            ("kosta", ad, at(10, 45)),
+            ("kosta", song, at(12, 0)),
+            ("other", song, at(11, 0)),
+            ("other", ad, at(35, 0)),
+        ] {
+            storage.insert(&MatchData::new(id, time, 90)).await.unwrap();
+            storage
+                .record_airplay(station, id, time, 90, gap)
+                .await
+                .unwrap();
+        }
+
+        let stations = storage
+            .station_stats(&AirplayFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            stations,
+            vec![
+                StationStats {
+                    station: "kosta".to_owned(),
+                    plays: 4,
+                    segments: 4,
+                    tracks: 2
+                },
+                StationStats {
+                    station: "other".to_owned(),
+                    plays: 2,
+                    segments: 2,
+                    tracks: 2
+                },
+            ]
+        );
+
+        let first_day = AirplayFilter {
+            until: Some(at(24, 0)),
+            ..Default::default()
+        };
+        let top = storage.top_tracks(&first_day, 10).await.unwrap();
+        assert_eq!(
+            top.iter()
+                .map(|t| (t.track_id, t.plays))
+                .collect::<Vec<_>>(),
+            vec![(song, 4), (ad, 1)]
+        );
+        let ads = AirplayFilter {
+            kind: Some(AudioKind::Advertisement),
+            ..Default::default()
+        };
+        assert_eq!(storage.top_tracks(&ads, 1).await.unwrap()[0].plays, 2);
+        assert_eq!(storage.airplays(&ads).await.unwrap().len(), 2);
+
+        let hourly = storage.plays_per(Bucket::Hour, &first_day).await.unwrap();
+        assert_eq!(
+            hourly,
+            vec![
+                PlayCount {
+                    start: at(10, 0),
+                    plays: 3
+                },
+                PlayCount {
+                    start: at(11, 0),
+                    plays: 1
+                },
+                PlayCount {
+                    start: at(12, 0),
+                    plays: 1
+                },
+            ]
+        );
+        let daily = storage
+            .plays_per(Bucket::Day, &AirplayFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            daily.iter().map(|c| (c.start, c.plays)).collect::<Vec<_>>(),
+            vec![(at(0, 0), 5), (at(24, 0), 1)]
+        );
+
+        assert_eq!(
+            storage.seen(song).await.unwrap(),
+            Some(Seen {
+                first: at(10, 0),
+                last: at(12, 0),
+                matches: 4
+            })
+        );
+        assert_eq!(storage.seen(Uuid::new_v4()).await.unwrap(), None);
+
+        let matches = storage
+            .between(Some(song), Some(at(10, 15)), Some(at(11, 30)))
+            .await
+            .unwrap();
+        assert_eq!(
+            matches.iter().map(|m| m.timestamp()).collect::<Vec<_>>(),
+            vec![at(10, 30), at(11, 0)]
+        );
+        assert_eq!(storage.between(None, None, None).await.unwrap().len(), 6);
 
-        let result = db.get(id).unwrap();
-        assert_eq!(&result, &[data1, data2]);
+        assert!(storage.orphans().await.unwrap().is_empty());
+        let deleted = Uuid::new_v4();
+        storage
+            .insert(&MatchData::new(deleted, at(13, 0), 90))
+            .await
+            .unwrap();
+        storage
+            .record_airplay("kosta", deleted, at(13, 0), 90, gap)
+            .await
+            .unwrap();
+        assert_eq!(storage.orphans().await.unwrap(), vec![(deleted, 1, 1)]);
     }
 }
@@ -1,20 +1,24 @@
 #![allow(dead_code)]
 
-use std::cell::RefCell;
 use std::fmt::Display;
-use std::path::Path;
+use std::time::Duration;
 
+use anyhow::Context;
 use chrono::{DateTime, Utc};
 use lazy_static::__Deref;
-use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef};
-use rusqlite::{params, Connection, OpenFlags, ToSql};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, Value, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, Row, ToSql};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::{incremental_vacuum, Database};
+
 pub struct MetadataStorage {
-    conn: RefCell<Connection>,
+    db: Database,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AudioKind {
     Advertisement,
     Music,
@@ -41,15 +45,14 @@ impl FromSql for AudioKind {
             .and_then(|v| v.try_into().map_err(|_| FromSqlError::InvalidType))
     }
 }
-impl ToString for AudioKind {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for AudioKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             AudioKind::Advertisement => "advertisement",
             AudioKind::Music => "music",
             AudioKind::Talk => "talk",
             AudioKind::Unknown => "unknown",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -67,6 +70,150 @@ impl TryFrom<&str> for AudioKind {
     }
 }
 
+/// Columns of an item, in the order [`Metadata::from_row`] reads them.
+const COLUMNS: &str = "id, date, kind, artist, title, station, segment_uri, media_sequence,
+    extinf_duration_ms, extinf_title, song_spot, media_base_id, itunes_track_id, amg_track_id,
+    amg_artist_id, ta_id, tp_id, cartcut_id, artwork_url, length_seconds, uns_id,
+    spot_instance_id, aired";
+
+/// Catalogue ids and classification fields of a KostaRadio `#EXTINF` title, as received.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogInfo {
+    pub song_spot: String,
+    pub media_base_id: i64,
+    pub itunes_track_id: i64,
+    pub amg_track_id: i64,
+    pub amg_artist_id: i64,
+    pub ta_id: i64,
+    pub tp_id: i64,
+    pub cartcut_id: i64,
+    pub artwork_url: Option<String>,
+    pub length: Duration,
+    pub uns_id: i64,
+    pub spot_instance_id: Option<Uuid>,
+}
+
+/// Where in a stream an item was received, with the playlist fields it was classified by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentSource {
+    pub station: Option<String>,
+    pub uri: Option<String>,
+    pub media_sequence: Option<u64>,
+    /// `#EXT-X-PROGRAM-DATE-TIME`, when the segment started airing.
+    pub aired: Option<DateTime<Utc>>,
+    /// `#EXTINF` duration.
+    pub duration: Option<Duration>,
+    /// `#EXTINF` title, unparsed.
+    pub raw_title: Option<String>,
+    pub catalog: Option<CatalogInfo>,
+}
+
+/// Order of [`MetadataStorage::search`] results.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MetadataOrder {
+    #[default]
+    Newest,
+    Oldest,
+    Artist,
+    Title,
+}
+
+impl MetadataOrder {
+    fn sql(&self) -> &'static str {
+        match self {
+            MetadataOrder::Newest => "date DESC, id",
+            MetadataOrder::Oldest => "date, id",
+            MetadataOrder::Artist => "artist COLLATE NOCASE, title COLLATE NOCASE, date, id",
+            MetadataOrder::Title => "title COLLATE NOCASE, artist COLLATE NOCASE, date, id",
+        }
+    }
+}
+
+impl TryFrom<&str> for MetadataOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "newest" => Ok(MetadataOrder::Newest),
+            "oldest" => Ok(MetadataOrder::Oldest),
+            "artist" => Ok(MetadataOrder::Artist),
+            "title" => Ok(MetadataOrder::Title),
+            _ => Err(anyhow::anyhow!("Invalid order value={value}")),
+        }
+    }
+}
+
+/// Which items to search for. Empty `kinds` means all kinds, other unset fields match all.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataFilter {
+    pub kinds: Vec<AudioKind>,
+    pub station: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Substring of the artist, ignoring ASCII case.
+    pub artist: Option<String>,
+    /// Substring of the title, ignoring ASCII case.
+    pub title: Option<String>,
+    /// FTS5 query over artist and title, e.g. `beatl* OR "let it be"`.
+    pub text: Option<String>,
+    pub order: MetadataOrder,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl MetadataFilter {
+    /// The `WHERE` clause and its parameters, ignoring order and paging.
+    fn condition(&self) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut conditions = vec!["1".to_owned()];
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+        if !self.kinds.is_empty() {
+            conditions.push(format!(
+                "kind IN ({})",
+                vec!["?"; self.kinds.len()].join(", ")
+            ));
+            values.extend(
+                self.kinds
+                    .iter()
+                    .map(|&kind| Box::new(kind) as Box<dyn ToSql>),
+            );
+        }
+        if let Some(station) = &self.station {
+            conditions.push("station=?".to_owned());
+            values.push(Box::new(station.clone()));
+        }
+        if let Some(since) = self.since {
+            conditions.push("date>=?".to_owned());
+            values.push(Box::new(since));
+        }
+        if let Some(until) = self.until {
+            conditions.push("date<?".to_owned());
+            values.push(Box::new(until));
+        }
+        for (column, pattern) in [("artist", &self.artist), ("title", &self.title)] {
+            if let Some(pattern) = pattern {
+                conditions.push(format!("{column} LIKE ? ESCAPE '\\'"));
+                values.push(Box::new(format!("%{}%", escape_like(pattern))));
+            }
+        }
+        if let Some(text) = &self.text {
+            conditions
+                .push("id IN (SELECT id FROM metadata_fts WHERE metadata_fts MATCH ?)".to_owned());
+            values.push(Box::new(format!("{{artist title}}: ({text})")));
+        }
+
+        (conditions.join(" AND "), values)
+    }
+}
+
+/// Escapes the `LIKE` wildcards in `value`, with `\` as the escape character.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Metadata {
     pub id: Uuid,
@@ -74,6 +221,7 @@ pub struct Metadata {
     kind: AudioKind,
     artist: String,
     title: String,
+    source: SegmentSource,
 }
 
 impl Metadata {
@@ -90,76 +238,301 @@ impl Metadata {
             kind,
             artist,
             title,
+            source: SegmentSource::default(),
         }
     }
-}
 
-impl MetadataStorage {
-    pub fn new<P>(path: &P) -> anyhow::Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let conn = Connection::open_with_flags(
-            path,
-            OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
-        )?;
-
-        conn.execute_batch(
-            r#"
-        CREATE TABLE IF NOT EXISTS metadata(
-            id STRING PRIMARY KEY,
-            date DATETIME NOT NULL,
-            kind STRING NOT NULL,
-            artist STRING NOT NULL,
-            title STRING NOT NULL
-        ) WITHOUT ROWID"#,
-        )?;
+    pub fn with_source(mut self, source: SegmentSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    pub fn kind(&self) -> AudioKind {
+        self.kind
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn source(&self) -> &SegmentSource {
+        &self.source
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let parse = |index: usize, id: String| {
+            Uuid::try_parse(&id)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, e.into()))
+        };
+
+        let song_spot: Option<String> = row.get(10)?;
+        let catalog = match song_spot {
+            Some(song_spot) => Some(CatalogInfo {
+                song_spot,
+                media_base_id: row.get(11)?,
+                itunes_track_id: row.get(12)?,
+                amg_track_id: row.get(13)?,
+                amg_artist_id: row.get(14)?,
+                ta_id: row.get(15)?,
+                tp_id: row.get(16)?,
+                cartcut_id: row.get(17)?,
+                artwork_url: row.get(18)?,
+                length: Duration::from_secs(row.get(19)?),
+                uns_id: row.get(20)?,
+                spot_instance_id: row
+                    .get::<_, Option<String>>(21)?
+                    .map(|id| parse(21, id))
+                    .transpose()?,
+            }),
+            None => None,
+        };
 
         Ok(Self {
-            conn: RefCell::new(conn),
+            id: parse(0, row.get(0)?)?,
+            date: row.get(1)?,
+            kind: row.get(2)?,
+            artist: row.get(3)?,
+            title: row.get(4)?,
+            source: SegmentSource {
+                station: row.get(5)?,
+                uri: row.get(6)?,
+                media_sequence: row.get(7)?,
+                aired: row.get(22)?,
+                duration: row.get::<_, Option<u64>>(8)?.map(Duration::from_millis),
+                raw_title: row.get(9)?,
+                catalog,
+            },
         })
     }
+}
+
+impl MetadataStorage {
+    pub fn new(db: &Database) -> Self {
+        Self { db: db.clone() }
+    }
+
+    pub async fn insert(&self, metadata: &Metadata) -> anyhow::Result<()> {
+        let metadata = metadata.clone();
+        self.db.call(move |conn| insert(conn, &metadata)).await
+    }
+
+    pub async fn get(&self, id: Uuid) -> anyhow::Result<Metadata> {
+        self.db
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare(&format!("SELECT {COLUMNS} FROM metadata WHERE id=?"))?;
+                let data = stmt.query_row([id.to_string()], Metadata::from_row)?;
+                Ok(data)
+            })
+            .await
+    }
+
+    pub async fn contains(&self, id: Uuid) -> anyhow::Result<bool> {
+        self.db
+            .call(move |conn| {
+                let exists = conn
+                    .prepare("SELECT 1 FROM metadata WHERE id=?")?
+                    .exists([id.to_string()])?;
+                Ok(exists)
+            })
+            .await
+    }
 
-    pub fn insert(&self, metadata: &Metadata) -> anyhow::Result<()> {
-        self.conn
-            .borrow_mut()
-            .prepare_cached(
-                "INSERT INTO metadata(id, date, kind, artist, title) VALUES(?, ?, ?, ?, ?)",
-            )?
-            .execute(params![
-                metadata.id.to_string(),
-                metadata.date,
-                metadata.kind,
-                metadata.artist,
-                metadata.title
-            ])?;
+    /// Returns items stored within `since..until`, oldest first. Open ends are unbounded.
+    pub async fn between(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Vec<Metadata>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS} FROM metadata
+                    WHERE (?1 IS NULL OR date>=?1) AND (?2 IS NULL OR date<?2) ORDER BY date, id"
+                ))?;
+                let rows = stmt.query(params![since, until])?;
+                rows.mapped(Metadata::from_row)
+                    .map(|m| m.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns a page of the items matching `filter`, in its order.
+    pub async fn search(&self, filter: &MetadataFilter) -> anyhow::Result<Vec<Metadata>> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let (condition, mut values) = filter.condition();
+                // A negative limit is no limit.
+                values.push(Box::new(
+                    filter.limit.map(|limit| limit as i64).unwrap_or(-1),
+                ));
+                values.push(Box::new(filter.offset as i64));
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS} FROM metadata WHERE {condition}
+                    ORDER BY {} LIMIT ? OFFSET ?",
+                    filter.order.sql()
+                ))?;
+                let rows = stmt.query(params_from_iter(values))?;
+                rows.mapped(Metadata::from_row)
+                    .map(|m| m.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+            .context("Search metadata")
+    }
+
+    /// Returns up to `limit` items matching `filter` that come after `after` in order of date and
+    /// id, to read all of them page by page. The order and paging of `filter` are ignored.
+    pub async fn after(
+        &self,
+        filter: &MetadataFilter,
+        after: Option<&Metadata>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Metadata>> {
+        let filter = filter.clone();
+        let after = after.map(|item| (item.date, item.id.to_string()));
+        self.db
+            .call(move |conn| {
+                let (mut condition, mut values) = filter.condition();
+                if let Some((date, id)) = after {
+                    condition += " AND (date, id) > (?, ?)";
+                    values.push(Box::new(date));
+                    values.push(Box::new(id));
+                }
+                values.push(Box::new(limit));
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {COLUMNS} FROM metadata WHERE {condition} ORDER BY date, id LIMIT ?"
+                ))?;
+                let rows = stmt.query(params_from_iter(values))?;
+                rows.mapped(Metadata::from_row)
+                    .map(|m| m.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns the number of items matching `filter`, ignoring its paging.
+    pub async fn count(&self, filter: &MetadataFilter) -> anyhow::Result<usize> {
+        let filter = filter.clone();
+        self.db
+            .call(move |conn| {
+                let (condition, values) = filter.condition();
+                Ok(conn.query_row(
+                    &format!("SELECT COUNT(*) FROM metadata WHERE {condition}"),
+                    params_from_iter(values),
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .context("Count metadata")
+    }
+
+    /// Returns ids of items of `kind` stored before `date`.
+    pub async fn older_than(
+        &self,
+        kind: AudioKind,
+        date: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare("SELECT id FROM metadata WHERE kind=? AND date<?")?;
+                let rows = stmt.query(params![kind, date])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
+
+    pub async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM metadata")?;
+                let rows = stmt.query([])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
 
-        Ok(())
+    pub async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM metadata WHERE id=?", [id.to_string()])?;
+                Ok(())
+            })
+            .await
     }
 
-    pub fn get(&self, id: Uuid) -> anyhow::Result<Metadata> {
-        let conn = self.conn.borrow();
-        let mut stmt = conn.prepare("SELECT date, kind, artist, title FROM metadata WHERE id=?")?;
-        let data = stmt.query_row([id.to_string()], |row| {
-            let date: DateTime<Utc> = row.get(0)?;
-            let kind: AudioKind = row.get(1)?;
-            let artist = row.get(2)?;
-            let title = row.get(3)?;
-            Ok(Metadata::new(id, date, kind, artist, title))
-        })?;
-        Ok(data)
+    pub async fn vacuum(&self, pages: u32) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| incremental_vacuum(conn, pages))
+            .await
     }
 }
 
+/// Inserts `metadata` with `conn`, which may be a transaction spanning other stores.
+pub(super) fn insert(conn: &Connection, metadata: &Metadata) -> anyhow::Result<()> {
+    let source = &metadata.source;
+    let catalog = source.catalog.as_ref();
+    conn.prepare_cached(&format!(
+        "INSERT INTO metadata({COLUMNS})
+        VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    ))?
+    .execute(params![
+        metadata.id.to_string(),
+        metadata.date,
+        metadata.kind,
+        metadata.artist,
+        metadata.title,
+        source.station,
+        source.uri,
+        source.media_sequence,
+        source.duration.map(|d| d.as_millis() as u64),
+        source.raw_title,
+        catalog.map(|c| &c.song_spot),
+        catalog.map(|c| c.media_base_id),
+        catalog.map(|c| c.itunes_track_id),
+        catalog.map(|c| c.amg_track_id),
+        catalog.map(|c| c.amg_artist_id),
+        catalog.map(|c| c.ta_id),
+        catalog.map(|c| c.tp_id),
+        catalog.map(|c| c.cartcut_id),
+        catalog.and_then(|c| c.artwork_url.as_ref()),
+        catalog.map(|c| c.length.as_secs()),
+        catalog.map(|c| c.uns_id),
+        catalog
+            .and_then(|c| c.spot_instance_id)
+            .map(|id| id.to_string()),
+        source.aired,
+    ])?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use chrono::Utc;
     use uuid::Uuid;
 
-    use super::{Metadata, MetadataStorage};
+    use super::{AudioKind, CatalogInfo, Metadata, MetadataFilter, MetadataOrder};
+    use super::{MetadataStorage, SegmentSource};
+    use crate::storage::Database;
 
-    #[test]
-    fn test_existing() {
+    #[tokio::test]
+    async fn test_existing() {
         let metadata = Metadata::new(
             Uuid::new_v4(),
             Utc::now(),
@@ -168,16 +541,236 @@ mod tests {
             "Title".to_string(),
         );
 
-        let storage = MetadataStorage::new(&"./test_metadata.db").unwrap();
-        storage.insert(&metadata).unwrap();
-        let result = storage.get(metadata.id).unwrap();
+        let storage = MetadataStorage::new(&Database::open(&":memory:").unwrap());
+        storage.insert(&metadata).await.unwrap();
+        let result = storage.get(metadata.id).await.unwrap();
 
         assert_eq!(metadata, result);
     }
 
-    #[test]
-    fn test_non_existing() {
-        let storage = MetadataStorage::new(&"./test_metadata.db").unwrap();
-        assert!(storage.get(Uuid::new_v4()).is_err());
+    #[tokio::test]
+    async fn test_older_than() {
+        let storage = MetadataStorage::new(&Database::open(&":memory:").unwrap());
+        let old = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now() - chrono::Duration::days(2),
+            super::AudioKind::Talk,
+            "Artist".to_string(),
+            "Title".to_string(),
+        );
+        let new = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            super::AudioKind::Talk,
+            "Artist".to_string(),
+            "Title".to_string(),
+        );
+        storage.insert(&old).await.unwrap();
+        storage.insert(&new).await.unwrap();
+
+        let before = Utc::now() - chrono::Duration::days(1);
+        assert_eq!(
+            storage
+                .older_than(super::AudioKind::Talk, before)
+                .await
+                .unwrap(),
+            vec![old.id]
+        );
+        assert!(storage
+            .older_than(super::AudioKind::Music, before)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            storage.between(None, None).await.unwrap(),
+            vec![old.clone(), new.clone()]
+        );
+        assert_eq!(
+            storage.between(Some(before), None).await.unwrap(),
+            vec![new.clone()]
+        );
+        assert_eq!(
+            storage.between(None, Some(before)).await.unwrap(),
+            vec![old.clone()]
+        );
+
+        storage.delete(old.id).await.unwrap();
+        assert!(storage.get(old.id).await.is_err());
+        assert!(storage.get(new.id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_source() {
+        let storage = MetadataStorage::new(&Database::open(&":memory:").unwrap());
+        let source = SegmentSource {
+            station: Some("kosta".to_owned()),
+            uri: Some("https://example.com/segment_123.aac".to_owned()),
+            media_sequence: Some(123),
+            aired: Some(Utc::now()),
+            duration: Some(Duration::from_millis(10_010)),
+            raw_title: Some(r#"title="Title",artist="Artist""#.to_owned()),
+            catalog: Some(CatalogInfo {
+                song_spot: "M".to_owned(),
+                media_base_id: 1,
+                itunes_track_id: 2,
+                amg_track_id: -1,
+                artwork_url: Some("https://example.com/artwork.jpg".to_owned()),
+                length: Duration::from_secs(183),
+                spot_instance_id: Some(Uuid::new_v4()),
+                ..Default::default()
+            }),
+        };
+        let with_source = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            super::AudioKind::Music,
+            "Artist".to_string(),
+            "Title".to_string(),
+        )
+        .with_source(source);
+        let without_source = Metadata::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            super::AudioKind::Talk,
+            "Artist".to_string(),
+            "Title".to_string(),
+        );
+        storage.insert(&with_source).await.unwrap();
+        storage.insert(&without_source).await.unwrap();
+
+        assert_eq!(storage.get(with_source.id).await.unwrap(), with_source);
+        assert_eq!(
+            storage.between(None, None).await.unwrap(),
+            vec![with_source, without_source]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let storage = MetadataStorage::new(&Database::open(&":memory:").unwrap());
+        let now = Utc::now();
+        let item = |hours, kind, station: &str, artist: &str, title: &str| {
+            Metadata::new(
+                Uuid::new_v4(),
+                now - chrono::Duration::hours(hours),
+                kind,
+                artist.to_owned(),
+                title.to_owned(),
+            )
+            .with_source(SegmentSource {
+                station: Some(station.to_owned()),
+                ..Default::default()
+            })
+        };
+        let help = item(4, AudioKind::Music, "kosta", "The Beatles", "Help!");
+        let waterloo = item(3, AudioKind::Music, "kosta", "ABBA", "Waterloo");
+        let news = item(2, AudioKind::Talk, "kosta", "News", "100%_news");
+        let yesterday = item(1, AudioKind::Music, "other", "Beatles Tribute", "Yesterday");
+        for item in [&help, &waterloo, &news, &yesterday] {
+            storage.insert(item).await.unwrap();
+        }
+
+        let search = |filter: MetadataFilter| {
+            let storage = &storage;
+            async move {
+                let items = storage.search(&filter).await.unwrap();
+                items.into_iter().map(|item| item.id).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            search(Default::default()).await,
+            vec![yesterday.id, news.id, waterloo.id, help.id]
+        );
+        assert_eq!(
+            search(MetadataFilter {
+                kinds: vec![AudioKind::Music],
+                station: Some("kosta".to_owned()),
+                order: MetadataOrder::Oldest,
+                ..Default::default()
+            })
+            .await,
+            vec![help.id, waterloo.id]
+        );
+        assert_eq!(
+            search(MetadataFilter {
+                since: Some(now - chrono::Duration::minutes(150)),
+                until: Some(now),
+                ..Default::default()
+            })
+            .await,
+            vec![yesterday.id, news.id]
+        );
+        assert_eq!(
+            search(MetadataFilter {
+                artist: Some("beatles".to_owned()),
+                order: MetadataOrder::Title,
+                ..Default::default()
+            })
+            .await,
+            vec![help.id, yesterday.id]
+        );
+        // Wildcards are matched literally.
+        assert_eq!(
+            search(MetadataFilter {
+                title: Some("%_".to_owned()),
+                ..Default::default()
+            })
+            .await,
+            vec![news.id]
+        );
+        assert_eq!(
+            search(MetadataFilter {
+                text: Some("beatl* NOT tribute".to_owned()),
+                ..Default::default()
+            })
+            .await,
+            vec![help.id]
+        );
+
+        let page = MetadataFilter {
+            order: MetadataOrder::Artist,
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(search(page.clone()).await, vec![yesterday.id, news.id]);
+        assert_eq!(storage.count(&page).await.unwrap(), 4);
+
+        // Reading all items page by page.
+        let mut all = Vec::new();
+        let mut after = None;
+        loop {
+            let page = storage
+                .after(&MetadataFilter::default(), after.as_ref(), 3)
+                .await
+                .unwrap();
+            all.extend(page.iter().map(|item| item.id));
+            match page.last() {
+                Some(last) if page.len() == 3 => after = Some(last.clone()),
+                _ => break,
+            }
+        }
+        let oldest = MetadataFilter {
+            order: MetadataOrder::Oldest,
+            ..Default::default()
+        };
+        assert_eq!(all, search(oldest).await);
+
+        // The full-text index follows deletes.
+        storage.delete(help.id).await.unwrap();
+        assert!(search(MetadataFilter {
+            text: Some("help".to_owned()),
+            ..Default::default()
+        })
+        .await
+        .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_non_existing() {
+        let storage = MetadataStorage::new(&Database::open(&":memory:").unwrap());
+        assert!(storage.get(Uuid::new_v4()).await.is_err());
     }
 }
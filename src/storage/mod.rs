@@ -1,15 +1,83 @@
 #![allow(unused_imports)]
 
-mod audio;
+pub mod audio;
+mod database;
 mod matches;
 mod metadata;
+mod outbox;
+mod queue;
 
 pub use audio::AudioData;
 pub use audio::AudioStorage;
 
+pub use database::Database;
+pub use database::LegacyFiles;
+
+pub use matches::Airplay;
+pub use matches::AirplayFilter;
+pub use matches::Bucket;
+pub use matches::Candidate;
 pub use matches::MatchData;
+pub use matches::MatchOffsets;
+pub use matches::MatchSource;
 pub use matches::MatchesStorage;
+pub use matches::PlayCount;
+pub use matches::Seen;
+pub use matches::StationStats;
+pub use matches::TrackStats;
 
 pub use metadata::AudioKind;
+pub use metadata::CatalogInfo;
 pub use metadata::Metadata;
+pub use metadata::MetadataFilter;
+pub use metadata::MetadataOrder;
 pub use metadata::MetadataStorage;
+pub use metadata::SegmentSource;
+
+pub use outbox::OutboxEntry;
+pub use outbox::OutboxState;
+pub use outbox::OutboxStorage;
+
+pub use queue::QueueStats;
+pub use queue::QueueStorage;
+pub use queue::QueuedSegment;
+
+use rusqlite::Connection;
+
+/// Pragma applied to every database before its tables are created, so deleted pages can be
+/// given back to the filesystem with [`incremental_vacuum`].
+const AUTO_VACUUM: &str = "PRAGMA auto_vacuum = INCREMENTAL;";
+
+/// Adds `column` to `table` unless a previous run already did.
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name=?"
+        ))?
+        .exists([column])?;
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))?;
+    }
+    Ok(())
+}
+
+/// Frees up to `pages` unused pages.
+///
+/// Databases created before incremental auto-vacuum was enabled are left alone: converting
+/// them takes a full `VACUUM`, which is run by [`Database::vacuum`] instead.
+fn incremental_vacuum(conn: &Connection, pages: u32) -> anyhow::Result<()> {
+    let mode: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+    if mode != 2 {
+        log::warn!("Database without incremental auto-vacuum not vacuumed, run `db vacuum`");
+    } else {
+        conn.execute_batch(&format!("PRAGMA incremental_vacuum({pages});"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, ValueRef};
+use rusqlite::{params, Row, ToSql};
+use uuid::Uuid;
+
+use super::{metadata, Database, Metadata};
+
+/// How far a registration got.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutboxState {
+    /// The id is taken and audio may be stored under it, the metadata is not.
+    Reserved,
+    /// Audio and metadata are stored, the track is not in the fingerprint backend yet.
+    Pending,
+}
+
+impl ToSql for OutboxState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            OutboxState::Reserved => "reserved",
+            OutboxState::Pending => "pending",
+        }
+        .to_sql()
+    }
+}
+
+impl FromSql for OutboxState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "reserved" => Ok(OutboxState::Reserved),
+            "pending" => Ok(OutboxState::Pending),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A registration of a new item that is not finished yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    pub id: Uuid,
+    pub created: DateTime<Utc>,
+    pub state: OutboxState,
+    /// The item comes from a queued segment, whose audio was stored under the id before.
+    pub queued: bool,
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+}
+
+impl OutboxEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let id: String = row.get(0)?;
+        Ok(Self {
+            id: Uuid::try_parse(&id)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into()))?,
+            created: row.get(1)?,
+            state: row.get(2)?,
+            queued: row.get(3)?,
+            attempts: row.get(4)?,
+            next_attempt: row.get(5)?,
+        })
+    }
+}
+
+/// Registrations of new items in progress, so the local stores and the fingerprint backend
+/// end up agreeing after a failure or crash in between.
+pub struct OutboxStorage {
+    db: Database,
+}
+
+impl OutboxStorage {
+    pub fn new(db: &Database) -> Self {
+        Self { db: db.clone() }
+    }
+
+    /// Starts registering `id`, before any of its audio is stored.
+    pub async fn reserve(&self, id: Uuid, now: DateTime<Utc>, queued: bool) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO outbox(id, created, state, queued, next_attempt)
+                    VALUES(?, ?, ?, ?, ?)",
+                    params![id.to_string(), now, OutboxState::Reserved, queued, now],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Inserts the metadata of a reserved item and marks it pending, removing the queued segment
+    /// it comes from if any, in one transaction.
+    pub async fn register(&self, metadata: &Metadata) -> anyhow::Result<()> {
+        let metadata = metadata.clone();
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let id = metadata.id.to_string();
+                let updated = tx.execute(
+                    "UPDATE outbox SET state=? WHERE id=? AND state=?",
+                    params![OutboxState::Pending, id, OutboxState::Reserved],
+                )?;
+                if updated != 1 {
+                    bail!("{id} is not reserved");
+                }
+                metadata::insert(&tx, &metadata)?;
+                tx.execute("DELETE FROM queue WHERE id=?", [&id])?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Returns all registrations in progress, oldest first.
+    pub async fn entries(&self) -> anyhow::Result<Vec<OutboxEntry>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT * FROM outbox ORDER BY created")?;
+                let rows = stmt.query([])?;
+                rows.mapped(OutboxEntry::from_row)
+                    .map(|entry| entry.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Returns up to `limit` pending registrations due at `now`, oldest first.
+    pub async fn due(&self, now: DateTime<Utc>, limit: usize) -> anyhow::Result<Vec<OutboxEntry>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT * FROM outbox WHERE state=? AND next_attempt<=?
+                    ORDER BY created LIMIT ?",
+                )?;
+                let rows = stmt.query(params![OutboxState::Pending, now, limit])?;
+                rows.mapped(OutboxEntry::from_row)
+                    .map(|entry| entry.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Records a failed backend insert and postpones the next one to `next_attempt`.
+    pub async fn postpone(&self, id: Uuid, next_attempt: DateTime<Utc>) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE outbox SET attempts=attempts+1, next_attempt=? WHERE id=?",
+                    params![next_attempt, id.to_string()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Finishes a registration, or drops a rolled back one.
+    pub async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM outbox WHERE id=?", [id.to_string()])?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM outbox")?;
+                let rows = stmt.query([])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::{OutboxState, OutboxStorage};
+    use crate::storage::{AudioKind, Database, Metadata, MetadataStorage};
+    use crate::storage::{QueueStorage, QueuedSegment};
+
+    #[tokio::test]
+    async fn test() {
+        let db = Database::open(&":memory:").unwrap();
+        let outbox = OutboxStorage::new(&db);
+        let metadata_storage = MetadataStorage::new(&db);
+        let queue = QueueStorage::new(&db);
+        let now = Utc::now();
+
+        let item = Metadata::new(
+            Uuid::new_v4(),
+            now,
+            AudioKind::Music,
+            "Artist".to_owned(),
+            "Title".to_owned(),
+        );
+        queue
+            .insert(&QueuedSegment {
+                id: item.id,
+                created: now,
+                attempts: 0,
+                next_attempt: now,
+                filename: "segment.aac".to_owned(),
+                format: "audio/aac".to_owned(),
+                kind: AudioKind::Music,
+                artist: "Artist".to_owned(),
+                title: "Title".to_owned(),
+                source: Default::default(),
+            })
+            .await
+            .unwrap();
+
+        // Not reserved.
+        assert!(outbox.register(&item).await.is_err());
+
+        outbox.reserve(item.id, now, true).await.unwrap();
+        assert!(outbox.due(now, 10).await.unwrap().is_empty());
+        outbox.register(&item).await.unwrap();
+        assert_eq!(metadata_storage.get(item.id).await.unwrap(), item);
+        assert!(queue.ids().await.unwrap().is_empty());
+        // Registered once only.
+        assert!(outbox.register(&item).await.is_err());
+
+        let entries = outbox.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, OutboxState::Pending);
+        assert!(entries[0].queued);
+        assert_eq!(outbox.due(now, 10).await.unwrap(), entries);
+
+        outbox
+            .postpone(item.id, now + Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(outbox.due(now, 10).await.unwrap().is_empty());
+        let due = outbox.due(now + Duration::minutes(1), 10).await.unwrap();
+        assert_eq!(due[0].attempts, 1);
+
+        outbox.delete(item.id).await.unwrap();
+        assert!(outbox.entries().await.unwrap().is_empty());
+    }
+}
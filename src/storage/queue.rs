@@ -0,0 +1,215 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rusqlite::types::Type;
+use rusqlite::{params, Row};
+use uuid::Uuid;
+
+use super::{AudioKind, Database, SegmentSource};
+
+/// A segment waiting for the fingerprint backend. Its bytes are in the audio storage under `id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedSegment {
+    pub id: Uuid,
+    /// When the segment was received.
+    pub created: DateTime<Utc>,
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    pub filename: String,
+    pub format: String,
+    pub kind: AudioKind,
+    pub artist: String,
+    pub title: String,
+    pub source: SegmentSource,
+}
+
+impl QueuedSegment {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let id: String = row.get(0)?;
+        Ok(Self {
+            id: Uuid::try_parse(&id)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into()))?,
+            created: row.get(1)?,
+            attempts: row.get(2)?,
+            next_attempt: row.get(3)?,
+            filename: row.get(4)?,
+            format: row.get(5)?,
+            kind: row.get(6)?,
+            artist: row.get(7)?,
+            title: row.get(8)?,
+            source: row
+                .get::<_, Option<String>>(9)?
+                .map(|source| serde_json::from_str(&source))
+                .transpose()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, e.into()))?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub oldest: Option<DateTime<Utc>>,
+}
+
+/// Durable queue of segments that failed to reach the fingerprint backend.
+pub struct QueueStorage {
+    db: Database,
+}
+
+impl QueueStorage {
+    pub fn new(db: &Database) -> Self {
+        Self { db: db.clone() }
+    }
+
+    pub async fn insert(&self, segment: &QueuedSegment) -> anyhow::Result<()> {
+        let segment = segment.clone();
+        let source = serde_json::to_string(&segment.source)?;
+        self.db
+            .call(move |conn| {
+                conn.prepare_cached("INSERT INTO queue VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+                    .context("Prepare statement")?
+                    .execute(params![
+                        segment.id.to_string(),
+                        segment.created,
+                        segment.attempts,
+                        segment.next_attempt,
+                        segment.filename,
+                        segment.format,
+                        segment.kind,
+                        segment.artist,
+                        segment.title,
+                        source
+                    ])
+                    .context("Execute statement")?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Returns up to `limit` segments due at `now`, oldest first.
+    pub async fn due(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<QueuedSegment>> {
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT * FROM queue WHERE next_attempt<=? ORDER BY created LIMIT ?",
+                )?;
+                let rows = stmt.query(params![now, limit])?;
+                rows.mapped(QueuedSegment::from_row)
+                    .map(|segment| segment.map_err(|e| e.into()))
+                    .collect()
+            })
+            .await
+    }
+
+    /// Records a failed attempt and postpones the segment to `next_attempt`.
+    pub async fn postpone(&self, id: Uuid, next_attempt: DateTime<Utc>) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE queue SET attempts=attempts+1, next_attempt=? WHERE id=?",
+                    params![next_attempt, id.to_string()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute("DELETE FROM queue WHERE id=?", [id.to_string()])?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn ids(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM queue")?;
+                let rows = stmt.query([])?;
+                rows.mapped(|row| row.get::<_, String>(0))
+                    .map(|id| Ok(Uuid::try_parse(&id?)?))
+                    .collect()
+            })
+            .await
+    }
+
+    pub async fn stats(&self) -> anyhow::Result<QueueStats> {
+        self.db
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(*), MIN(created) FROM queue", [], |row| {
+                        Ok(QueueStats {
+                            depth: row.get(0)?,
+                            oldest: row.get(1)?,
+                        })
+                    })?,
+                )
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::{QueueStorage, QueuedSegment};
+    use crate::storage::{AudioKind, Database};
+
+    fn segment(created: chrono::DateTime<Utc>) -> QueuedSegment {
+        QueuedSegment {
+            id: Uuid::new_v4(),
+            created,
+            attempts: 0,
+            next_attempt: created,
+            filename: "segment.aac".to_owned(),
+            format: "audio/aac".to_owned(),
+            kind: AudioKind::Music,
+            artist: "Artist".to_owned(),
+            title: "Title".to_owned(),
+            source: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let now = Utc::now();
+        let older = segment(now - Duration::minutes(2));
+        let newer = segment(now - Duration::minutes(1));
+
+        let queue = QueueStorage::new(&Database::open(&":memory:").unwrap());
+        queue.insert(&newer).await.unwrap();
+        queue.insert(&older).await.unwrap();
+
+        let stats = queue.stats().await.unwrap();
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.oldest, Some(older.created));
+
+        assert_eq!(
+            queue.due(now, 10).await.unwrap(),
+            vec![older.clone(), newer.clone()]
+        );
+
+        queue
+            .postpone(older.id, now + Duration::minutes(1))
+            .await
+            .unwrap();
+        let due = queue.due(now, 10).await.unwrap();
+        assert_eq!(due, vec![newer.clone()]);
+        let due = queue.due(now + Duration::minutes(1), 1).await.unwrap();
+        assert_eq!(due[0].id, older.id);
+        assert_eq!(due[0].attempts, 1);
+
+        queue.delete(older.id).await.unwrap();
+        queue.delete(newer.id).await.unwrap();
+        assert_eq!(queue.stats().await.unwrap(), Default::default());
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::emysound::{FingerprintBackend, QueryResult, TrackInfo};
+use crate::output::{print_json, table, Output};
+
+/// Management of the tracks in the fingerprint backend.
+pub struct Tracks<'a> {
+    backend: &'a dyn FingerprintBackend,
+    output: Output,
+}
+
+impl<'a> Tracks<'a> {
+    pub fn new(backend: &'a dyn FingerprintBackend, output: Output) -> Self {
+        Self { backend, output }
+    }
+
+    pub async fn add(
+        &self,
+        path: &Path,
+        artist: String,
+        title: String,
+        id: Option<Uuid>,
+    ) -> anyhow::Result<()> {
+        let (filename, bytes) = read(path).await?;
+        let info = TrackInfo::new(id.unwrap_or_else(Uuid::new_v4), artist, title);
+
+        self.backend.insert(info.clone(), &filename, &bytes).await?;
+        self.print_tracks(&[info])
+    }
+
+    pub async fn query(&self, path: &Path) -> anyhow::Result<()> {
+        let (filename, bytes) = read(path).await?;
+        let results = self.backend.query(&filename, &bytes).await?;
+        self.print_results(&results)
+    }
+
+    pub async fn list(&self) -> anyhow::Result<()> {
+        let tracks = self.backend.list_all().await?;
+        self.print_tracks(&tracks)
+    }
+
+    pub async fn delete(&self, id: Uuid) -> anyhow::Result<()> {
+        self.backend.delete(id).await?;
+        log::info!("Deleted {id}");
+        Ok(())
+    }
+
+    /// Deletes every track. Without `confirm`, only reports how many there are.
+    pub async fn delete_all(&self, confirm: bool) -> anyhow::Result<usize> {
+        let tracks = self.backend.list_all().await?;
+        if !confirm {
+            bail!(
+                "Refusing to delete {} tracks without --confirm",
+                tracks.len()
+            );
+        }
+
+        for track in &tracks {
+            self.backend
+                .delete(track.id())
+                .await
+                .with_context(|| format!("Delete {}", track.id()))?;
+        }
+        log::info!("Deleted {} tracks", tracks.len());
+        Ok(tracks.len())
+    }
+
+    fn print_tracks(&self, tracks: &[TrackInfo]) -> anyhow::Result<()> {
+        match self.output {
+            Output::Json => print_json(tracks),
+            Output::Table => {
+                let rows = tracks
+                    .iter()
+                    .map(|track| {
+                        vec![
+                            track.id().to_string(),
+                            track.artist().to_owned(),
+                            track.title().to_owned(),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                print!("{}", table(&["ID", "ARTIST", "TITLE"], &rows));
+                Ok(())
+            }
+        }
+    }
+
+    fn print_results(&self, results: &[QueryResult]) -> anyhow::Result<()> {
+        match self.output {
+            Output::Json => print_json(results),
+            Output::Table => {
+                let rows = results
+                    .iter()
+                    .map(|result| {
+                        vec![
+                            result.id().to_string(),
+                            result.score().to_string(),
+                            result
+                                .offsets()
+                                .map(|o| format!("{:.1}-{:.1}s", o.track_start, o.track_end))
+                                .unwrap_or_default(),
+                            result.artist().clone().unwrap_or_default(),
+                            result.title().clone().unwrap_or_default(),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                print!(
+                    "{}",
+                    table(&["ID", "SCORE", "TRACK AT", "ARTIST", "TITLE"], &rows)
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn read(path: &Path) -> anyhow::Result<(String, Bytes)> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Read {}", path.display()))?;
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok((filename, bytes.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::Tracks;
+    use crate::emysound::{FingerprintBackend, MockBackend, TrackInfo};
+    use crate::output::Output;
+
+    #[tokio::test]
+    async fn test_delete_all() {
+        let backend = MockBackend::new();
+        for i in 0..25 {
+            let info = TrackInfo::new(Uuid::new_v4(), format!("Artist {i}"), "Title".to_owned());
+            backend
+                .insert(info, "track.mp3", &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let tracks = Tracks::new(&backend, Output::Table);
+        assert_eq!(backend.list_all().await.unwrap().len(), 25);
+        assert!(tracks.delete_all(false).await.is_err());
+        assert_eq!(backend.tracks().len(), 25);
+
+        assert_eq!(tracks.delete_all(true).await.unwrap(), 25);
+        assert!(backend.tracks().is_empty());
+    }
+}
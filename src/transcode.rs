@@ -0,0 +1,357 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::config::TranscodeConfig;
+use crate::storage::{AudioData, AudioKind, AudioStorage};
+
+pub const OPUS_FORMAT: &str = "audio/ogg";
+
+/// Sample rate of PCM returned by [`Transcoder::decode`].
+pub const PCM_SAMPLE_RATE: u32 = 8000;
+
+/// Which variant of stored audio to return.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AudioVariant {
+    /// As stored, possibly transcoded.
+    Stored,
+    /// In the format it was received in. Transcoded audio has no such variant, as its original
+    /// is not kept.
+    Original,
+}
+
+/// Re-encodes, cuts and decodes audio with ffmpeg.
+pub struct Transcoder<'a> {
+    config: &'a TranscodeConfig,
+}
+
+impl<'a> Transcoder<'a> {
+    pub fn new(config: &'a TranscodeConfig) -> Self {
+        Self { config }
+    }
+
+    /// Returns `data` re-encoded to Opus if `kind` is configured for transcoding, or as is.
+    ///
+    /// Transcoding failures are logged and the original data is kept, so no segment is lost.
+    pub async fn encode(&self, kind: AudioKind, data: AudioData) -> AudioData {
+        if !self.config.kinds.contains(&kind) || data.format() == OPUS_FORMAT {
+            return data;
+        }
+
+        match self.convert(data.bytes(), OPUS_FORMAT).await {
+            Ok(bytes) => {
+                log::debug!(
+                    "Transcoded {}: {} bytes -> {} bytes",
+                    data.id(),
+                    data.bytes().len(),
+                    bytes.len()
+                );
+                AudioData::new(data.id(), OPUS_FORMAT.to_owned(), bytes)
+                    .with_original_format(data.original_format().to_owned())
+            }
+            Err(e) => {
+                log::error!("Failed to transcode {}: {e:#}", data.id());
+                data
+            }
+        }
+    }
+
+    /// Reads `id` from `storage` in the requested `variant`, with the bytes as stored.
+    ///
+    /// Asking for the original of transcoded audio fails: converting it back would only lose
+    /// more quality, without bringing back what the first transcoding lost.
+    pub async fn get(
+        &self,
+        storage: &dyn AudioStorage,
+        id: Uuid,
+        variant: AudioVariant,
+    ) -> anyhow::Result<AudioData> {
+        let data = storage.get(id).await?;
+        if variant == AudioVariant::Original && data.is_transcoded() {
+            bail!(
+                "Audio {id} is stored transcoded to {}, its original {} is not kept",
+                data.format(),
+                data.original_format()
+            );
+        }
+        Ok(data)
+    }
+
+    /// Converts `bytes` to `format` (a MIME type).
+    pub async fn convert(&self, bytes: &Bytes, format: &str) -> anyhow::Result<Bytes> {
+        self.ffmpeg(&self.output_args(format)?, bytes).await
+    }
+
+    /// Writes `bytes` into an MP4 audio file at `path`, copying the AAC stream of ADTS or MPEG-TS
+    /// audio. MP4 needs a seekable output, so it is written to a file rather than a pipe.
+    pub async fn remux_mp4(&self, bytes: &Bytes, path: &Path) -> anyhow::Result<()> {
+        self.ffmpeg_to(
+            &[
+                "-c:a",
+                "copy",
+                "-bsf:a",
+                "aac_adtstoasc",
+                "-f",
+                "ipod",
+                "-y",
+            ],
+            bytes,
+            path.as_os_str(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Cuts `bytes` to `start..end` (to the end if `end` is `None`), keeping `format`.
+    pub async fn trim(
+        &self,
+        bytes: &Bytes,
+        format: &str,
+        start: Duration,
+        end: Option<Duration>,
+    ) -> anyhow::Result<Bytes> {
+        let start = format!("{:.3}", start.as_secs_f64());
+        let end = end.map(|end| format!("{:.3}", end.as_secs_f64()));
+
+        let mut args = vec!["-ss", &start];
+        if let Some(end) = &end {
+            args.extend(["-to", end]);
+        }
+        args.extend(self.output_args(format)?);
+
+        self.ffmpeg(&args, bytes).await
+    }
+
+    /// Decodes `bytes` to mono 16-bit PCM at `PCM_SAMPLE_RATE`.
+    pub async fn decode(&self, bytes: &Bytes) -> anyhow::Result<Vec<i16>> {
+        let sample_rate = PCM_SAMPLE_RATE.to_string();
+        let pcm = self
+            .ffmpeg(
+                &[
+                    "-ac",
+                    "1",
+                    "-ar",
+                    &sample_rate,
+                    "-c:a",
+                    "pcm_s16le",
+                    "-f",
+                    "s16le",
+                ],
+                bytes,
+            )
+            .await?;
+
+        Ok(pcm
+            .chunks_exact(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+
+    /// Runs ffmpeg with `bytes` on stdin and `args` as output options, returning its stdout.
+    async fn ffmpeg(&self, args: &[&str], bytes: &Bytes) -> anyhow::Result<Bytes> {
+        self.ffmpeg_to(args, bytes, OsStr::new("pipe:1")).await
+    }
+
+    /// Runs ffmpeg with `bytes` on stdin, writing to `output` with `args` as output options.
+    /// Returns its stdout.
+    async fn ffmpeg_to(
+        &self,
+        args: &[&str],
+        bytes: &Bytes,
+        output: &OsStr,
+    ) -> anyhow::Result<Bytes> {
+        let mut child = Command::new(&self.config.ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0", "-vn"])
+            .args(args)
+            .arg(output)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Run {}", self.config.ffmpeg.display()))?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("ffmpeg stdin is not piped"))?;
+        let input = bytes.clone();
+        let writer = tokio::spawn(async move { stdin.write_all(&input).await });
+
+        let output = child.wait_with_output().await?;
+        // ffmpeg closes stdin when it gives up on the input, so its stderr tells why writing
+        // failed.
+        let written = writer.await?;
+        if !output.status.success() {
+            bail!(
+                "ffmpeg failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        written.context("Write to ffmpeg")?;
+        Ok(output.stdout.into())
+    }
+
+    fn output_args(&self, format: &str) -> anyhow::Result<Vec<&str>> {
+        let mime = format
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        Ok(match mime.as_str() {
+            OPUS_FORMAT | "audio/opus" => {
+                vec!["-c:a", "libopus", "-b:a", &self.config.bitrate, "-f", "ogg"]
+            }
+            "audio/aac" | "audio/aacp" | "audio/x-aac" => vec!["-c:a", "aac", "-f", "adts"],
+            "audio/mpeg" | "audio/mp3" => vec!["-c:a", "libmp3lame", "-f", "mp3"],
+            "video/mp2t" => vec!["-c:a", "aac", "-f", "mpegts"],
+            _ => bail!("Unsupported format {format}"),
+        })
+    }
+}
+
+/// Returns the usual file extension of `format` (a MIME type).
+pub fn extension(format: &str) -> &'static str {
+    let mime = format.split(';').next().unwrap_or_default().trim();
+    match mime.to_lowercase().as_str() {
+        OPUS_FORMAT | "audio/opus" => "ogg",
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => "aac",
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "video/mp2t" => "ts",
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{AudioVariant, Transcoder, OPUS_FORMAT};
+    use crate::config::TranscodeConfig;
+    use crate::storage::audio::SqliteAudioStorage;
+    use crate::storage::{AudioData, AudioKind, AudioStorage, Database};
+
+    #[test]
+    fn test_output_args() {
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+
+        assert_eq!(
+            transcoder.output_args("audio/aac; charset=UTF-8").unwrap(),
+            vec!["-c:a", "aac", "-f", "adts"]
+        );
+        assert_eq!(
+            transcoder.output_args(OPUS_FORMAT).unwrap(),
+            vec!["-c:a", "libopus", "-b:a", "24k", "-f", "ogg"]
+        );
+        assert!(transcoder.output_args("text/plain").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode_skips_kinds() {
+        let config = TranscodeConfig {
+            kinds: vec![AudioKind::Talk],
+            ..Default::default()
+        };
+        let data = AudioData::new(
+            Uuid::new_v4(),
+            "audio/aac".to_owned(),
+            b"1234567890".as_ref().into(),
+        );
+
+        let result = Transcoder::new(&config)
+            .encode(AudioKind::Music, data.clone())
+            .await;
+        assert_eq!(result, data);
+    }
+
+    #[tokio::test]
+    async fn test_encode_keeps_data_on_failure() {
+        let config = TranscodeConfig {
+            kinds: vec![AudioKind::Talk],
+            ffmpeg: "./no-such-ffmpeg".into(),
+            ..Default::default()
+        };
+        let data = AudioData::new(
+            Uuid::new_v4(),
+            "audio/aac".to_owned(),
+            b"1234567890".as_ref().into(),
+        );
+
+        let result = Transcoder::new(&config)
+            .encode(AudioKind::Talk, data.clone())
+            .await;
+        assert_eq!(result, data);
+    }
+
+    #[tokio::test]
+    async fn test_get_keeps_bytes() {
+        let storage = SqliteAudioStorage::new(&Database::open(&":memory:").unwrap());
+        let received = AudioData::new(
+            Uuid::new_v4(),
+            "audio/aac".to_owned(),
+            b"1234567890".as_ref().into(),
+        );
+        let transcoded = AudioData::new(
+            Uuid::new_v4(),
+            OPUS_FORMAT.to_owned(),
+            b"12345".as_ref().into(),
+        )
+        .with_original_format("audio/aac".to_owned());
+        storage.insert(&received).await.unwrap();
+        storage.insert(&transcoded).await.unwrap();
+
+        let config = TranscodeConfig::default();
+        let transcoder = Transcoder::new(&config);
+        for variant in [AudioVariant::Original, AudioVariant::Stored] {
+            let result = transcoder.get(&storage, received.id(), variant).await;
+            assert_eq!(result.unwrap(), received);
+        }
+        let result = transcoder
+            .get(&storage, transcoded.id(), AudioVariant::Stored)
+            .await;
+        assert_eq!(result.unwrap(), transcoded);
+        assert!(transcoder
+            .get(&storage, transcoded.id(), AudioVariant::Original)
+            .await
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_convert_reports_stderr() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("test_ffmpeg_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ffmpeg = dir.join("ffmpeg");
+        std::fs::write(
+            &ffmpeg,
+            "#!/bin/sh\necho 'Invalid data found' >&2\nexit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = TranscodeConfig {
+            ffmpeg,
+            ..Default::default()
+        };
+        // Larger than a pipe buffer, so writing fails once ffmpeg has exited.
+        let bytes = vec![0; 1 << 20].into();
+        let result = Transcoder::new(&config).convert(&bytes, OPUS_FORMAT).await;
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("Invalid data found"), "{error}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}